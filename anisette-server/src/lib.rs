@@ -0,0 +1,130 @@
+//! Anisette servers built on omnisette, so clients can use our own machines instead of the public ones.
+//!
+//! [`v3::AnisetteV3Server`] speaks the SideStore v3 protocol of `omnisette::remote_anisette_v3`,
+//! generating the headers with a local [`ConfigurableADIProxy`](omnisette::adi_proxy::ConfigurableADIProxy).
+//! [`v1::AnisetteV1Server`] serves the headers of any `AnisetteHeadersProvider` for clients of the
+//! older v1 servers.
+
+pub mod v1;
+pub mod v3;
+
+use std::{convert::Infallible, future::Future, io, net::SocketAddr};
+
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use omnisette::adi_proxy::ADIError;
+use thiserror::Error;
+use tokio::{sync::oneshot, task::JoinHandle};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("ADI error {0}")]
+    ADIError(#[from] ADIError),
+    #[error("IO error {0}")]
+    IOError(#[from] io::Error),
+    #[error("Server error {0}")]
+    HyperError(#[from] hyper::Error),
+    #[error("Provisioning socket error {0}")]
+    WsError(Box<tokio_tungstenite::tungstenite::Error>),
+    #[error("JSON error {0}")]
+    SerdeError(#[from] serde_json::Error),
+    #[error("Base64 error {0}")]
+    Base64Error(#[from] base64::DecodeError),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(&'static str),
+    #[error("Timed out")]
+    Timeout,
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(err: tokio_tungstenite::tungstenite::Error) -> Self {
+        Error::WsError(Box::new(err))
+    }
+}
+
+/// A running server, stopped when dropped
+pub struct ServerHandle {
+    addr: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: JoinHandle<Result<(), hyper::Error>>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The url clients are configured with
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Serves until `signal` completes, then lets the open connections finish
+    pub async fn run_until(mut self, signal: impl Future<Output = ()>) -> Result<(), Error> {
+        tokio::select! {
+            res = &mut self.task => return Ok(res.expect("server task panicked")?),
+            _ = signal => {}
+        }
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+        Ok((&mut self.task).await.expect("server task panicked")?)
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+    }
+}
+
+/// Serves `handle` on `addr`; must be called from within a tokio runtime
+pub(crate) fn serve<F, R>(addr: SocketAddr, handle: F) -> Result<ServerHandle, Error>
+where
+    F: Fn(Request<Body>) -> R + Clone + Send + Sync + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    let make_service = make_service_fn(move |_| {
+        let handle = handle.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let res = handle(req);
+                async move { Ok::<_, Infallible>(res.await) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    let addr = server.local_addr();
+
+    let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+    let task = tokio::spawn(server.with_graceful_shutdown(async {
+        shutdown_rx.await.ok();
+    }));
+
+    Ok(ServerHandle {
+        addr,
+        shutdown: Some(shutdown),
+        task,
+    })
+}
+
+pub(crate) fn json_response(status: StatusCode, value: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(value.to_string()))
+        .unwrap()
+}
+
+pub(crate) fn empty(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
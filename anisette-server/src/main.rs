@@ -0,0 +1,122 @@
+//! `anisette-server`, serving anisette v3 with StoreServicesCore, or v1 with the local provider.
+
+use std::{net::SocketAddr, path::PathBuf, process::ExitCode};
+
+use anisette_server::{v1::AnisetteV1Server, v3::AnisetteV3Server, ServerHandle};
+use clap::{Parser, ValueEnum};
+use log::{error, info, LevelFilter};
+use omnisette::{
+    store_services_core::StoreServicesCoreADIProxy, AnisetteConfiguration, AnisetteHeaders,
+};
+use simplelog::{ColorChoice, ConfigBuilder, TermLogger, TerminalMode};
+
+#[derive(Debug, Parser)]
+#[command(name = "anisette-server", version, about)]
+struct Cli {
+    /// Address to listen on
+    #[arg(long, env = "ANISETTE_SERVER_LISTEN", default_value = "127.0.0.1:6969")]
+    listen: SocketAddr,
+
+    /// Protocol spoken to the clients
+    #[arg(long, env = "ANISETTE_SERVER_PROTOCOL", value_enum, default_value_t = Protocol::V3)]
+    protocol: Protocol,
+
+    /// Directory holding `lib/<arch>/libstoreservicescore.so` and `libCoreADI.so`, from the Apple Music APK.
+    /// In v1 mode the provisioning data is kept there too
+    #[arg(long, env = "ANISETTE_SERVER_LIBRARY_PATH", default_value = ".")]
+    library_path: PathBuf,
+
+    /// Where the per-request provisioning directories are created in v3 mode
+    #[arg(long, env = "ANISETTE_SERVER_WORK_DIR")]
+    work_dir: Option<PathBuf>,
+
+    /// Bearer token v1 clients have to send
+    #[arg(long, env = "ANISETTE_SERVER_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// Log the requests and provisioning sessions
+    #[arg(long, short)]
+    verbose: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Protocol {
+    /// The flat header map of the older servers, generated by the best provider available here
+    V1,
+    /// SideStore's v3, where the clients keep their own provisioning
+    V3,
+}
+
+fn start(cli: Cli) -> Result<ServerHandle, String> {
+    let listen = cli.listen;
+    let res = match cli.protocol {
+        Protocol::V3 => {
+            let adi_proxy = StoreServicesCoreADIProxy::new(&cli.library_path).map_err(|err| {
+                format!(
+                    "Could not load StoreServicesCore from {:?}: {err}",
+                    cli.library_path
+                )
+            })?;
+            let mut server = AnisetteV3Server::new(adi_proxy);
+            if let Some(work_dir) = cli.work_dir {
+                server = server.set_work_dir(work_dir);
+            }
+            server.start(listen)
+        }
+        Protocol::V1 => {
+            let configuration =
+                AnisetteConfiguration::new().set_configuration_path(cli.library_path);
+            let provider = AnisetteHeaders::get_anisette_headers_provider(configuration)
+                .map_err(|err| format!("Could not create an anisette provider: {err}"))?;
+            let mut server = AnisetteV1Server::new(provider.provider);
+            if let Some(token) = cli.token {
+                server = server.set_token(token);
+            }
+            server.start(listen)
+        }
+    };
+    res.map_err(|err| format!("Could not listen on {listen}: {err}"))
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    TermLogger::init(
+        if cli.verbose {
+            LevelFilter::Debug
+        } else {
+            LevelFilter::Info
+        },
+        ConfigBuilder::new()
+            .add_filter_allow_str("anisette_server")
+            .add_filter_allow_str("omnisette")
+            .build(),
+        TerminalMode::Mixed,
+        ColorChoice::Auto,
+    )
+    .ok();
+
+    let protocol = cli.protocol;
+    let handle = match start(cli) {
+        Ok(handle) => handle,
+        Err(err) => {
+            error!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    info!("Serving anisette {protocol:?} on {}", handle.url());
+
+    match handle
+        .run_until(async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await
+    {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            error!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! The flat JSON header map read by `omnisette::remote_anisette::RemoteAnisetteProvider`.
+//!
+//! | Route | |
+//! | --- | --- |
+//! | `GET /` | the provider's authentication headers |
+//! | `GET /health` | `{"status": "ok"}`, never requires the token |
+//!
+//! Unlike v3, the provisioning lives on the server, in whatever provider it is given.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use hyper::{
+    header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    Body, Method, Request, Response, StatusCode,
+};
+use log::warn;
+use omnisette::anisette_headers_provider::AnisetteHeadersProvider;
+use serde_json::json;
+use tokio::sync::Mutex;
+
+use crate::{empty, json_response, serve, Error, ServerHandle};
+
+/// Serves the headers of a single provider, used by one request at a time
+pub struct AnisetteV1Server {
+    provider: Mutex<Box<dyn AnisetteHeadersProvider>>,
+    token: Option<String>,
+}
+
+impl AnisetteV1Server {
+    pub fn new(provider: Box<dyn AnisetteHeadersProvider>) -> AnisetteV1Server {
+        AnisetteV1Server {
+            provider: Mutex::new(provider),
+            token: None,
+        }
+    }
+
+    /// Only answers requests sending `Authorization: Bearer <token>`
+    pub fn set_token(mut self, token: String) -> AnisetteV1Server {
+        self.token = Some(token);
+        self
+    }
+
+    /// Starts serving on `addr`; must be called from within a tokio runtime
+    pub fn start(self, addr: SocketAddr) -> Result<ServerHandle, Error> {
+        let server = Arc::new(self);
+        serve(addr, move |req| server.clone().handle(req))
+    }
+
+    async fn handle(self: Arc<Self>, req: Request<Body>) -> Response<Body> {
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/health") => json_response(StatusCode::OK, json!({ "status": "ok" })),
+            (&Method::GET, "/") => {
+                if !self.is_authorized(&req) {
+                    let mut res = empty(StatusCode::UNAUTHORIZED);
+                    res.headers_mut()
+                        .insert(WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+                    return res;
+                }
+
+                // the providers aren't thread safe, so requests wait for each other here
+                let mut provider = self.provider.lock().await;
+                match provider.get_authentication_headers().await {
+                    Ok(headers) => json_response(StatusCode::OK, json!(headers)),
+                    Err(err) => {
+                        warn!("Could not get anisette headers: {err}");
+                        json_response(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            json!({ "error": err.to_string() }),
+                        )
+                    }
+                }
+            }
+            _ => empty(StatusCode::NOT_FOUND),
+        }
+    }
+
+    fn is_authorized(&self, req: &Request<Body>) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        req.headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|sent| constant_time_eq(sent.as_bytes(), token.as_bytes()))
+    }
+}
+
+/// Compares without returning early, so the token can't be guessed from response times
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
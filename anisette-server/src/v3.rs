@@ -0,0 +1,343 @@
+//! The server side of the SideStore anisette v3 protocol.
+//!
+//! | Route | |
+//! | --- | --- |
+//! | `GET /v3/client_info` | the client info and user agent the client provisions with |
+//! | `POST /v3/get_headers` | the OTP headers for an `identifier` and its `adi_pb` |
+//! | `GET /v3/provisioning_session` | a websocket relaying a provisioning between Apple and the ADI |
+//!
+//! The server keeps no provisioning data. Each request runs the ADI in a fresh directory holding
+//! only the client's `adi.pb`, which is deleted afterwards, so clients can't see each other's.
+//!
+//! The ADI has a single provisioning session at a time, so a websocket session holds it from
+//! `start_provisioning` to `end_provisioning`, for at most the session timeout; other requests
+//! wait for it.
+
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD as base64_engine, Engine};
+use futures_util::{SinkExt, StreamExt};
+use hyper::{
+    header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE},
+    Body, Method, Request, Response, StatusCode,
+};
+use log::{debug, warn};
+use omnisette::{
+    adi_proxy::{ADIError, ADIProxy, ConfigurableADIProxy, Identifier, DS_ID},
+    ClientProfile,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{Mutex, OwnedMutexGuard},
+};
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+
+use crate::{empty, json_response, serve, Error, ServerHandle};
+
+/// What `X-Apple-I-MD-RINFO` is set to, like the local provider does
+const ROUTING_INFO: &str = "17106176";
+
+/// Serves anisette v3 requests with a single ADI, used by one request or provisioning session at a time
+pub struct AnisetteV3Server<P: ConfigurableADIProxy + 'static> {
+    adi_proxy: Arc<Mutex<P>>,
+    client_profile: ClientProfile,
+    work_dir: PathBuf,
+    session_timeout: Duration,
+}
+
+impl<P: ConfigurableADIProxy + 'static> AnisetteV3Server<P> {
+    pub fn new(adi_proxy: P) -> AnisetteV3Server<P> {
+        AnisetteV3Server {
+            adi_proxy: Arc::new(Mutex::new(adi_proxy)),
+            client_profile: ClientProfile::default(),
+            work_dir: std::env::temp_dir(),
+            session_timeout: Duration::from_secs(60),
+        }
+    }
+
+    /// The client identity handed to clients in `/v3/client_info`
+    pub fn set_client_profile(mut self, client_profile: ClientProfile) -> AnisetteV3Server<P> {
+        self.client_profile = client_profile;
+        self
+    }
+
+    /// Where the per-request provisioning directories are created, the system temp dir by default
+    pub fn set_work_dir(mut self, work_dir: PathBuf) -> AnisetteV3Server<P> {
+        self.work_dir = work_dir;
+        self
+    }
+
+    /// How long a provisioning session may take, one minute by default
+    pub fn set_session_timeout(mut self, session_timeout: Duration) -> AnisetteV3Server<P> {
+        self.session_timeout = session_timeout;
+        self
+    }
+
+    /// Starts serving on `addr`; must be called from within a tokio runtime
+    pub fn start(self, addr: SocketAddr) -> Result<ServerHandle, Error> {
+        let server = Arc::new(self);
+        serve(addr, move |req| server.clone().handle(req))
+    }
+
+    async fn handle(self: Arc<Self>, req: Request<Body>) -> Response<Body> {
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/v3/client_info") => json_response(
+                StatusCode::OK,
+                json!({
+                    "client_info": self.client_profile.client_info,
+                    "user_agent": self.client_profile.provisioning_user_agent,
+                }),
+            ),
+            (&Method::POST, "/v3/get_headers") => {
+                let res = match hyper::body::to_bytes(req.into_body()).await {
+                    Ok(body) => self.get_headers(&body).await,
+                    Err(err) => Err(err.into()),
+                };
+                json_response(
+                    StatusCode::OK,
+                    res.unwrap_or_else(
+                        |err| json!({ "result": "GetHeadersError", "message": err.to_string() }),
+                    ),
+                )
+            }
+            (&Method::GET, "/v3/provisioning_session") => self.provisioning_session(req),
+            _ => empty(StatusCode::NOT_FOUND),
+        }
+    }
+
+    async fn get_headers(&self, body: &[u8]) -> Result<Value, Error> {
+        #[derive(Deserialize)]
+        struct GetHeadersBody {
+            identifier: String,
+            adi_pb: String,
+        }
+        let body: GetHeadersBody = serde_json::from_slice(body)?;
+        let identifier = decode_identifier(&body.identifier)?;
+
+        let dir = self.provisioning_dir()?;
+        fs::write(
+            dir.path().join("adi.pb"),
+            base64_engine.decode(body.adi_pb)?,
+        )?;
+        let otp = self
+            .lease_adi(dir.path(), identifier)
+            .await?
+            .run(|adi_proxy| adi_proxy.request_otp(DS_ID))
+            .await?;
+
+        Ok(json!({
+            "result": "Headers",
+            "X-Apple-I-MD": base64_engine.encode(otp.otp),
+            "X-Apple-I-MD-M": base64_engine.encode(otp.mid),
+            "X-Apple-I-MD-RINFO": ROUTING_INFO,
+        }))
+    }
+
+    /// Accepts the websocket and runs the session on its own task
+    fn provisioning_session(self: Arc<Self>, req: Request<Body>) -> Response<Body> {
+        let Some(key) = req.headers().get(SEC_WEBSOCKET_KEY) else {
+            return empty(StatusCode::BAD_REQUEST);
+        };
+        let accept = derive_accept_key(key.as_bytes());
+
+        tokio::spawn(async move {
+            let upgraded = match hyper::upgrade::on(req).await {
+                Ok(upgraded) => upgraded,
+                Err(err) => return warn!("Provisioning socket upgrade failed: {err}"),
+            };
+            let mut socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+
+            let res = tokio::time::timeout(self.session_timeout, self.provision(&mut socket))
+                .await
+                .unwrap_or(Err(Error::Timeout));
+            if let Err(err) = res {
+                warn!("Provisioning session failed: {err}");
+                let message = json!({ "result": "ProvisioningError", "message": err.to_string() });
+                socket.send(Message::Text(message.to_string())).await.ok();
+            }
+            socket.close(None).await.ok();
+        });
+
+        Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(UPGRADE, "websocket")
+            .header(CONNECTION, "Upgrade")
+            .header(SEC_WEBSOCKET_ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn provision<S>(&self, socket: &mut WebSocketStream<S>) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        #[derive(Deserialize)]
+        struct GiveIdentifier {
+            identifier: String,
+        }
+        #[derive(Deserialize)]
+        struct StartProvisioningData {
+            spim: String,
+        }
+        #[derive(Deserialize)]
+        struct EndProvisioningData {
+            ptm: String,
+            tk: String,
+        }
+
+        send(socket, json!({ "result": "GiveIdentifier" })).await?;
+        let GiveIdentifier { identifier } = receive(socket).await?;
+        let identifier = match decode_identifier(&identifier) {
+            Ok(identifier) => identifier,
+            Err(err) => {
+                send(socket, json!({ "result": "InvalidIdentifier" })).await?;
+                return Err(err);
+            }
+        };
+        let dir = self.provisioning_dir()?;
+
+        send(socket, json!({ "result": "GiveStartProvisioningData" })).await?;
+        let StartProvisioningData { spim } = receive(socket).await?;
+        let spim = base64_engine.decode(spim)?;
+        // kept until the end of the session, so no other client can start one in between
+        let mut adi_proxy = self.lease_adi(dir.path(), identifier).await?;
+        let start = adi_proxy
+            .run(move |adi_proxy| adi_proxy.start_provisioning(DS_ID, &spim))
+            .await?;
+        let session = start.session;
+        debug!("Started provisioning session {session}");
+
+        let end = async {
+            send(
+                socket,
+                json!({ "result": "GiveEndProvisioningData", "cpim": base64_engine.encode(&start.cpim) }),
+            )
+            .await?;
+            let EndProvisioningData { ptm, tk } = receive(socket).await?;
+            Ok::<_, Error>((base64_engine.decode(ptm)?, base64_engine.decode(tk)?))
+        };
+        let res = match end.await {
+            Ok((ptm, tk)) => {
+                adi_proxy
+                    .run(move |adi_proxy| adi_proxy.end_provisioning(session, &ptm, &tk))
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+        if let Err(err) = res {
+            adi_proxy
+                .run(move |adi_proxy| adi_proxy.destroy_provisioning_session(session))
+                .await
+                .ok();
+            return Err(err);
+        }
+        drop(adi_proxy);
+
+        let adi_pb = fs::read(dir.path().join("adi.pb"))?;
+        send(
+            socket,
+            json!({ "result": "ProvisioningSuccess", "adi_pb": base64_engine.encode(adi_pb) }),
+        )
+        .await?;
+        debug!("Provisioning session {session} succeeded");
+        Ok(())
+    }
+
+    fn provisioning_dir(&self) -> Result<TempDir, Error> {
+        Ok(tempfile::Builder::new()
+            .prefix("anisette-")
+            .tempdir_in(&self.work_dir)?)
+    }
+
+    /// Waits for the ADI and sets it up to provision in `dir` as `identifier`
+    async fn lease_adi(&self, dir: &Path, identifier: Identifier) -> Result<AdiLease<P>, Error> {
+        let dir = dir
+            .to_str()
+            .ok_or(Error::InvalidRequest("work dir is not valid UTF-8"))?
+            .to_string();
+
+        let mut lease = AdiLease(Some(self.adi_proxy.clone().lock_owned().await));
+        lease
+            .run(move |adi_proxy| {
+                adi_proxy.set_provisioning_path(&dir)?;
+                (adi_proxy as &mut dyn ADIProxy).set_identity(&identifier)
+            })
+            .await?;
+        Ok(lease)
+    }
+}
+
+/// The ADI, kept for one request or provisioning session until dropped
+struct AdiLease<P>(Option<OwnedMutexGuard<P>>);
+
+impl<P: ConfigurableADIProxy + 'static> AdiLease<P> {
+    /// Runs `f` on a blocking thread
+    async fn run<T, F>(&mut self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut P) -> Result<T, ADIError> + Send + 'static,
+    {
+        // a panic drops the guard on the way, and the next lease sets the path and identity again
+        let mut adi_proxy = self
+            .0
+            .take()
+            .expect("the ADI is used by one call at a time");
+        let res = tokio::task::spawn_blocking(move || {
+            let res = f(&mut adi_proxy);
+            (adi_proxy, res)
+        })
+        .await;
+        match res {
+            Ok((adi_proxy, res)) => {
+                self.0 = Some(adi_proxy);
+                Ok(res?)
+            }
+            Err(err) => std::panic::resume_unwind(err.into_panic()),
+        }
+    }
+}
+
+fn decode_identifier(identifier: &str) -> Result<Identifier, Error> {
+    let identifier = base64_engine.decode(identifier)?;
+    Identifier::try_from(identifier.as_slice())
+        .map_err(|_| Error::InvalidRequest("the identifier must be 16 bytes long"))
+}
+
+async fn send<S>(socket: &mut WebSocketStream<S>, message: Value) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    Ok(socket.send(Message::Text(message.to_string())).await?)
+}
+
+/// Waits for the next text message, answering pings on the way
+async fn receive<S, T>(socket: &mut WebSocketStream<S>) -> Result<T, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    T: DeserializeOwned,
+{
+    loop {
+        match socket.next().await {
+            Some(Ok(Message::Text(text))) => return Ok(serde_json::from_str(&text)?),
+            Some(Ok(Message::Close(_))) | None => {
+                return Err(Error::InvalidRequest(
+                    "the client left the provisioning session",
+                ))
+            }
+            Some(Ok(_)) => continue,
+            Some(Err(err)) => return Err(err.into()),
+        }
+    }
+}
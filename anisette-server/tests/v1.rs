@@ -0,0 +1,118 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use anisette_server::{v1::AnisetteV1Server, ServerHandle};
+    use hyper::{header::AUTHORIZATION, Body, Client, Request, StatusCode};
+    use omnisette::{
+        anisette_headers_provider::AnisetteHeadersProvider,
+        remote_anisette::RemoteAnisetteProvider, AnisetteError,
+    };
+    use serde_json::Value;
+
+    /// Counts its calls, and fails the test if two of them overlap
+    #[derive(Default)]
+    struct FakeProvider {
+        busy: Arc<AtomicBool>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl AnisetteHeadersProvider for FakeProvider {
+        async fn get_anisette_headers(
+            &mut self,
+            _skip_provisioning: bool,
+        ) -> Result<HashMap<String, String>, AnisetteError> {
+            assert!(
+                !self.busy.swap(true, Ordering::SeqCst),
+                "provider used concurrently"
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let calls = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+            self.busy.store(false, Ordering::SeqCst);
+
+            Ok(HashMap::from([
+                ("X-Apple-I-MD".to_string(), format!("otp{calls}")),
+                ("X-Apple-I-MD-M".to_string(), "mid".to_string()),
+                ("X-MMe-Client-Info".to_string(), "client".to_string()),
+            ]))
+        }
+    }
+
+    fn start(server: AnisetteV1Server) -> ServerHandle {
+        server
+            .start("127.0.0.1:0".parse::<SocketAddr>().unwrap())
+            .unwrap()
+    }
+
+    async fn get(url: String, token: Option<&str>) -> (StatusCode, Option<Value>) {
+        let mut request = Request::get(url);
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let response = Client::new()
+            .request(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).ok())
+    }
+
+    #[tokio::test]
+    async fn serves_authentication_headers() {
+        let server = start(AnisetteV1Server::new(Box::<FakeProvider>::default()));
+
+        let mut provider = RemoteAnisetteProvider::new(server.url());
+        let headers = provider.get_authentication_headers().await.unwrap();
+        assert_eq!(headers["X-Apple-I-MD"], "otp1");
+        // the headers were normalized before being sent
+        assert_eq!(headers["X-Mme-Client-Info"], "client");
+        assert!(!headers.contains_key("X-MMe-Client-Info"));
+    }
+
+    #[tokio::test]
+    async fn requests_are_serialized() {
+        let provider = FakeProvider::default();
+        let calls = provider.calls.clone();
+        let server = start(AnisetteV1Server::new(Box::new(provider)));
+
+        let requests = (0..5).map(|_| get(server.url(), None));
+        for (status, _) in futures_util::future::join_all(requests).await {
+            assert_eq!(status, StatusCode::OK);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn token_is_required() {
+        let provider = FakeProvider::default();
+        let calls = provider.calls.clone();
+        let server =
+            start(AnisetteV1Server::new(Box::new(provider)).set_token("secret".to_string()));
+
+        assert_eq!(get(server.url(), None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            get(server.url(), Some("wrong")).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        let (status, headers) = get(server.url(), Some("secret")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers.unwrap()["X-Apple-I-MD"], "otp1");
+
+        // the health check stays open
+        let (status, health) = get(format!("{}/health", server.url()), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(health.unwrap()["status"], "ok");
+    }
+}
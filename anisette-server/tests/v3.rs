@@ -0,0 +1,489 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        net::SocketAddr,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use anisette_server::v3::AnisetteV3Server;
+    use base64::{engine::general_purpose::STANDARD as base64_engine, Engine};
+    use omnisette::{
+        adi_proxy::{
+            ADIError, ADIProxy, ADIProxyAnisetteProvider, ConfigurableADIProxy, RequestOTPData,
+            StartProvisioningData, SynchronizeData,
+        },
+        anisette_headers_provider::AnisetteHeadersProvider,
+        http::{HeaderMap, HttpRequest, HttpResponse, HttpTransport, StatusCode, TransportError},
+        provisioning_bundle::ProvisioningBundle,
+        remote_anisette_v3::RemoteAnisetteProviderV3,
+        state_store::{AnisetteStateStore, MemoryStateStore, ADI_PB_KEY, STATE_KEY},
+    };
+    use serde_json::{json, Value};
+
+    /// An ADI whose `adi.pb` records the device it was provisioned for. Like the real one, it has a
+    /// single provisioning session, lost when another one starts.
+    #[derive(Default)]
+    struct FakeADIProxy {
+        provisioning_path: PathBuf,
+        device_identifier: String,
+        local_user_uuid: String,
+        session: Option<(u32, String)>,
+        sessions: u32,
+    }
+
+    impl ADIProxy for FakeADIProxy {
+        fn erase_provisioning(&mut self, _ds_id: i64) -> Result<(), ADIError> {
+            Ok(fs::remove_file(self.provisioning_path.join("adi.pb"))?)
+        }
+
+        fn synchronize(&mut self, _ds_id: i64, _sim: &[u8]) -> Result<SynchronizeData, ADIError> {
+            Err(ADIError::Unknown(-45061))
+        }
+
+        fn destroy_provisioning_session(&mut self, _session: u32) -> Result<(), ADIError> {
+            Ok(())
+        }
+
+        fn end_provisioning(
+            &mut self,
+            session: u32,
+            ptm: &[u8],
+            tk: &[u8],
+        ) -> Result<(), ADIError> {
+            assert_eq!((ptm, tk), (&b"ptm"[..], &b"tk"[..]));
+            match self.session.take() {
+                Some((started, device))
+                    if started == session && device == self.device_identifier =>
+                {
+                    let adi_pb = format!("provisioned {device}");
+                    Ok(fs::write(self.provisioning_path.join("adi.pb"), adi_pb)?)
+                }
+                _ => Err(ADIError::Unknown(-45054)),
+            }
+        }
+
+        fn start_provisioning(
+            &mut self,
+            _ds_id: i64,
+            spim: &[u8],
+        ) -> Result<StartProvisioningData, ADIError> {
+            self.sessions += 1;
+            self.session = Some((self.sessions, self.device_identifier.clone()));
+            Ok(StartProvisioningData {
+                cpim: [&b"cpim-"[..], spim].concat(),
+                session: self.sessions,
+            })
+        }
+
+        fn is_machine_provisioned(&self, _ds_id: i64) -> bool {
+            self.provisioning_path.join("adi.pb").exists()
+        }
+
+        fn request_otp(&self, _ds_id: i64) -> Result<RequestOTPData, ADIError> {
+            let adi_pb = fs::read_to_string(self.provisioning_path.join("adi.pb"))?;
+            match adi_pb.strip_prefix("provisioned ") {
+                Some(device) if device == self.device_identifier => Ok(RequestOTPData {
+                    otp: b"otp".to_vec(),
+                    mid: device.as_bytes().to_vec(),
+                }),
+                _ => Err(ADIError::Unknown(-45061)),
+            }
+        }
+
+        fn set_local_user_uuid(&mut self, local_user_uuid: String) {
+            self.local_user_uuid = local_user_uuid;
+        }
+
+        fn set_device_identifier(&mut self, device_identifier: String) -> Result<(), ADIError> {
+            self.device_identifier = device_identifier;
+            Ok(())
+        }
+
+        fn get_local_user_uuid(&self) -> String {
+            self.local_user_uuid.clone()
+        }
+
+        fn get_device_identifier(&self) -> String {
+            self.device_identifier.clone()
+        }
+
+        fn get_serial_number(&self) -> String {
+            "0".to_string()
+        }
+    }
+
+    impl ConfigurableADIProxy for FakeADIProxy {
+        fn set_identifier(&mut self, identifier: &str) -> Result<(), ADIError> {
+            self.set_device_identifier(identifier.to_string())
+        }
+
+        fn set_provisioning_path(&mut self, path: &str) -> Result<(), ADIError> {
+            self.provisioning_path = PathBuf::from(path);
+            Ok(())
+        }
+    }
+
+    /// Stands in for Apple's provisioning endpoints and remembers what was sent
+    #[derive(Default)]
+    struct FakeApple {
+        requests: Mutex<Vec<HttpRequest>>,
+        /// Answers the next finish request with a server error
+        fail_finish: AtomicBool,
+    }
+
+    #[async_trait::async_trait]
+    impl HttpTransport for FakeApple {
+        async fn send(&self, request: HttpRequest) -> Result<HttpResponse, TransportError> {
+            let dictionary = |entries: Vec<(&str, plist::Value)>| {
+                plist::Value::Dictionary(
+                    entries
+                        .into_iter()
+                        .map(|(key, value)| (key.to_string(), value))
+                        .collect(),
+                )
+            };
+            let status = || dictionary(vec![("ec", 0.into())]);
+            let response = match request.url.as_str() {
+                "https://gsa.apple.com/grandslam/GsService2/lookup" => dictionary(vec![(
+                    "urls",
+                    dictionary(vec![
+                        ("midStartProvisioning", "https://apple.invalid/start".into()),
+                        (
+                            "midFinishProvisioning",
+                            "https://apple.invalid/finish".into(),
+                        ),
+                    ]),
+                )]),
+                "https://apple.invalid/start" => dictionary(vec![(
+                    "Response",
+                    dictionary(vec![
+                        ("Status", status()),
+                        ("spim", base64_engine.encode("spim").into()),
+                    ]),
+                )]),
+                "https://apple.invalid/finish"
+                    if self.fail_finish.swap(false, Ordering::SeqCst) =>
+                {
+                    self.requests.lock().unwrap().push(request);
+                    return Ok(HttpResponse {
+                        status: StatusCode::SERVICE_UNAVAILABLE,
+                        headers: HeaderMap::new(),
+                        body: Vec::new(),
+                    });
+                }
+                "https://apple.invalid/finish" => dictionary(vec![(
+                    "Response",
+                    dictionary(vec![
+                        ("Status", status()),
+                        ("ptm", base64_engine.encode("ptm").into()),
+                        ("tk", base64_engine.encode("tk").into()),
+                    ]),
+                )]),
+                url => panic!("unexpected request to {url}"),
+            };
+            self.requests.lock().unwrap().push(request);
+
+            let mut body = Vec::new();
+            plist::to_writer_xml(&mut body, &response).unwrap();
+            Ok(HttpResponse {
+                status: StatusCode::OK,
+                headers: HeaderMap::new(),
+                body,
+            })
+        }
+    }
+
+    fn localhost() -> SocketAddr {
+        "127.0.0.1:0".parse().unwrap()
+    }
+
+    async fn get_headers(url: &str, body: Value) -> Value {
+        let request = hyper::Request::post(format!("{url}/v3/get_headers"))
+            .body(hyper::Body::from(body.to_string()))
+            .unwrap();
+        let response = hyper::Client::new().request(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn provisions_and_serves_headers() {
+        let work_dir = tempfile::tempdir().unwrap();
+        let server = AnisetteV3Server::new(FakeADIProxy::default())
+            .set_work_dir(work_dir.path().to_path_buf())
+            .start(localhost())
+            .unwrap();
+
+        let config_dir = tempfile::tempdir().unwrap();
+        let apple = Arc::new(FakeApple::default());
+        let mut provider = RemoteAnisetteProviderV3::new(
+            server.url(),
+            config_dir.path().to_path_buf(),
+            "0".to_string(),
+        )
+        .set_apple_transport(apple.clone());
+
+        let headers = provider.get_anisette_headers(false).await.unwrap();
+        assert_eq!(headers["X-Apple-I-MD"], base64_engine.encode("otp"));
+        // the ADI was given the client's identity
+        assert_eq!(
+            base64_engine.decode(&headers["X-Apple-I-MD-M"]).unwrap(),
+            headers["X-Mme-Device-Id"].to_uppercase().as_bytes()
+        );
+        assert_eq!(headers["X-Apple-I-MD-RINFO"], "17106176");
+
+        {
+            let requests = apple.requests.lock().unwrap();
+            assert_eq!(requests.len(), 3);
+            let cpim = base64_engine.encode("cpim-spim");
+            assert!(String::from_utf8_lossy(requests[2].body.as_ref().unwrap()).contains(&cpim));
+        }
+
+        // the provisioning is kept by the client, so the next headers need no new session
+        provider.get_anisette_headers(false).await.unwrap();
+        assert_eq!(apple.requests.lock().unwrap().len(), 3);
+        assert_eq!(fs::read_dir(work_dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn state_can_live_in_memory() {
+        let server = AnisetteV3Server::new(FakeADIProxy::default())
+            .start(localhost())
+            .unwrap();
+        let apple = Arc::new(FakeApple::default());
+        let store = Arc::new(MemoryStateStore::new());
+        let config_dir = std::env::temp_dir().join("anisette-server-unused");
+        let provider = || {
+            RemoteAnisetteProviderV3::new(server.url(), config_dir.clone(), "0".to_string())
+                .set_apple_transport(apple.clone())
+                .set_state_store(store.clone())
+        };
+
+        provider().get_anisette_headers(false).await.unwrap();
+        assert!(store.load(STATE_KEY).unwrap().is_some());
+        assert!(!config_dir.exists());
+
+        // a new provider picks the provisioning up from the store
+        provider().get_anisette_headers(false).await.unwrap();
+        assert_eq!(apple.requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn local_provisioning_is_saved_in_the_store() {
+        let apple = Arc::new(FakeApple::default());
+        let store = Arc::new(MemoryStateStore::new());
+        let provider = || {
+            ADIProxyAnisetteProvider::with_stored_provisioning(
+                FakeADIProxy::default(),
+                store.clone(),
+            )
+            .unwrap()
+            .set_transport(apple.clone())
+        };
+
+        let headers = provider().get_anisette_headers(false).await.unwrap();
+        let adi_pb = store.load(ADI_PB_KEY).unwrap().unwrap();
+        assert!(adi_pb.starts_with(b"provisioned "));
+
+        // another provider, in another directory, picks the provisioning up from the store
+        let requests = apple.requests.lock().unwrap().len();
+        let again = provider().get_anisette_headers(false).await.unwrap();
+        assert_eq!(again["X-Apple-I-MD-M"], headers["X-Apple-I-MD-M"]);
+        assert_eq!(apple.requests.lock().unwrap().len(), requests);
+    }
+
+    #[tokio::test]
+    async fn failed_provisioning_starts_over() {
+        let apple = Arc::new(FakeApple::default());
+        apple.fail_finish.store(true, Ordering::SeqCst);
+        let mut provider = ADIProxyAnisetteProvider::with_stored_provisioning(
+            FakeADIProxy::default(),
+            Arc::new(MemoryStateStore::new()),
+        )
+        .unwrap()
+        .set_transport(apple.clone());
+
+        // the finish request isn't sent again, Apple may have handled it
+        assert!(provider.get_anisette_headers(false).await.is_err());
+        let urls = |apple: &FakeApple| {
+            apple
+                .requests
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|request| request.url.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(urls(&apple).len(), 3);
+
+        // the next call goes through a new session
+        provider.get_anisette_headers(false).await.unwrap();
+        assert_eq!(urls(&apple)[3..], urls(&apple)[..3]);
+    }
+
+    #[tokio::test]
+    async fn provisioning_moves_to_a_local_adi() {
+        let server = AnisetteV3Server::new(FakeADIProxy::default())
+            .start(localhost())
+            .unwrap();
+        let apple = Arc::new(FakeApple::default());
+        let remote_store = Arc::new(MemoryStateStore::new());
+        let mut remote = RemoteAnisetteProviderV3::new(
+            server.url(),
+            std::env::temp_dir().join("anisette-server-unused"),
+            "0".to_string(),
+        )
+        .set_apple_transport(apple.clone())
+        .set_state_store(remote_store.clone());
+        let remote_headers = remote.get_anisette_headers(false).await.unwrap();
+
+        let bundle = ProvisioningBundle::export_remote_v3(remote_store.as_ref()).unwrap();
+        let bundle = ProvisioningBundle::from_bytes(&bundle.to_bytes().unwrap()).unwrap();
+        let local_store = Arc::new(MemoryStateStore::new());
+        bundle.import_local(local_store.as_ref()).unwrap();
+
+        let mut local = ADIProxyAnisetteProvider::with_stored_provisioning(
+            FakeADIProxy::default(),
+            local_store,
+        )
+        .unwrap();
+        let local_headers = local.get_anisette_headers(false).await.unwrap();
+
+        // same machine, and Apple wasn't asked to provision another one
+        assert_eq!(
+            local_headers["X-Apple-I-MD-M"],
+            remote_headers["X-Apple-I-MD-M"]
+        );
+        assert_eq!(
+            local_headers["X-Mme-Device-Id"],
+            remote_headers["X-Mme-Device-Id"].to_uppercase()
+        );
+        assert_eq!(apple.requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn interleaved_sessions_wait_for_the_adi() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::{
+            connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream,
+        };
+
+        type Socket = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+        async fn send(socket: &mut Socket, message: Value) {
+            socket
+                .send(Message::Text(message.to_string()))
+                .await
+                .unwrap();
+        }
+        async fn receive(socket: &mut Socket) -> Value {
+            let message = socket.next().await.unwrap().unwrap();
+            serde_json::from_str(message.to_text().unwrap()).unwrap()
+        }
+        async fn start(url: &str, identifier: [u8; 16]) -> Socket {
+            let url = format!(
+                "{}/v3/provisioning_session",
+                url.replacen("http://", "ws://", 1)
+            );
+            let (mut socket, _) = connect_async(url).await.unwrap();
+            assert_eq!(receive(&mut socket).await["result"], "GiveIdentifier");
+            send(
+                &mut socket,
+                json!({ "identifier": base64_engine.encode(identifier) }),
+            )
+            .await;
+            assert_eq!(
+                receive(&mut socket).await["result"],
+                "GiveStartProvisioningData"
+            );
+            send(&mut socket, json!({ "spim": base64_engine.encode("spim") })).await;
+            socket
+        }
+        async fn end(socket: &mut Socket) -> String {
+            let ptm_tk =
+                json!({ "ptm": base64_engine.encode("ptm"), "tk": base64_engine.encode("tk") });
+            send(socket, ptm_tk).await;
+            let res = receive(socket).await;
+            assert_eq!(res["result"], "ProvisioningSuccess", "{res}");
+            String::from_utf8(
+                base64_engine
+                    .decode(res["adi_pb"].as_str().unwrap())
+                    .unwrap(),
+            )
+            .unwrap()
+        }
+
+        let server = AnisetteV3Server::new(FakeADIProxy::default())
+            .start(localhost())
+            .unwrap();
+
+        let mut first = start(&server.url(), [1; 16]).await;
+        assert_eq!(
+            receive(&mut first).await["result"],
+            "GiveEndProvisioningData"
+        );
+        let mut second = start(&server.url(), [2; 16]).await;
+
+        // the second session can't start while the first one holds the ADI
+        let waiting =
+            tokio::time::timeout(std::time::Duration::from_millis(200), second.next()).await;
+        assert!(waiting.is_err());
+
+        assert!(end(&mut first)
+            .await
+            .ends_with("01010101-0101-0101-0101-010101010101"));
+        assert_eq!(
+            receive(&mut second).await["result"],
+            "GiveEndProvisioningData"
+        );
+        assert!(end(&mut second)
+            .await
+            .ends_with("02020202-0202-0202-0202-020202020202"));
+    }
+
+    #[tokio::test]
+    async fn get_headers_reports_adi_errors() {
+        let server = AnisetteV3Server::new(FakeADIProxy::default())
+            .start(localhost())
+            .unwrap();
+
+        let res = get_headers(
+            &server.url(),
+            json!({
+                "identifier": base64_engine.encode([0u8; 16]),
+                "adi_pb": base64_engine.encode("provisioned someone else"),
+            }),
+        )
+        .await;
+        assert_eq!(res["result"], "GetHeadersError");
+        assert!(res["message"].as_str().unwrap().contains("-45061"));
+
+        let res = get_headers(
+            &server.url(),
+            json!({ "identifier": base64_engine.encode([0u8; 4]), "adi_pb": "" }),
+        )
+        .await;
+        assert_eq!(res["result"], "GetHeadersError");
+    }
+
+    #[tokio::test]
+    async fn client_info() {
+        let server = AnisetteV3Server::new(FakeADIProxy::default())
+            .start(localhost())
+            .unwrap();
+
+        let uri = format!("{}/v3/client_info", server.url()).parse().unwrap();
+        let response = hyper::Client::new().get(uri).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let client_info: Value = serde_json::from_slice(&body).unwrap();
+
+        let profile = omnisette::ClientProfile::default();
+        assert_eq!(client_info["client_info"], profile.client_info);
+        assert_eq!(client_info["user_agent"], profile.provisioning_user_agent);
+    }
+}
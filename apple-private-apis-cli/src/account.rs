@@ -0,0 +1,111 @@
+use icloud_auth::{AccountManager, DeliveryMode, LoginFlow, LoginState};
+use serde_json::{json, Value};
+
+use crate::{prompt, Error};
+
+/// How the login gets its 2FA code
+pub struct SecondFactor {
+    pub phone: bool,
+    pub phone_id: Option<u32>,
+    pub voice: bool,
+}
+
+pub async fn login(
+    mut manager: AccountManager,
+    username: Option<String>,
+    password: Option<String>,
+    second_factor: SecondFactor,
+) -> Result<Value, Error> {
+    let username = match username {
+        Some(username) => username,
+        None => prompt("Apple ID: ")?,
+    };
+    let password = match password {
+        Some(password) => password,
+        None => rpassword::prompt_password("Password: ")?,
+    };
+
+    let mut flow = LoginFlow::new(manager.new_account().await?);
+    let mut state = flow.begin(&username, &password).await?;
+    loop {
+        state = match state {
+            LoginState::LoggedIn | LoginState::NeedsExtraStep(_) => break,
+            LoginState::NeedsLogin => flow.relogin().await?,
+            LoginState::NeedsDevice2FA if !second_factor.phone => flow.request_device_code().await?,
+            LoginState::Needs2FAVerification if !second_factor.phone => {
+                let code = prompt("Code sent to your trusted devices: ")?;
+                flow.submit_device_code(code).await?
+            }
+            LoginState::NeedsDevice2FA
+            | LoginState::Needs2FAVerification
+            | LoginState::NeedsSMS2FA => {
+                let (id, mode) = pick_phone_number(&flow, &second_factor).await?;
+                flow.request_phone_code(id, mode).await?
+            }
+            LoginState::NeedsSMS2FAVerification(_) => {
+                let code = prompt("Code sent to your phone: ")?;
+                flow.submit_sms_code(code).await?
+            }
+        };
+    }
+
+    let account = flow.into_account()?;
+    let (first_name, last_name) = account.get_name()?;
+    let adsid = manager.insert(account)?;
+    Ok(json!({ "adsid": adsid, "first_name": first_name, "last_name": last_name }))
+}
+
+/// The trusted phone number to send the code to, asking for it when there are several
+async fn pick_phone_number(
+    flow: &LoginFlow,
+    second_factor: &SecondFactor,
+) -> Result<(u32, DeliveryMode), Error> {
+    let numbers = flow.trusted_phone_numbers().await?;
+    let id = match (second_factor.phone_id, numbers.as_slice()) {
+        (Some(id), _) => id,
+        (None, []) => return Err(icloud_auth::Error::NoTrustedPhoneNumbers.into()),
+        (None, [number]) => number.id,
+        (None, numbers) => {
+            for number in numbers {
+                eprintln!("{}: {}", number.id, number.number_with_dial_code);
+            }
+            let id = prompt("Phone number id: ")?;
+            id.parse().map_err(|_| Error::UnknownPhoneNumber(id))?
+        }
+    };
+
+    let number = numbers
+        .iter()
+        .find(|number| number.id == id)
+        .ok_or_else(|| Error::UnknownPhoneNumber(id.to_string()))?;
+    let mode = if second_factor.voice {
+        DeliveryMode::Voice
+    } else {
+        number.delivery_mode()
+    };
+    Ok((id, mode))
+}
+
+pub async fn app_token(
+    mut manager: AccountManager,
+    app: &str,
+    adsid: Option<String>,
+) -> Result<Value, Error> {
+    manager.load()?;
+    let adsid = match adsid {
+        Some(adsid) => adsid,
+        None => manager.current_adsid().ok_or(Error::NotLoggedIn)?.to_string(),
+    };
+
+    let account = manager.get(&adsid)?;
+    let token = account.lock().await.app_token(app).await?;
+    // the session may have been renewed on the way
+    manager.save(&adsid).await?;
+
+    Ok(json!({
+        "adsid": adsid,
+        "app": token.app,
+        "token": token.auth_token,
+        "expiry": token.expiry,
+    }))
+}
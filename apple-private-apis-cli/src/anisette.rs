@@ -0,0 +1,113 @@
+use std::{fs, path::Path};
+
+use clap::ValueEnum;
+use omnisette::{
+    anisette_headers_provider::AnisetteHeadersProvider, provisioning_bundle::ProvisioningBundle,
+    remote_anisette::RemoteAnisetteProvider, remote_anisette_v3::RemoteAnisetteProviderV3,
+    AnisetteConfiguration, AnisetteHeaders,
+};
+use serde_json::{json, Value};
+
+use crate::Error;
+
+/// Files the providers keep their provisioning state in
+const PROVISIONING_FILES: [&str; 3] = ["adi.pb", "identifier", "state.plist"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Provider {
+    /// The best provider available, as picked by omnisette
+    Auto,
+    /// StoreServicesCore, with the provisioning data on this machine
+    Local,
+    /// A v1 anisette server, which provisions on its side
+    Remote,
+    /// A v3 anisette server, with the provisioning data on this machine
+    RemoteV3,
+}
+
+impl Provider {
+    fn create(
+        self,
+        config: AnisetteConfiguration,
+    ) -> Result<Box<dyn AnisetteHeadersProvider>, Error> {
+        Ok(match self {
+            Provider::Auto => AnisetteHeaders::get_anisette_headers_provider(config)?.provider,
+            Provider::Local => AnisetteHeaders::get_ssc_anisette_headers_provider(config)?.provider,
+            Provider::Remote => Box::new(
+                RemoteAnisetteProvider::new(config.anisette_url().clone())
+                    .set_transport(config.transport()?),
+            ),
+            Provider::RemoteV3 => Box::new(
+                RemoteAnisetteProviderV3::new(
+                    config.anisette_url_v3().clone(),
+                    config.configuration_path().clone(),
+                    config.macos_serial().clone(),
+                )
+                .set_transport(config.transport()?),
+            ),
+        })
+    }
+}
+
+pub async fn headers(config: AnisetteConfiguration, provider: Provider) -> Result<Value, Error> {
+    fs::create_dir_all(config.configuration_path())?;
+    let mut provider = provider.create(config)?;
+    let headers = provider.get_authentication_headers().await?;
+    Ok(json!(headers))
+}
+
+pub async fn provision(config: AnisetteConfiguration, provider: Provider) -> Result<Value, Error> {
+    let path = config.configuration_path().clone();
+    fs::create_dir_all(&path)?;
+    // the providers provision on the first request if needed
+    provider.create(config)?.get_authentication_headers().await?;
+    Ok(json!({ "provisioned": true, "path": path }))
+}
+
+pub fn reset(config: AnisetteConfiguration) -> Result<Value, Error> {
+    let path = config.configuration_path();
+    let mut removed = vec![];
+    for name in PROVISIONING_FILES {
+        let file = path.join(name);
+        if file.exists() {
+            fs::remove_file(file)?;
+            removed.push(name);
+        }
+    }
+    Ok(json!({ "path": path, "removed": removed }))
+}
+
+pub fn export(
+    config: AnisetteConfiguration,
+    provider: Provider,
+    output: &Path,
+) -> Result<Value, Error> {
+    let store = config.state_store();
+    let bundle = match provider {
+        Provider::Local => ProvisioningBundle::export_local(store.as_ref())?,
+        Provider::RemoteV3 => ProvisioningBundle::export_remote_v3(store.as_ref())?,
+        _ => return Err(no_local_provisioning(provider)),
+    };
+    fs::write(output, bundle.to_bytes()?)?;
+    Ok(json!({ "exported": output }))
+}
+
+pub fn import(
+    config: AnisetteConfiguration,
+    provider: Provider,
+    input: &Path,
+) -> Result<Value, Error> {
+    let bundle = ProvisioningBundle::from_bytes(&fs::read(input)?)?;
+    let store = config.state_store();
+    match provider {
+        Provider::Local => bundle.import_local(store.as_ref())?,
+        Provider::RemoteV3 => bundle.import_remote_v3(store.as_ref())?,
+        _ => return Err(no_local_provisioning(provider)),
+    }
+    Ok(json!({ "imported": input }))
+}
+
+fn no_local_provisioning(provider: Provider) -> Error {
+    let name = provider.to_possible_value().map(|value| value.get_name().to_string());
+    Error::NoLocalProvisioning(name.unwrap_or_default())
+}
@@ -0,0 +1,234 @@
+//! `apple-private-apis`, a command-line tool over omnisette, icloud_auth and apple-codesign-wrapper.
+//!
+//! Every command prints a single JSON value on stdout. Failures print `{"error": ...}` and exit with 1,
+//! while prompts go to stderr so they don't end up in the output.
+
+mod account;
+mod anisette;
+#[cfg(feature = "codesign")]
+mod sign;
+
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use clap::{Parser, Subcommand};
+use icloud_auth::{AccountConfiguration, AccountManager, AnisetteConfiguration};
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::anisette::Provider;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    Auth(#[from] icloud_auth::Error),
+    #[error("{0}")]
+    Anisette(#[from] omnisette::AnisetteError),
+    #[error("IO error {0}")]
+    Io(#[from] io::Error),
+    #[error("No account is logged in, run `login` first")]
+    NotLoggedIn,
+    #[error("No trusted phone number with id {0}")]
+    UnknownPhoneNumber(String),
+    #[error("The {0} provider keeps no provisioning on this machine")]
+    NoLocalProvisioning(String),
+    #[cfg(feature = "codesign")]
+    #[error("Signing failed {0}")]
+    Codesign(String),
+}
+
+#[derive(Debug, Parser)]
+#[command(name = "apple-private-apis", version, about)]
+struct Cli {
+    /// Where anisette provisioning data and account sessions are stored
+    #[arg(long, global = true, env = "APPLE_PRIVATE_APIS_DIR", default_value_os_t = default_data_dir())]
+    data_dir: PathBuf,
+
+    /// Server used by the `remote` anisette provider
+    #[arg(long, global = true, env = "ANISETTE_URL", default_value = omnisette::DEFAULT_ANISETTE_URL)]
+    anisette_url: String,
+
+    /// Server used by the `remote-v3` anisette provider
+    #[arg(long, global = true, env = "ANISETTE_URL_V3", default_value = omnisette::DEFAULT_ANISETTE_URL_V3)]
+    anisette_url_v3: String,
+
+    /// GSA server to log in against
+    #[arg(long, global = true, env = "GSA_URL", default_value = icloud_auth::DEFAULT_GSA_URL)]
+    gsa_url: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Generate anisette headers and manage the provisioning data under `<data-dir>/anisette`
+    #[command(subcommand)]
+    Anisette(AnisetteCommand),
+    /// Log in interactively, going through 2FA, and save the session under `<data-dir>/accounts`
+    Login {
+        /// Apple ID, prompted for if missing
+        #[arg(long)]
+        username: Option<String>,
+        /// Password, prompted for without echo if missing
+        #[arg(long, env = "APPLE_ID_PASSWORD", hide_env_values = true)]
+        password: Option<String>,
+        /// Get the code on a trusted phone number instead of a trusted device
+        #[arg(long)]
+        phone: bool,
+        /// Id of the trusted phone number, asked for when there are several
+        #[arg(long, requires = "phone")]
+        phone_id: Option<u32>,
+        /// Call the phone number instead of sending an SMS
+        #[arg(long, requires = "phone")]
+        voice: bool,
+    },
+    /// Fetch a token for an app, e.g. `com.apple.gs.xcode.auth`
+    Apptoken {
+        app: String,
+        /// Account to use, defaults to the first saved one
+        #[arg(long)]
+        adsid: Option<String>,
+    },
+    /// Sign a .app with a .p12 certificate
+    #[cfg(feature = "codesign")]
+    Sign {
+        app: String,
+        #[arg(long)]
+        p12: PathBuf,
+        #[arg(long, env = "APPLE_P12_PASSWORD", hide_env_values = true, default_value = "")]
+        p12_password: String,
+        #[arg(long)]
+        bundle_id: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum AnisetteCommand {
+    /// Print the headers as a JSON object
+    Headers {
+        #[arg(long, value_enum, default_value_t = Provider::Auto)]
+        provider: Provider,
+    },
+    /// Provision the machine if it isn't already
+    Provision {
+        #[arg(long, value_enum, default_value_t = Provider::Auto)]
+        provider: Provider,
+    },
+    /// Delete the provisioning data, so the next request provisions a new machine
+    Reset,
+    /// Save the provisioned machine of `local` or `remote-v3` to a file
+    Export {
+        output: PathBuf,
+        #[arg(long, value_enum)]
+        provider: Provider,
+    },
+    /// Replace the provisioned machine of `local` or `remote-v3` with one saved by `export`
+    Import {
+        input: PathBuf,
+        #[arg(long, value_enum)]
+        provider: Provider,
+    },
+}
+
+fn default_data_dir() -> PathBuf {
+    match std::env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(".apple-private-apis"),
+        None => PathBuf::from(".apple-private-apis"),
+    }
+}
+
+impl Cli {
+    fn anisette_configuration(&self, configuration_path: PathBuf) -> AnisetteConfiguration {
+        AnisetteConfiguration::new()
+            .set_anisette_url(self.anisette_url.clone())
+            .set_anisette_url_v3(self.anisette_url_v3.clone())
+            .set_configuration_path(configuration_path)
+    }
+
+    fn manager(&self) -> AccountManager {
+        let root = self.data_dir.join("accounts");
+        AccountManager::new(root, self.anisette_configuration(PathBuf::new()))
+            .set_account_configuration(AccountConfiguration::new().set_base_url(self.gsa_url.clone()))
+    }
+
+    async fn run(self) -> Result<Value, Error> {
+        match &self.command {
+            Command::Anisette(command) => {
+                let config = self.anisette_configuration(self.data_dir.join("anisette"));
+                match command {
+                    AnisetteCommand::Headers { provider } => anisette::headers(config, *provider).await,
+                    AnisetteCommand::Provision { provider } => {
+                        anisette::provision(config, *provider).await
+                    }
+                    AnisetteCommand::Reset => anisette::reset(config),
+                    AnisetteCommand::Export { output, provider } => {
+                        anisette::export(config, *provider, output)
+                    }
+                    AnisetteCommand::Import { input, provider } => {
+                        anisette::import(config, *provider, input)
+                    }
+                }
+            }
+            Command::Login {
+                username,
+                password,
+                phone,
+                phone_id,
+                voice,
+            } => {
+                let second_factor = account::SecondFactor {
+                    phone: *phone,
+                    phone_id: *phone_id,
+                    voice: *voice,
+                };
+                account::login(self.manager(), username.clone(), password.clone(), second_factor)
+                    .await
+            }
+            Command::Apptoken { app, adsid } => {
+                account::app_token(self.manager(), app, adsid.clone()).await
+            }
+            #[cfg(feature = "codesign")]
+            Command::Sign {
+                app,
+                p12,
+                p12_password,
+                bundle_id,
+            } => sign::sign(app, p12, p12_password, bundle_id),
+        }
+    }
+}
+
+/// Asks for a line on stderr
+pub fn prompt(label: &str) -> Result<String, Error> {
+    eprint!("{label}");
+    io::stderr().flush()?;
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let (output, code) = match cli.run().await {
+        Ok(value) => (value, ExitCode::SUCCESS),
+        Err(err) => {
+            let mut output = json!({ "error": err.to_string() });
+            if let Some(code) = match &err {
+                Error::Auth(err) => err.gsa_code(),
+                _ => None,
+            } {
+                output["gsa_code"] = json!(code);
+            }
+            (output, ExitCode::FAILURE)
+        }
+    };
+
+    println!("{}", serde_json::to_string_pretty(&output).unwrap_or_default());
+    code
+}
@@ -0,0 +1,12 @@
+use std::{fs, path::Path};
+
+use serde_json::{json, Value};
+
+use crate::Error;
+
+pub fn sign(app: &str, p12: &Path, p12_password: &str, bundle_id: &str) -> Result<Value, Error> {
+    let certificate = fs::read(p12)?;
+    apple_codesign_wrapper::sign_app(app, bundle_id, &certificate, p12_password)
+        .map_err(|err| Error::Codesign(err.to_string()))?;
+    Ok(json!({ "app": app, "bundle_id": bundle_id, "signed": true }))
+}
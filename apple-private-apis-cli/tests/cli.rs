@@ -0,0 +1,85 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        process::{Command, Output},
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use serde_json::Value;
+
+    fn temp_dir() -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        std::env::temp_dir().join(format!("apple-private-apis-cli-{}-{nanos}", std::process::id()))
+    }
+
+    fn run(data_dir: &Path, args: &[&str]) -> (Output, Value) {
+        let output = Command::new(env!("CARGO_BIN_EXE_apple-private-apis"))
+            .arg("--data-dir")
+            .arg(data_dir)
+            .args(args)
+            .output()
+            .unwrap();
+        let json = serde_json::from_slice(&output.stdout).unwrap();
+        (output, json)
+    }
+
+    #[test]
+    fn anisette_reset() {
+        let data_dir = temp_dir();
+        let anisette = data_dir.join("anisette");
+        fs::create_dir_all(anisette.join("lib")).unwrap();
+        fs::write(anisette.join("adi.pb"), b"adi").unwrap();
+        fs::write(anisette.join("state.plist"), b"state").unwrap();
+
+        let (output, json) = run(&data_dir, &["anisette", "reset"]);
+        assert!(output.status.success());
+        assert_eq!(json["removed"], serde_json::json!(["adi.pb", "state.plist"]));
+        assert!(!anisette.join("adi.pb").exists());
+        // the downloaded libraries are kept
+        assert!(anisette.join("lib").exists());
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn anisette_export_import() {
+        let data_dir = temp_dir();
+        let anisette = data_dir.join("anisette");
+        let bundle = data_dir.join("machine.plist");
+        let bundle_arg = bundle.to_str().unwrap();
+
+        let (output, json) = run(&data_dir, &["anisette", "export", bundle_arg, "--provider", "local"]);
+        assert_eq!(output.status.code(), Some(1));
+        assert!(json["error"].as_str().unwrap().contains("not provisioned"));
+
+        fs::create_dir_all(&anisette).unwrap();
+        fs::write(anisette.join("adi.pb"), b"adi").unwrap();
+        fs::write(anisette.join("identifier"), b"0123456789abcdef").unwrap();
+        let (output, _) = run(&data_dir, &["anisette", "export", bundle_arg, "--provider", "local"]);
+        assert!(output.status.success());
+
+        let (output, _) = run(&data_dir, &["anisette", "import", bundle_arg, "--provider", "remote-v3"]);
+        assert!(output.status.success());
+        assert!(anisette.join("state.plist").exists());
+
+        let (output, json) = run(&data_dir, &["anisette", "export", bundle_arg, "--provider", "remote"]);
+        assert_eq!(output.status.code(), Some(1));
+        assert!(json["error"].as_str().unwrap().contains("remote"));
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn apptoken_without_account() {
+        let data_dir = temp_dir();
+
+        let (output, json) = run(&data_dir, &["apptoken", "com.apple.gs.xcode.auth"]);
+        assert_eq!(output.status.code(), Some(1));
+        assert!(json["error"].as_str().unwrap().contains("login"));
+    }
+}
@@ -0,0 +1,27 @@
+use std::{env, path::PathBuf};
+
+fn main() {
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    // the checked-in header is regenerated explicitly (see the README), this one only checks that
+    // cbindgen can still parse the crate
+    let header = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml"))
+        .map_err(|err| err.to_string())
+        .and_then(|config| {
+            cbindgen::Builder::new()
+                .with_crate(&crate_dir)
+                .with_config(config)
+                .generate()
+                .map_err(|err| err.to_string())
+        });
+    match header {
+        Ok(header) => {
+            header.write_to_file(out_dir.join("apple_private_apis.h"));
+        }
+        Err(err) => println!("cargo:warning=Failed to generate the C header: {err}"),
+    }
+}
@@ -0,0 +1,286 @@
+#ifndef APPLE_PRIVATE_APIS_H
+#define APPLE_PRIVATE_APIS_H
+
+/* Generated by cbindgen from apple-private-apis-ffi, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum AppleStatus {
+  APPLE_STATUS_OK = 0,
+  APPLE_STATUS_INVALID_ARGUMENT,
+  APPLE_STATUS_INCORRECT_CREDENTIALS,
+  APPLE_STATUS_ACCOUNT_LOCKED,
+  APPLE_STATUS_BAD2FA_CODE,
+  APPLE_STATUS_SECOND_FACTOR_REQUIRED,
+  APPLE_STATUS_ANISETTE_REJECTED,
+  APPLE_STATUS_RATE_LIMITED,
+  APPLE_STATUS_REAUTH_REQUIRED,
+  APPLE_STATUS_INVALID_LOGIN_STEP,
+  APPLE_STATUS_NO_TRUSTED_PHONE_NUMBERS,
+  APPLE_STATUS_FAILED,
+} AppleStatus;
+
+/**
+ * What the login needs next
+ */
+typedef enum AppleLoginStep {
+  APPLE_LOGIN_STEP_LOGGED_IN = 0,
+  APPLE_LOGIN_STEP_NEEDS_DEVICE2FA,
+  APPLE_LOGIN_STEP_NEEDS2FA_VERIFICATION,
+  APPLE_LOGIN_STEP_NEEDS_SMS2FA,
+  APPLE_LOGIN_STEP_NEEDS_SMS2FA_VERIFICATION,
+  APPLE_LOGIN_STEP_NEEDS_EXTRA_STEP,
+  APPLE_LOGIN_STEP_NEEDS_LOGIN,
+} AppleLoginStep;
+
+/**
+ * A logged in account, see `icloud_auth::AppleAccount`
+ */
+typedef struct AppleAccount AppleAccount;
+
+/**
+ * A step-wise login, see `icloud_auth::LoginFlow`
+ */
+typedef struct AppleLoginFlow AppleLoginFlow;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * The message of the last error on the calling thread, or null.
+ *
+ * The string is owned by the library and valid until the next call on the same thread.
+ */
+const char *apple_last_error(void);
+
+/**
+ * Releases a string returned by the library
+ *
+ * # Safety
+ *
+ * `value` must be null or a string returned by the library that wasn't freed yet.
+ */
+void apple_string_free(char *value);
+
+/**
+ * Releases a buffer returned by the library
+ *
+ * # Safety
+ *
+ * `data` must be null or a buffer returned by the library with its length `len`, that wasn't freed yet.
+ */
+void apple_bytes_free(uint8_t *data,
+                      size_t len);
+
+/**
+ * Resumes an account from a session exported with [`apple_account_export_session`]
+ *
+ * # Safety
+ *
+ * `data` must point to `len` bytes, the strings must be valid C strings (`gsa_url` may be null)
+ * and `out_account` a valid pointer.
+ */
+enum AppleStatus apple_account_from_session(const uint8_t *data,
+                                            size_t len,
+                                            const char *configuration_path,
+                                            const char *gsa_url,
+                                            struct AppleAccount **out_account);
+
+/**
+ * Exports the session of the account, released with [`crate::apple_bytes_free`].
+ *
+ * It holds the account's tokens and should be stored encrypted.
+ *
+ * # Safety
+ *
+ * `account` must be a live account and `out_data`/`out_len` valid pointers.
+ */
+enum AppleStatus apple_account_export_session(const struct AppleAccount *account,
+                                              uint8_t **out_data,
+                                              size_t *out_len);
+
+/**
+ * Gets a token for `app_name`, or [`AppleStatus::ReauthRequired`] once the session is expiring.
+ *
+ * `out_token` is released with [`crate::apple_string_free`].
+ *
+ * # Safety
+ *
+ * `account` must be a live account, `app_name` a valid C string and `out_token` a valid pointer.
+ */
+enum AppleStatus apple_account_app_token(struct AppleAccount *account,
+                                         const char *app_name,
+                                         char **out_token);
+
+/**
+ * # Safety
+ *
+ * `account` must be null or a live account, which can't be used afterwards.
+ */
+void apple_account_free(struct AppleAccount *account);
+
+/**
+ * Generates anisette headers, provisioning the machine in `configuration_path` first if needed.
+ *
+ * `out_json` receives a JSON object of header names to values.
+ *
+ * # Safety
+ *
+ * `configuration_path` must be a valid C string and `out_json` a valid pointer.
+ */
+enum AppleStatus apple_anisette_headers(const char *configuration_path, char **out_json);
+
+#if defined(APPLE_PRIVATE_APIS_CODESIGN)
+/**
+ * Signs the .app at `app_path` with a .p12 certificate, see `apple_codesign_wrapper::sign_app`
+ *
+ * # Safety
+ *
+ * The strings must be valid C strings and `certificate` must point to `certificate_len` bytes.
+ */
+enum AppleStatus apple_sign_app(const char *app_path,
+                                const char *bundle_id,
+                                const uint8_t *certificate,
+                                size_t certificate_len,
+                                const char *certificate_password);
+#endif
+
+/**
+ * Starts a login with anisette data provisioned in `configuration_path`.
+ *
+ * `gsa_url` may be null to talk to Apple's servers.
+ *
+ * # Safety
+ *
+ * `configuration_path` must be a valid C string, `gsa_url` null or a valid C string, and `out_flow` a valid pointer.
+ */
+enum AppleStatus apple_login_flow_new(const char *configuration_path,
+                                      const char *gsa_url,
+                                      struct AppleLoginFlow **out_flow);
+
+/**
+ * Continues a flow suspended with [`apple_login_flow_suspend`], with the same key
+ *
+ * # Safety
+ *
+ * `data` must point to `len` bytes, `key` to `key_len` bytes, the strings must be valid C strings
+ * (`gsa_url` may be null) and `out_flow` a valid pointer.
+ */
+enum AppleStatus apple_login_flow_resume(const uint8_t *data,
+                                         size_t len,
+                                         const uint8_t *key,
+                                         size_t key_len,
+                                         const char *configuration_path,
+                                         const char *gsa_url,
+                                         struct AppleLoginFlow **out_flow);
+
+/**
+ * Serializes the flow so it can be continued later, even in another process.
+ *
+ * The flow holds the password, so the buffer is sealed with AES-256-GCM under `key`, which
+ * must be 32 bytes long. It must be released with [`crate::apple_bytes_free`].
+ *
+ * # Safety
+ *
+ * `flow` must be a live flow, `key` must point to `key_len` bytes and `out_data`/`out_len` be valid pointers.
+ */
+enum AppleStatus apple_login_flow_suspend(const struct AppleLoginFlow *flow,
+                                          const uint8_t *key,
+                                          size_t key_len,
+                                          uint8_t **out_data,
+                                          size_t *out_len);
+
+/**
+ * # Safety
+ *
+ * `flow` must be null or a live flow, which can't be used afterwards.
+ */
+void apple_login_flow_free(struct AppleLoginFlow *flow);
+
+/**
+ * The step of the flow, `APPLE_LOGIN_STEP_NEEDS_LOGIN` with the last error set when `flow` is null
+ *
+ * # Safety
+ *
+ * `flow` must be null or a live flow.
+ */
+enum AppleLoginStep apple_login_flow_step(const struct AppleLoginFlow *flow);
+
+/**
+ * # Safety
+ *
+ * `flow` must be a live flow, the strings valid C strings and `out_step` a valid pointer.
+ */
+enum AppleStatus apple_login_flow_begin(struct AppleLoginFlow *flow,
+                                        const char *username,
+                                        const char *password,
+                                        enum AppleLoginStep *out_step);
+
+/**
+ * # Safety
+ *
+ * `flow` must be a live flow and `out_step` a valid pointer.
+ */
+enum AppleStatus apple_login_flow_request_device_code(struct AppleLoginFlow *flow,
+                                                      enum AppleLoginStep *out_step);
+
+/**
+ * # Safety
+ *
+ * `flow` must be a live flow, `code` a valid C string and `out_step` a valid pointer.
+ */
+enum AppleStatus apple_login_flow_submit_device_code(struct AppleLoginFlow *flow,
+                                                     const char *code,
+                                                     enum AppleLoginStep *out_step);
+
+/**
+ * Lists the trusted phone numbers as a JSON array, released with [`crate::apple_string_free`]
+ *
+ * # Safety
+ *
+ * `flow` must be a live flow and `out_json` a valid pointer.
+ */
+enum AppleStatus apple_login_flow_trusted_phone_numbers(const struct AppleLoginFlow *flow,
+                                                        char **out_json);
+
+/**
+ * Sends a code to the trusted phone number `phone_id`, by voice call if `voice` is set or by SMS otherwise
+ *
+ * # Safety
+ *
+ * `flow` must be a live flow and `out_step` a valid pointer.
+ */
+enum AppleStatus apple_login_flow_request_phone_code(struct AppleLoginFlow *flow,
+                                                     uint32_t phone_id,
+                                                     bool voice,
+                                                     enum AppleLoginStep *out_step);
+
+/**
+ * # Safety
+ *
+ * `flow` must be a live flow, `code` a valid C string and `out_step` a valid pointer.
+ */
+enum AppleStatus apple_login_flow_submit_sms_code(struct AppleLoginFlow *flow,
+                                                  const char *code,
+                                                  enum AppleLoginStep *out_step);
+
+/**
+ * Finishes the flow, which is freed whether this succeeds or not
+ *
+ * # Safety
+ *
+ * `flow` must be a live flow, which can't be used afterwards, and `out_account` a valid pointer.
+ */
+enum AppleStatus apple_login_flow_into_account(struct AppleLoginFlow *flow,
+                                               struct AppleAccount **out_account);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* APPLE_PRIVATE_APIS_H */
@@ -0,0 +1,124 @@
+use std::ffi::c_char;
+
+use icloud_auth::AccountSession;
+
+use crate::{
+    account_configuration, anisette_configuration, catch_panic, finish, into_c_string, invalid_argument,
+    optional_str, required_bytes, required_str, runtime, write_bytes, AppleStatus,
+};
+
+/// A logged in account, see `icloud_auth::AppleAccount`
+pub struct AppleAccount(pub(crate) icloud_auth::AppleAccount);
+
+/// Resumes an account from a session exported with [`apple_account_export_session`]
+///
+/// # Safety
+///
+/// `data` must point to `len` bytes, the strings must be valid C strings (`gsa_url` may be null)
+/// and `out_account` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn apple_account_from_session(
+    data: *const u8,
+    len: usize,
+    configuration_path: *const c_char,
+    gsa_url: *const c_char,
+    out_account: *mut *mut AppleAccount,
+) -> AppleStatus {
+    catch_panic(AppleStatus::Failed, || {
+        let Some(data) = required_bytes(data, len) else {
+            return invalid_argument("data");
+        };
+        let Some(configuration_path) = required_str(configuration_path) else {
+            return invalid_argument("configuration_path");
+        };
+        let Ok(gsa_url) = optional_str(gsa_url) else {
+            return invalid_argument("gsa_url");
+        };
+        if out_account.is_null() {
+            return invalid_argument("out_account");
+        }
+
+        let result = runtime().block_on(async {
+            let session = AccountSession::from_bytes(data)?;
+            let anisette =
+                icloud_auth::anisette::AnisetteData::new(anisette_configuration(configuration_path))
+                    .await?;
+            icloud_auth::AppleAccount::from_session_with_configuration(
+                session,
+                anisette,
+                account_configuration(gsa_url),
+            )
+        });
+
+        finish(result, |account| {
+            *out_account = Box::into_raw(Box::new(AppleAccount(account)))
+        })
+    })
+}
+
+/// Exports the session of the account, released with [`crate::apple_bytes_free`].
+///
+/// It holds the account's tokens and should be stored encrypted.
+///
+/// # Safety
+///
+/// `account` must be a live account and `out_data`/`out_len` valid pointers.
+#[no_mangle]
+pub unsafe extern "C" fn apple_account_export_session(
+    account: *const AppleAccount,
+    out_data: *mut *mut u8,
+    out_len: *mut usize,
+) -> AppleStatus {
+    catch_panic(AppleStatus::Failed, || {
+        let Some(account) = account.as_ref() else {
+            return invalid_argument("account");
+        };
+        if out_data.is_null() || out_len.is_null() {
+            return invalid_argument("out_data");
+        }
+
+        let result = account.0.export_session().and_then(|session| session.to_bytes());
+        finish(result, |data| write_bytes(data, out_data, out_len))
+    })
+}
+
+/// Gets a token for `app_name`, or [`AppleStatus::ReauthRequired`] once the session is expiring.
+///
+/// `out_token` is released with [`crate::apple_string_free`].
+///
+/// # Safety
+///
+/// `account` must be a live account, `app_name` a valid C string and `out_token` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn apple_account_app_token(
+    account: *mut AppleAccount,
+    app_name: *const c_char,
+    out_token: *mut *mut c_char,
+) -> AppleStatus {
+    catch_panic(AppleStatus::Failed, || {
+        let Some(account) = account.as_mut() else {
+            return invalid_argument("account");
+        };
+        let Some(app_name) = required_str(app_name) else {
+            return invalid_argument("app_name");
+        };
+        if out_token.is_null() {
+            return invalid_argument("out_token");
+        }
+
+        let result = runtime().block_on(account.0.app_token(app_name));
+        finish(result, |token| *out_token = into_c_string(token.auth_token))
+    })
+}
+
+/// # Safety
+///
+/// `account` must be null or a live account, which can't be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn apple_account_free(account: *mut AppleAccount) {
+    catch_panic((), || {
+        if !account.is_null() {
+            drop(Box::from_raw(account));
+        }
+    })
+}
@@ -0,0 +1,37 @@
+use std::ffi::c_char;
+
+use icloud_auth::Error;
+use omnisette::AnisetteHeaders;
+
+use crate::{anisette_configuration, catch_panic, finish, into_c_string, invalid_argument, required_str, runtime, AppleStatus};
+
+/// Generates anisette headers, provisioning the machine in `configuration_path` first if needed.
+///
+/// `out_json` receives a JSON object of header names to values.
+///
+/// # Safety
+///
+/// `configuration_path` must be a valid C string and `out_json` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn apple_anisette_headers(
+    configuration_path: *const c_char,
+    out_json: *mut *mut c_char,
+) -> AppleStatus {
+    catch_panic(AppleStatus::Failed, || {
+        let Some(configuration_path) = required_str(configuration_path) else {
+            return invalid_argument("configuration_path");
+        };
+        if out_json.is_null() {
+            return invalid_argument("out_json");
+        }
+
+        let config = anisette_configuration(configuration_path);
+        let result = runtime().block_on(async {
+            let mut provider = AnisetteHeaders::get_anisette_headers_provider(config)?.provider;
+            let headers = provider.get_authentication_headers().await?;
+            Ok::<_, Error>(serde_json::to_string(&headers)?)
+        });
+
+        finish(result, |json| *out_json = into_c_string(json))
+    })
+}
@@ -0,0 +1,43 @@
+use std::ffi::c_char;
+
+use crate::{catch_panic, clear_last_error, invalid_argument, required_bytes, required_str, set_last_error, AppleStatus};
+
+/// Signs the .app at `app_path` with a .p12 certificate, see `apple_codesign_wrapper::sign_app`
+///
+/// # Safety
+///
+/// The strings must be valid C strings and `certificate` must point to `certificate_len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn apple_sign_app(
+    app_path: *const c_char,
+    bundle_id: *const c_char,
+    certificate: *const u8,
+    certificate_len: usize,
+    certificate_password: *const c_char,
+) -> AppleStatus {
+    catch_panic(AppleStatus::Failed, || {
+        let Some(app_path) = required_str(app_path) else {
+            return invalid_argument("app_path");
+        };
+        let Some(bundle_id) = required_str(bundle_id) else {
+            return invalid_argument("bundle_id");
+        };
+        let Some(certificate) = required_bytes(certificate, certificate_len) else {
+            return invalid_argument("certificate");
+        };
+        let Some(certificate_password) = required_str(certificate_password) else {
+            return invalid_argument("certificate_password");
+        };
+
+        match apple_codesign_wrapper::sign_app(app_path, bundle_id, certificate, certificate_password) {
+            Ok(()) => {
+                clear_last_error();
+                AppleStatus::Ok
+            }
+            Err(err) => {
+                set_last_error(err.to_string());
+                AppleStatus::Failed
+            }
+        }
+    })
+}
@@ -0,0 +1,359 @@
+use std::ffi::c_char;
+
+use icloud_auth::{AesGcmCipher, DeliveryMode, Error, LoginFlow, LoginFlowSnapshot, LoginState};
+
+use crate::{
+    account_configuration, anisette_configuration, catch_panic, finish, into_c_string, invalid_argument,
+    optional_str, required_bytes, required_key, required_str, runtime, write_bytes, AppleAccount,
+    AppleStatus,
+};
+
+/// A step-wise login, see `icloud_auth::LoginFlow`
+pub struct AppleLoginFlow(LoginFlow);
+
+/// What the login needs next
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AppleLoginStep {
+    LoggedIn = 0,
+    NeedsDevice2fa,
+    Needs2faVerification,
+    NeedsSms2fa,
+    NeedsSms2faVerification,
+    NeedsExtraStep,
+    NeedsLogin,
+}
+
+impl From<&LoginState> for AppleLoginStep {
+    fn from(state: &LoginState) -> Self {
+        match state {
+            LoginState::LoggedIn => AppleLoginStep::LoggedIn,
+            LoginState::NeedsDevice2FA => AppleLoginStep::NeedsDevice2fa,
+            LoginState::Needs2FAVerification => AppleLoginStep::Needs2faVerification,
+            LoginState::NeedsSMS2FA => AppleLoginStep::NeedsSms2fa,
+            LoginState::NeedsSMS2FAVerification(_) => AppleLoginStep::NeedsSms2faVerification,
+            LoginState::NeedsExtraStep(_) => AppleLoginStep::NeedsExtraStep,
+            LoginState::NeedsLogin => AppleLoginStep::NeedsLogin,
+        }
+    }
+}
+
+/// Runs a step of `flow`, writing the new step to `out_step`
+unsafe fn step(
+    flow: *mut AppleLoginFlow,
+    out_step: *mut AppleLoginStep,
+    run: impl FnOnce(&mut LoginFlow) -> Result<LoginState, Error>,
+) -> AppleStatus {
+    let Some(flow) = flow.as_mut() else {
+        return invalid_argument("flow");
+    };
+    if out_step.is_null() {
+        return invalid_argument("out_step");
+    }
+
+    finish(run(&mut flow.0), |state| *out_step = AppleLoginStep::from(&state))
+}
+
+/// Starts a login with anisette data provisioned in `configuration_path`.
+///
+/// `gsa_url` may be null to talk to Apple's servers.
+///
+/// # Safety
+///
+/// `configuration_path` must be a valid C string, `gsa_url` null or a valid C string, and `out_flow` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn apple_login_flow_new(
+    configuration_path: *const c_char,
+    gsa_url: *const c_char,
+    out_flow: *mut *mut AppleLoginFlow,
+) -> AppleStatus {
+    catch_panic(AppleStatus::Failed, || {
+        let Some(configuration_path) = required_str(configuration_path) else {
+            return invalid_argument("configuration_path");
+        };
+        let Ok(gsa_url) = optional_str(gsa_url) else {
+            return invalid_argument("gsa_url");
+        };
+        if out_flow.is_null() {
+            return invalid_argument("out_flow");
+        }
+
+        let result = runtime().block_on(async {
+            let anisette =
+                icloud_auth::anisette::AnisetteData::new(anisette_configuration(configuration_path))
+                    .await?;
+            icloud_auth::AppleAccount::new_with_configuration(anisette, account_configuration(gsa_url))
+        });
+
+        finish(result, |account| {
+            *out_flow = Box::into_raw(Box::new(AppleLoginFlow(LoginFlow::new(account))))
+        })
+    })
+}
+
+/// Continues a flow suspended with [`apple_login_flow_suspend`], with the same key
+///
+/// # Safety
+///
+/// `data` must point to `len` bytes, `key` to `key_len` bytes, the strings must be valid C strings
+/// (`gsa_url` may be null) and `out_flow` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn apple_login_flow_resume(
+    data: *const u8,
+    len: usize,
+    key: *const u8,
+    key_len: usize,
+    configuration_path: *const c_char,
+    gsa_url: *const c_char,
+    out_flow: *mut *mut AppleLoginFlow,
+) -> AppleStatus {
+    catch_panic(AppleStatus::Failed, || {
+        let Some(data) = required_bytes(data, len) else {
+            return invalid_argument("data");
+        };
+        let Some(key) = required_key(key, key_len) else {
+            return invalid_argument("key");
+        };
+        let Some(configuration_path) = required_str(configuration_path) else {
+            return invalid_argument("configuration_path");
+        };
+        let Ok(gsa_url) = optional_str(gsa_url) else {
+            return invalid_argument("gsa_url");
+        };
+        if out_flow.is_null() {
+            return invalid_argument("out_flow");
+        }
+
+        let result = runtime().block_on(async {
+            let snapshot = LoginFlowSnapshot::from_encrypted_bytes(data, &AesGcmCipher::new(key))?;
+            let anisette =
+                icloud_auth::anisette::AnisetteData::new(anisette_configuration(configuration_path))
+                    .await?;
+            let account = icloud_auth::AppleAccount::new_with_configuration(
+                anisette,
+                account_configuration(gsa_url),
+            )?;
+            Ok(LoginFlow::resume(snapshot, account))
+        });
+
+        finish(result, |flow| {
+            *out_flow = Box::into_raw(Box::new(AppleLoginFlow(flow)))
+        })
+    })
+}
+
+/// Serializes the flow so it can be continued later, even in another process.
+///
+/// The flow holds the password, so the buffer is sealed with AES-256-GCM under `key`, which
+/// must be 32 bytes long. It must be released with [`crate::apple_bytes_free`].
+///
+/// # Safety
+///
+/// `flow` must be a live flow, `key` must point to `key_len` bytes and `out_data`/`out_len` be valid pointers.
+#[no_mangle]
+pub unsafe extern "C" fn apple_login_flow_suspend(
+    flow: *const AppleLoginFlow,
+    key: *const u8,
+    key_len: usize,
+    out_data: *mut *mut u8,
+    out_len: *mut usize,
+) -> AppleStatus {
+    catch_panic(AppleStatus::Failed, || {
+        let Some(flow) = flow.as_ref() else {
+            return invalid_argument("flow");
+        };
+        let Some(key) = required_key(key, key_len) else {
+            return invalid_argument("key");
+        };
+        if out_data.is_null() || out_len.is_null() {
+            return invalid_argument("out_data");
+        }
+
+        let cipher = AesGcmCipher::new(key);
+        finish(flow.0.suspend().to_encrypted_bytes(&cipher), |data| {
+            write_bytes(data, out_data, out_len)
+        })
+    })
+}
+
+/// # Safety
+///
+/// `flow` must be null or a live flow, which can't be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn apple_login_flow_free(flow: *mut AppleLoginFlow) {
+    catch_panic((), || {
+        if !flow.is_null() {
+            drop(Box::from_raw(flow));
+        }
+    })
+}
+
+/// The step of the flow, `APPLE_LOGIN_STEP_NEEDS_LOGIN` with the last error set when `flow` is null
+///
+/// # Safety
+///
+/// `flow` must be null or a live flow.
+#[no_mangle]
+pub unsafe extern "C" fn apple_login_flow_step(flow: *const AppleLoginFlow) -> AppleLoginStep {
+    if flow.is_null() {
+        invalid_argument("flow");
+        return AppleLoginStep::NeedsLogin;
+    }
+    catch_panic(AppleLoginStep::NeedsLogin, || AppleLoginStep::from((*flow).0.state()))
+}
+
+/// # Safety
+///
+/// `flow` must be a live flow, the strings valid C strings and `out_step` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn apple_login_flow_begin(
+    flow: *mut AppleLoginFlow,
+    username: *const c_char,
+    password: *const c_char,
+    out_step: *mut AppleLoginStep,
+) -> AppleStatus {
+    catch_panic(AppleStatus::Failed, || {
+        let Some(username) = required_str(username) else {
+            return invalid_argument("username");
+        };
+        let Some(password) = required_str(password) else {
+            return invalid_argument("password");
+        };
+
+        step(flow, out_step, |flow| {
+            runtime().block_on(flow.begin(username, password))
+        })
+    })
+}
+
+/// # Safety
+///
+/// `flow` must be a live flow and `out_step` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn apple_login_flow_request_device_code(
+    flow: *mut AppleLoginFlow,
+    out_step: *mut AppleLoginStep,
+) -> AppleStatus {
+    catch_panic(AppleStatus::Failed, || {
+        step(flow, out_step, |flow| {
+            runtime().block_on(flow.request_device_code())
+        })
+    })
+}
+
+/// # Safety
+///
+/// `flow` must be a live flow, `code` a valid C string and `out_step` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn apple_login_flow_submit_device_code(
+    flow: *mut AppleLoginFlow,
+    code: *const c_char,
+    out_step: *mut AppleLoginStep,
+) -> AppleStatus {
+    catch_panic(AppleStatus::Failed, || {
+        let Some(code) = required_str(code) else {
+            return invalid_argument("code");
+        };
+
+        step(flow, out_step, |flow| {
+            runtime().block_on(flow.submit_device_code(code.to_string()))
+        })
+    })
+}
+
+/// Lists the trusted phone numbers as a JSON array, released with [`crate::apple_string_free`]
+///
+/// # Safety
+///
+/// `flow` must be a live flow and `out_json` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn apple_login_flow_trusted_phone_numbers(
+    flow: *const AppleLoginFlow,
+    out_json: *mut *mut c_char,
+) -> AppleStatus {
+    catch_panic(AppleStatus::Failed, || {
+        let Some(flow) = flow.as_ref() else {
+            return invalid_argument("flow");
+        };
+        if out_json.is_null() {
+            return invalid_argument("out_json");
+        }
+
+        let result = runtime().block_on(async {
+            let numbers = flow.0.trusted_phone_numbers().await?;
+            Ok::<_, Error>(serde_json::to_string(&numbers)?)
+        });
+
+        finish(result, |json| *out_json = into_c_string(json))
+    })
+}
+
+/// Sends a code to the trusted phone number `phone_id`, by voice call if `voice` is set or by SMS otherwise
+///
+/// # Safety
+///
+/// `flow` must be a live flow and `out_step` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn apple_login_flow_request_phone_code(
+    flow: *mut AppleLoginFlow,
+    phone_id: u32,
+    voice: bool,
+    out_step: *mut AppleLoginStep,
+) -> AppleStatus {
+    catch_panic(AppleStatus::Failed, || {
+        let mode = if voice {
+            DeliveryMode::Voice
+        } else {
+            DeliveryMode::Sms
+        };
+
+        step(flow, out_step, |flow| {
+            runtime().block_on(flow.request_phone_code(phone_id, mode))
+        })
+    })
+}
+
+/// # Safety
+///
+/// `flow` must be a live flow, `code` a valid C string and `out_step` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn apple_login_flow_submit_sms_code(
+    flow: *mut AppleLoginFlow,
+    code: *const c_char,
+    out_step: *mut AppleLoginStep,
+) -> AppleStatus {
+    catch_panic(AppleStatus::Failed, || {
+        let Some(code) = required_str(code) else {
+            return invalid_argument("code");
+        };
+
+        step(flow, out_step, |flow| {
+            runtime().block_on(flow.submit_sms_code(code.to_string()))
+        })
+    })
+}
+
+/// Finishes the flow, which is freed whether this succeeds or not
+///
+/// # Safety
+///
+/// `flow` must be a live flow, which can't be used afterwards, and `out_account` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn apple_login_flow_into_account(
+    flow: *mut AppleLoginFlow,
+    out_account: *mut *mut AppleAccount,
+) -> AppleStatus {
+    catch_panic(AppleStatus::Failed, || {
+        if flow.is_null() {
+            return invalid_argument("flow");
+        }
+        let flow = Box::from_raw(flow);
+        if out_account.is_null() {
+            return invalid_argument("out_account");
+        }
+
+        finish(flow.0.into_account(), |account| {
+            *out_account = Box::into_raw(Box::new(AppleAccount(account)))
+        })
+    })
+}
@@ -0,0 +1,219 @@
+//! A C ABI over omnisette and icloud_auth, for hosts written in Swift, C# or C.
+//!
+//! Every object is an opaque handle created by a `*_new`-style function and released by the
+//! matching `*_free` function. Functions return an [`AppleStatus`]; when it isn't
+//! `APPLE_STATUS_OK`, [`apple_last_error`] describes what went wrong. Strings and byte buffers
+//! handed out by the library must be released with [`apple_string_free`] and [`apple_bytes_free`].
+//!
+//! The header is checked in at `include/apple_private_apis.h`, see the README to regenerate it.
+
+mod account;
+mod anisette;
+#[cfg(feature = "codesign")]
+mod codesign;
+mod flow;
+
+pub use account::*;
+pub use anisette::*;
+#[cfg(feature = "codesign")]
+pub use codesign::*;
+pub use flow::*;
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+    ptr,
+    sync::OnceLock,
+};
+
+use icloud_auth::{AccountConfiguration, AnisetteConfiguration, Error};
+use tokio::runtime::{Builder, Runtime};
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AppleStatus {
+    Ok = 0,
+    InvalidArgument,
+    IncorrectCredentials,
+    AccountLocked,
+    Bad2faCode,
+    SecondFactorRequired,
+    AnisetteRejected,
+    RateLimited,
+    ReauthRequired,
+    InvalidLoginStep,
+    NoTrustedPhoneNumbers,
+    Failed,
+}
+
+impl From<&Error> for AppleStatus {
+    fn from(err: &Error) -> Self {
+        match err {
+            Error::IncorrectCredentials(..) => AppleStatus::IncorrectCredentials,
+            Error::AccountLocked(..) => AppleStatus::AccountLocked,
+            Error::Bad2faCode(..) => AppleStatus::Bad2faCode,
+            Error::SecondFactorRequired(..) => AppleStatus::SecondFactorRequired,
+            Error::AnisetteRejected(..) => AppleStatus::AnisetteRejected,
+            Error::RateLimited(..) => AppleStatus::RateLimited,
+            Error::ReauthRequired => AppleStatus::ReauthRequired,
+            Error::InvalidLoginStep(_) => AppleStatus::InvalidLoginStep,
+            Error::NoTrustedPhoneNumbers => AppleStatus::NoTrustedPhoneNumbers,
+            _ => AppleStatus::Failed,
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn clear_last_error() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+}
+
+/// Records `err` as the last error and returns its status
+fn fail(err: &Error) -> AppleStatus {
+    set_last_error(err.to_string());
+    AppleStatus::from(err)
+}
+
+fn invalid_argument(name: &str) -> AppleStatus {
+    set_last_error(format!("`{name}` is null or not valid"));
+    AppleStatus::InvalidArgument
+}
+
+/// Runs an entry point, turning a panic into the last error and `fallback` instead of unwinding
+/// into the host
+fn catch_panic<T>(fallback: T, run: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(run)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        set_last_error(format!("Panicked: {message}"));
+        fallback
+    })
+}
+
+/// Turns a `Result` into a status, storing the value in `out` on success
+fn finish<T>(result: Result<T, Error>, out: impl FnOnce(T)) -> AppleStatus {
+    match result {
+        Ok(value) => {
+            clear_last_error();
+            out(value);
+            AppleStatus::Ok
+        }
+        Err(err) => fail(&err),
+    }
+}
+
+/// The runtime the async APIs are driven on
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .expect("Failed to start the runtime")
+    })
+}
+
+/// Reads a required string argument
+unsafe fn required_str<'a>(value: *const c_char) -> Option<&'a str> {
+    if value.is_null() {
+        return None;
+    }
+    CStr::from_ptr(value).to_str().ok()
+}
+
+/// Reads an optional string argument, where null means `None`
+unsafe fn optional_str<'a>(value: *const c_char) -> Result<Option<&'a str>, ()> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(value).to_str().map(Some).map_err(|_| ())
+}
+
+unsafe fn required_bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    if data.is_null() {
+        return None;
+    }
+    Some(std::slice::from_raw_parts(data, len))
+}
+
+/// Reads a 32 bytes AES key
+unsafe fn required_key<'a>(key: *const u8, len: usize) -> Option<&'a [u8; 32]> {
+    required_bytes(key, len)?.try_into().ok()
+}
+
+fn into_c_string(value: String) -> *mut c_char {
+    CString::new(value.replace('\0', ""))
+        .unwrap_or_default()
+        .into_raw()
+}
+
+/// Hands a buffer over to the caller, to be released with [`apple_bytes_free`]
+unsafe fn write_bytes(data: Vec<u8>, out_data: *mut *mut u8, out_len: *mut usize) {
+    let data = data.into_boxed_slice();
+    *out_len = data.len();
+    *out_data = Box::into_raw(data) as *mut u8;
+}
+
+fn anisette_configuration(configuration_path: &str) -> AnisetteConfiguration {
+    AnisetteConfiguration::new().set_configuration_path(configuration_path.into())
+}
+
+fn account_configuration(gsa_url: Option<&str>) -> AccountConfiguration {
+    match gsa_url {
+        Some(url) => AccountConfiguration::new().set_base_url(url.to_string()),
+        None => AccountConfiguration::new(),
+    }
+}
+
+/// The message of the last error on the calling thread, or null.
+///
+/// The string is owned by the library and valid until the next call on the same thread.
+#[no_mangle]
+pub extern "C" fn apple_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Releases a string returned by the library
+///
+/// # Safety
+///
+/// `value` must be null or a string returned by the library that wasn't freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn apple_string_free(value: *mut c_char) {
+    catch_panic((), || {
+        if !value.is_null() {
+            drop(CString::from_raw(value));
+        }
+    })
+}
+
+/// Releases a buffer returned by the library
+///
+/// # Safety
+///
+/// `data` must be null or a buffer returned by the library with its length `len`, that wasn't freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn apple_bytes_free(data: *mut u8, len: usize) {
+    catch_panic((), || {
+        if !data.is_null() {
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(data, len)));
+        }
+    })
+}
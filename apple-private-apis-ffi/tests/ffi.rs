@@ -0,0 +1,83 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::{CStr, CString},
+        ptr,
+    };
+
+    use apple_private_apis::*;
+
+    fn last_error() -> String {
+        let message = apple_last_error();
+        assert!(!message.is_null());
+        unsafe { CStr::from_ptr(message) }.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn null_arguments() {
+        let mut flow = ptr::null_mut();
+        let status = unsafe { apple_login_flow_new(ptr::null(), ptr::null(), &mut flow) };
+        assert_eq!(status, AppleStatus::InvalidArgument);
+        assert!(flow.is_null());
+        assert!(last_error().contains("configuration_path"));
+
+        let username = CString::new("test@example.com").unwrap();
+        let password = CString::new("password").unwrap();
+        let mut step = AppleLoginStep::NeedsLogin;
+        let status = unsafe {
+            apple_login_flow_begin(ptr::null_mut(), username.as_ptr(), password.as_ptr(), &mut step)
+        };
+        assert_eq!(status, AppleStatus::InvalidArgument);
+        assert!(last_error().contains("flow"));
+
+        let data = b"snapshot";
+        let key = [0u8; 16];
+        let path = CString::new("anisette").unwrap();
+        let mut flow = ptr::null_mut();
+        let status = unsafe {
+            apple_login_flow_resume(
+                data.as_ptr(),
+                data.len(),
+                key.as_ptr(),
+                key.len(),
+                path.as_ptr(),
+                ptr::null(),
+                &mut flow,
+            )
+        };
+        assert_eq!(status, AppleStatus::InvalidArgument);
+        assert!(last_error().contains("key"));
+
+        let mut token = ptr::null_mut();
+        let status = unsafe { apple_account_app_token(ptr::null_mut(), username.as_ptr(), &mut token) };
+        assert_eq!(status, AppleStatus::InvalidArgument);
+        assert!(token.is_null());
+
+        let step = unsafe { apple_login_flow_step(ptr::null()) };
+        assert_eq!(step, AppleLoginStep::NeedsLogin);
+        assert!(last_error().contains("flow"));
+    }
+
+    #[test]
+    fn invalid_session() {
+        let data = b"not a session";
+        let path = CString::new("anisette").unwrap();
+        let mut account = ptr::null_mut();
+        let status = unsafe {
+            apple_account_from_session(data.as_ptr(), data.len(), path.as_ptr(), ptr::null(), &mut account)
+        };
+        assert_eq!(status, AppleStatus::Failed);
+        assert!(account.is_null());
+        assert!(!last_error().is_empty());
+    }
+
+    #[test]
+    fn free_null() {
+        unsafe {
+            apple_string_free(ptr::null_mut());
+            apple_bytes_free(ptr::null_mut(), 0);
+            apple_login_flow_free(ptr::null_mut());
+            apple_account_free(ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use aes::cipher::block_padding::Pkcs7;
+use aes_gcm::{
+    aead::{consts::U16, generic_array::GenericArray, Aead, Payload},
+    AesGcm,
+};
+use cbc::cipher::{BlockEncryptMut, KeyIvInit};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use srp::apple::{AppleSrp, PasswordDerivation};
+
+use crate::PasswordProtocol;
+
+/// PBKDF2 over the SHA-256 digest of the password, hex encoded first for `s2k_fo`
+pub fn derive_password(
+    protocol: PasswordProtocol,
+    password: &str,
+    salt: &[u8],
+    iterations: u32,
+) -> [u8; 32] {
+    let derivation = match protocol {
+        PasswordProtocol::S2k => PasswordDerivation::S2k,
+        PasswordProtocol::S2kFo => PasswordDerivation::S2kFo,
+    };
+    derivation.derive(password.as_bytes(), salt, iterations)
+}
+
+pub fn compute_verifier(derived_password: &[u8], salt: &[u8]) -> Vec<u8> {
+    AppleSrp::new().compute_verifier(derived_password, salt)
+}
+
+pub fn compute_b_pub(b: &[u8], verifier: &[u8]) -> Vec<u8> {
+    AppleSrp::new().compute_server_public_ephemeral(b, verifier)
+}
+
+/// Checks the client proof `M1`, returning the server proof `M2` and the session key `K`
+pub fn verify_client(
+    username: &str,
+    salt: &[u8],
+    verifier: &[u8],
+    a_pub: &[u8],
+    b: &[u8],
+    m1: &[u8],
+) -> Option<(Vec<u8>, Vec<u8>)> {
+    let verifier = AppleSrp::new()
+        .process_client_reply(b, verifier, a_pub, username.as_bytes(), salt)
+        .ok()?;
+    verifier.verify_client(m1).ok()?;
+    Some((verifier.proof().to_vec(), verifier.key().to_vec()))
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).unwrap();
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Encrypts the server provided data with the keys derived from the SRP session key
+pub fn encrypt_spd(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let extra_data_key = hmac(key, &[b"extra data key:"]);
+    let extra_data_iv = hmac(key, &[b"extra data iv:"]);
+
+    cbc::Encryptor::<aes::Aes256>::new_from_slices(&extra_data_key, &extra_data_iv[..16])
+        .unwrap()
+        .encrypt_padded_vec_mut::<Pkcs7>(data)
+}
+
+pub fn app_token_checksum(session_key: &[u8], adsid: &str, app: &str) -> Vec<u8> {
+    hmac(session_key, &[b"apptokens", adsid.as_bytes(), app.as_bytes()])
+}
+
+/// The checksum of a `complete` carrying the PET
+pub fn complete_checksum(session_key: &[u8], adsid: &str) -> Vec<u8> {
+    hmac(session_key, &[b"complete", adsid.as_bytes()])
+}
+
+/// Builds an `et` payload: a 3 byte version header (also the AAD), a 16 byte IV, then the ciphertext and tag
+pub fn encrypt_gcm(session_key: &[u8], data: &[u8]) -> Vec<u8> {
+    let version = b"XYZ";
+    let iv = rand::random::<[u8; 16]>();
+
+    let ciphertext = <AesGcm<aes::Aes256, U16> as aes_gcm::KeyInit>::new_from_slice(session_key)
+        .unwrap()
+        .encrypt(
+            GenericArray::from_slice(&iv),
+            Payload {
+                msg: data,
+                aad: version,
+            },
+        )
+        .unwrap();
+
+    [version.as_slice(), &iv, &ciphertext].concat()
+}
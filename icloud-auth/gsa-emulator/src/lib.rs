@@ -0,0 +1,813 @@
+//! A local stand-in for Apple's GrandSlam (GSA) authentication servers.
+//!
+//! It speaks just enough of the SRP login, 2FA and app token protocol for `icloud_auth` to be
+//! tested without a real Apple ID or network access. Start it with [`GsaEmulator::start`] and
+//! point an `icloud_auth::AccountConfiguration` at [`GsaEmulator::base_url`].
+
+mod crypto;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{
+    header::{CONTENT_TYPE, RETRY_AFTER},
+    service::{make_service_fn, service_fn},
+    Body, HeaderMap, Method, Request, Response, Server, StatusCode,
+};
+use serde::Deserialize;
+use tokio::sync::oneshot;
+
+const PET_TOKEN: &str = "com.apple.gs.idms.pet";
+
+/// Which second factor the emulated account asks for after the password is accepted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SecondFactor {
+    None,
+    TrustedDevice,
+    Sms,
+}
+
+/// The password protocol the emulator picks in its `init` response
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PasswordProtocol {
+    S2k,
+    S2kFo,
+}
+
+impl PasswordProtocol {
+    fn name(&self) -> &'static str {
+        match self {
+            PasswordProtocol::S2k => "s2k",
+            PasswordProtocol::S2kFo => "s2k_fo",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EmulatedPhoneNumber {
+    pub id: u32,
+    pub number_with_dial_code: String,
+    pub push_mode: String,
+}
+
+/// The Apple ID the emulator accepts
+#[derive(Clone, Debug)]
+pub struct EmulatedAccount {
+    pub username: String,
+    pub password: String,
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    pub password_protocol: PasswordProtocol,
+    pub second_factor: SecondFactor,
+    /// Code accepted for both trusted device and phone verification
+    pub security_code: String,
+    pub adsid: String,
+    pub dsid: i64,
+    pub first_name: String,
+    pub last_name: String,
+    pub trusted_phone_numbers: Vec<EmulatedPhoneNumber>,
+    /// Lifetime of the PET handed out once 2FA is done, in seconds
+    pub pet_duration: i64,
+}
+
+impl Default for EmulatedAccount {
+    fn default() -> Self {
+        EmulatedAccount {
+            username: "test@example.com".to_string(),
+            password: "password".to_string(),
+            salt: b"emulated salt".to_vec(),
+            iterations: 1000,
+            password_protocol: PasswordProtocol::S2k,
+            second_factor: SecondFactor::None,
+            security_code: "123456".to_string(),
+            adsid: "000000-00-00000000-0000-0000-0000-000000000000".to_string(),
+            dsid: 1234567890,
+            first_name: "Test".to_string(),
+            last_name: "User".to_string(),
+            trusted_phone_numbers: vec![EmulatedPhoneNumber {
+                id: 1,
+                number_with_dial_code: "+1 (•••) •••-••42".to_string(),
+                push_mode: "sms".to_string(),
+            }],
+            pet_duration: 300,
+        }
+    }
+}
+
+/// A request for a verification code the emulator received
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CodeDelivery {
+    TrustedDevice,
+    Phone { id: u32, mode: String },
+}
+
+/// A failure the emulator answers the next request with, see [`GsaEmulator::fail_next`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// An empty response with `status` and, if set, a `Retry-After` in seconds
+    Status { status: u16, retry_after: Option<u64> },
+    /// A GSA status with the rate limiting `ec`
+    RateLimited,
+    /// Waits before handling the request as usual
+    Stall(Duration),
+}
+
+/// Anisette headers the emulator accepts; it only checks that the machine headers are present
+pub fn fake_anisette_headers() -> HashMap<String, String> {
+    HashMap::from([
+        ("X-Apple-I-MD".to_string(), "AAAABQAAABAAAAAA".to_string()),
+        ("X-Apple-I-MD-M".to_string(), "AAAAAAAAAAAAAAAA".to_string()),
+        ("X-Apple-I-MD-RINFO".to_string(), "17106176".to_string()),
+        ("X-Apple-I-MD-LU".to_string(), "0000000000000000".to_string()),
+        ("X-Mme-Device-Id".to_string(), "00000000-0000-0000-0000-000000000000".to_string()),
+        ("X-Apple-I-SRL-NO".to_string(), "0".to_string()),
+        ("X-Apple-Locale".to_string(), "en_US".to_string()),
+        ("X-Apple-I-TimeZone".to_string(), "UTC".to_string()),
+        (
+            "X-Mme-Client-Info".to_string(),
+            "<MacBookPro13,2> <macOS;13.1;22C65> <com.apple.AuthKit/1 (com.apple.dt.Xcode/3594.4.19)>"
+                .to_string(),
+        ),
+    ])
+}
+
+type GsaResult = Result<plist::Dictionary, (i64, &'static str)>;
+
+struct PendingLogin {
+    username: String,
+    a_pub: Vec<u8>,
+    b: Vec<u8>,
+}
+
+struct Session {
+    gs_idms_token: String,
+    session_key: Vec<u8>,
+    c: Vec<u8>,
+}
+
+struct Pet {
+    token: String,
+    expiry: i64,
+}
+
+struct State {
+    account: EmulatedAccount,
+    verifier: Vec<u8>,
+    pending: HashMap<String, PendingLogin>,
+    session: Option<Session>,
+    pet: Option<Pet>,
+    pet_renewals: usize,
+    second_factor_verified: bool,
+    last_phone_id: Option<u32>,
+    code_deliveries: Vec<CodeDelivery>,
+    reject_anisette: bool,
+    failures: VecDeque<Failure>,
+    requests: usize,
+    url_bag_base: String,
+    url_bag_lookups: usize,
+}
+
+/// A running emulator, stopped when dropped
+pub struct GsaEmulator {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl GsaEmulator {
+    /// Starts serving `account` on a random local port; must be called from within a tokio runtime
+    pub async fn start(account: EmulatedAccount) -> Result<GsaEmulator, hyper::Error> {
+        let state = Arc::new(Mutex::new(State::new(account)));
+
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+        });
+
+        let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?
+            .http1_title_case_headers(true)
+            .serve(make_service);
+        let addr = server.local_addr();
+        state.lock().unwrap().url_bag_base = format!("http://{}", addr);
+
+        let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            shutdown_rx.await.ok();
+        }));
+
+        Ok(GsaEmulator {
+            addr,
+            state,
+            shutdown: Some(shutdown),
+        })
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Every verification code request received so far, oldest first
+    pub fn code_deliveries(&self) -> Vec<CodeDelivery> {
+        self.state.lock().unwrap().code_deliveries.clone()
+    }
+
+    /// How many sessions were renewed from the PET instead of the password
+    pub fn pet_renewals(&self) -> usize {
+        self.state.lock().unwrap().pet_renewals
+    }
+
+    /// Stops accepting the PET handed out last, as if the user changed their password
+    pub fn revoke_pet(&self) {
+        self.state.lock().unwrap().pet = None;
+    }
+
+    /// Makes every GSA request fail as if the anisette data had been rejected
+    pub fn set_reject_anisette(&self, reject: bool) {
+        self.state.lock().unwrap().reject_anisette = reject;
+    }
+
+    /// Answers the next request that isn't already answered by a queued failure with `failure`
+    pub fn fail_next(&self, failure: Failure) {
+        self.state.lock().unwrap().failures.push_back(failure);
+    }
+
+    /// How many requests were received so far, failed ones included
+    pub fn requests(&self) -> usize {
+        self.state.lock().unwrap().requests
+    }
+
+    /// Points the URL bag at `base_url` instead of the emulator itself, e.g. at another emulator
+    pub fn set_url_bag_base(&self, base_url: String) {
+        self.state.lock().unwrap().url_bag_base = base_url;
+    }
+
+    /// How many times the URL bag was fetched
+    pub fn url_bag_lookups(&self) -> usize {
+        self.state.lock().unwrap().url_bag_lookups
+    }
+}
+
+impl Drop for GsaEmulator {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+    }
+}
+
+async fn handle(state: Arc<Mutex<State>>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let headers = req.headers().clone();
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .unwrap_or_default();
+
+    let failure = {
+        let mut state = state.lock().unwrap();
+        state.requests += 1;
+        state.failures.pop_front()
+    };
+    match failure {
+        Some(Failure::Status {
+            status,
+            retry_after,
+        }) => {
+            let mut response = Response::builder()
+                .status(StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR));
+            if let Some(retry_after) = retry_after {
+                response = response.header(RETRY_AFTER, retry_after.to_string());
+            }
+            return Ok(response.body(Body::empty()).unwrap());
+        }
+        Some(Failure::RateLimited) => {
+            return Ok(plist_response(plist::Dictionary::from_iter([(
+                "Status".to_string(),
+                plist::Value::Dictionary(status(-36607, "Too many requests.")),
+            )])));
+        }
+        Some(Failure::Stall(delay)) => tokio::time::sleep(delay).await,
+        None => {}
+    }
+
+    let response = {
+        let mut state = state.lock().unwrap();
+        match (method, path.trim_end_matches('/')) {
+            (Method::GET, "/grandslam/GsService2/lookup") => state.url_bag(),
+            (Method::POST, "/grandslam/GsService2") => state.gs_service(&body),
+            (Method::GET, "/grandslam/GsService2/validate") => state.validate(&headers),
+            (Method::GET, "/auth/verify/trusteddevice") => state.trusted_device(&headers),
+            (Method::GET, "/auth") => state.auth_extras(&headers),
+            (Method::PUT, "/auth/verify/phone") => state.request_phone_code(&headers, &body),
+            (Method::POST, "/auth/verify/phone/securitycode") => {
+                state.verify_phone_code(&headers, &body)
+            }
+            _ => empty(StatusCode::NOT_FOUND),
+        }
+    };
+
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct PhoneNumber {
+    id: u32,
+}
+
+#[derive(Deserialize)]
+struct SecurityCode {
+    code: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VerifyBody {
+    phone_number: PhoneNumber,
+    mode: String,
+    security_code: Option<SecurityCode>,
+}
+
+impl State {
+    fn new(account: EmulatedAccount) -> Self {
+        let derived_password = crypto::derive_password(
+            account.password_protocol,
+            &account.password,
+            &account.salt,
+            account.iterations,
+        );
+        let verifier = crypto::compute_verifier(&derived_password, &account.salt);
+
+        State {
+            account,
+            verifier,
+            pending: HashMap::new(),
+            session: None,
+            pet: None,
+            pet_renewals: 0,
+            second_factor_verified: false,
+            last_phone_id: None,
+            code_deliveries: Vec::new(),
+            reject_anisette: false,
+            failures: VecDeque::new(),
+            requests: 0,
+            url_bag_base: String::new(),
+            url_bag_lookups: 0,
+        }
+    }
+
+    fn needs_second_factor(&self) -> bool {
+        self.account.second_factor != SecondFactor::None && !self.second_factor_verified
+    }
+
+    fn url_bag(&mut self) -> Response<Body> {
+        self.url_bag_lookups += 1;
+        let urls = [
+            ("gsService", "/grandslam/GsService2"),
+            ("validateCode", "/grandslam/GsService2/validate"),
+            ("trustedDeviceSecondaryAuth", "/auth/verify/trusteddevice"),
+            ("secondaryAuth", "/auth"),
+        ]
+        .into_iter()
+        .map(|(key, path)| {
+            (
+                key.to_string(),
+                plist::Value::String(format!("{}{}", self.url_bag_base, path)),
+            )
+        });
+        plist_response(plist::Dictionary::from_iter([(
+            "urls".to_string(),
+            plist::Value::Dictionary(plist::Dictionary::from_iter(urls)),
+        )]))
+    }
+
+    fn gs_service(&mut self, body: &[u8]) -> Response<Body> {
+        let packet: plist::Dictionary = match plist::from_bytes(body) {
+            Ok(packet) => packet,
+            Err(_) => return empty(StatusCode::BAD_REQUEST),
+        };
+        let request = match packet.get("Request").and_then(|r| r.as_dictionary()) {
+            Some(request) => request,
+            None => return empty(StatusCode::BAD_REQUEST),
+        };
+
+        let result = if self.reject_anisette || !has_anisette(request) {
+            Err((-45061, "Anisette data was rejected."))
+        } else {
+            match request.get("o").and_then(|o| o.as_string()) {
+                Some("init") => self.init(request),
+                Some("complete") => self.complete(request),
+                Some("apptokens") => self.app_tokens(request),
+                _ => Err((-1, "Unknown operation.")),
+            }
+        };
+
+        let response = match result {
+            Ok(mut response) => {
+                if !response.contains_key("Status") {
+                    response.insert("Status".to_string(), plist::Value::Dictionary(status(0, "")));
+                }
+                response
+            }
+            Err((code, message)) => {
+                plist::Dictionary::from_iter([("Status".to_string(), plist::Value::Dictionary(status(code, message)))])
+            }
+        };
+
+        plist_response(plist::Dictionary::from_iter([(
+            "Response".to_string(),
+            plist::Value::Dictionary(response),
+        )]))
+    }
+
+    fn init(&mut self, request: &plist::Dictionary) -> GsaResult {
+        let username = string_field(request, "u")?;
+        let a_pub = data_field(request, "A2k")?;
+        let protocols = request
+            .get("ps")
+            .and_then(|ps| ps.as_array())
+            .ok_or((-1, "Missing protocols."))?;
+        let protocol = self.account.password_protocol.name();
+        if !protocols.iter().any(|p| p.as_string() == Some(protocol)) {
+            return Err((-1, "Unsupported protocol."));
+        }
+        if username != self.account.username {
+            return Err((-20101, "Your Apple ID or password was incorrect."));
+        }
+
+        let b = rand::random::<[u8; 32]>().to_vec();
+        let b_pub = crypto::compute_b_pub(&b, &self.verifier);
+        let c = random_hex(16);
+
+        self.pending.insert(
+            c.clone(),
+            PendingLogin {
+                username: username.to_string(),
+                a_pub: a_pub.to_vec(),
+                b,
+            },
+        );
+
+        Ok(plist::Dictionary::from_iter([
+            ("s".to_string(), plist::Value::Data(self.account.salt.clone())),
+            ("i".to_string(), plist::Value::from(self.account.iterations as i64)),
+            ("B".to_string(), plist::Value::Data(b_pub)),
+            ("c".to_string(), plist::Value::from(c)),
+            ("sp".to_string(), plist::Value::from(protocol)),
+        ]))
+    }
+
+    fn complete(&mut self, request: &plist::Dictionary) -> GsaResult {
+        // a session renewal carries the PET instead of an SRP proof
+        if request.contains_key("t") {
+            return self.complete_with_pet(request);
+        }
+
+        let c = string_field(request, "c")?;
+        let m1 = data_field(request, "M1")?;
+        let pending = self
+            .pending
+            .remove(c)
+            .ok_or((-1, "Unknown authentication session."))?;
+
+        let (m2, key) = crypto::verify_client(
+            &pending.username,
+            &self.account.salt,
+            &self.verifier,
+            &pending.a_pub,
+            &pending.b,
+            m1,
+        )
+        .ok_or((-22406, "Your Apple ID or password was incorrect."))?;
+
+        let spd = self.new_session();
+
+        let mut status = status(0, "");
+        if self.needs_second_factor() {
+            let au = match self.account.second_factor {
+                SecondFactor::TrustedDevice => "trustedDeviceSecondaryAuth",
+                _ => "secondaryAuth",
+            };
+            status.insert("au".to_string(), plist::Value::from(au));
+        }
+
+        Ok(plist::Dictionary::from_iter([
+            ("M2".to_string(), plist::Value::Data(m2)),
+            ("spd".to_string(), plist::Value::Data(crypto::encrypt_spd(&key, &spd))),
+            ("Status".to_string(), plist::Value::Dictionary(status)),
+        ]))
+    }
+
+    fn complete_with_pet(&mut self, request: &plist::Dictionary) -> GsaResult {
+        let session = self.session.as_ref().ok_or((-1, "Not authenticated."))?;
+        if string_field(request, "u")? != self.account.adsid
+            || data_field(request, "c")? != session.c.as_slice()
+            || data_field(request, "checksum")?
+                != crypto::complete_checksum(&session.session_key, &self.account.adsid)
+        {
+            return Err((-1, "Not authenticated."));
+        }
+        match &self.pet {
+            Some(pet) if pet.expiry > now_millis() && string_field(request, "t")? == pet.token => {}
+            _ => return Err((-22406, "Your Apple ID or password was incorrect.")),
+        }
+
+        let session_key = session.session_key.clone();
+        self.pet_renewals += 1;
+        let spd = self.new_session();
+        Ok(plist::Dictionary::from_iter([(
+            "spd".to_string(),
+            plist::Value::Data(crypto::encrypt_gcm(&session_key, &spd)),
+        )]))
+    }
+
+    /// Starts a new session, returning its server provided data
+    fn new_session(&mut self) -> Vec<u8> {
+        let session = Session {
+            gs_idms_token: random_hex(32),
+            session_key: rand::random::<[u8; 32]>().to_vec(),
+            c: rand::random::<[u8; 32]>().to_vec(),
+        };
+
+        let pet = token(random_hex(32), self.account.pet_duration);
+        // the PET only skips 2FA once it was issued to a verified session
+        if !self.needs_second_factor() {
+            self.pet = Some(Pet {
+                token: pet["token"].as_string().unwrap().to_string(),
+                expiry: pet["expiry"].as_signed_integer().unwrap(),
+            });
+        }
+        let spd = plist::Dictionary::from_iter([
+            ("adsid".to_string(), plist::Value::from(self.account.adsid.clone())),
+            ("DsPrsId".to_string(), plist::Value::from(self.account.dsid)),
+            ("GsIdmsToken".to_string(), plist::Value::from(session.gs_idms_token.clone())),
+            ("fn".to_string(), plist::Value::from(self.account.first_name.clone())),
+            ("ln".to_string(), plist::Value::from(self.account.last_name.clone())),
+            ("acname".to_string(), plist::Value::from(self.account.username.clone())),
+            ("sk".to_string(), plist::Value::Data(session.session_key.clone())),
+            ("c".to_string(), plist::Value::Data(session.c.clone())),
+            (
+                "t".to_string(),
+                plist::Value::Dictionary(plist::Dictionary::from_iter([(
+                    PET_TOKEN.to_string(),
+                    plist::Value::Dictionary(pet),
+                )])),
+            ),
+            ("StatusCode".to_string(), plist::Value::from(200)),
+        ]);
+        self.session = Some(session);
+
+        let mut spd_bytes = Vec::new();
+        plist::to_writer_xml(&mut spd_bytes, &spd).unwrap();
+        spd_bytes
+    }
+
+    fn app_tokens(&mut self, request: &plist::Dictionary) -> GsaResult {
+        let session = self.session.as_ref().ok_or((-1, "Not authenticated."))?;
+        if string_field(request, "u")? != self.account.adsid
+            || string_field(request, "t")? != session.gs_idms_token
+            || data_field(request, "c")? != session.c.as_slice()
+        {
+            return Err((-1, "Not authenticated."));
+        }
+        if self.needs_second_factor() {
+            return Err((-22938, "Two-factor authentication is required."));
+        }
+
+        let apps = request
+            .get("app")
+            .and_then(|app| app.as_array())
+            .ok_or((-1, "Missing apps."))?;
+        let checksum = data_field(request, "checksum")?;
+
+        let mut tokens = plist::Dictionary::new();
+        for app in apps {
+            let app = app.as_string().ok_or((-1, "Invalid app."))?;
+            if checksum
+                != crypto::app_token_checksum(&session.session_key, &self.account.adsid, app)
+            {
+                return Err((-1, "Invalid checksum."));
+            }
+            tokens.insert(app.to_string(), plist::Value::Dictionary(token(random_hex(32), 31536000)));
+        }
+
+        let mut et = Vec::new();
+        plist::to_writer_xml(
+            &mut et,
+            &plist::Dictionary::from_iter([("t".to_string(), plist::Value::from(tokens))]),
+        )
+        .unwrap();
+
+        Ok(plist::Dictionary::from_iter([(
+            "et".to_string(),
+            plist::Value::Data(crypto::encrypt_gcm(&session.session_key, &et)),
+        )]))
+    }
+
+    fn identity_matches(&self, headers: &HeaderMap) -> bool {
+        let session = match &self.session {
+            Some(session) => session,
+            None => return false,
+        };
+        let expected = base64::encode(format!("{}:{}", self.account.adsid, session.gs_idms_token));
+
+        headers
+            .get("X-Apple-Identity-Token")
+            .and_then(|token| token.to_str().ok())
+            == Some(expected.as_str())
+    }
+
+    fn validate(&mut self, headers: &HeaderMap) -> Response<Body> {
+        if !self.identity_matches(headers) {
+            return empty(StatusCode::UNAUTHORIZED);
+        }
+
+        let code = headers.get("security-code").and_then(|c| c.to_str().ok());
+        let status = if code == Some(self.account.security_code.as_str()) {
+            self.second_factor_verified = true;
+            status(0, "")
+        } else {
+            status(-21669, "Incorrect verification code.")
+        };
+
+        plist_response(plist::Dictionary::from_iter([(
+            "Status".to_string(),
+            plist::Value::Dictionary(status),
+        )]))
+    }
+
+    fn trusted_device(&mut self, headers: &HeaderMap) -> Response<Body> {
+        if !self.identity_matches(headers) {
+            return empty(StatusCode::UNAUTHORIZED);
+        }
+
+        self.code_deliveries.push(CodeDelivery::TrustedDevice);
+        empty(StatusCode::OK)
+    }
+
+    fn auth_extras(&self, headers: &HeaderMap) -> Response<Body> {
+        if !self.identity_matches(headers) {
+            return empty(StatusCode::UNAUTHORIZED);
+        }
+
+        let numbers = self
+            .account
+            .trusted_phone_numbers
+            .iter()
+            .map(|number| {
+                let digits = number
+                    .number_with_dial_code
+                    .chars()
+                    .filter(char::is_ascii_digit)
+                    .collect::<String>();
+                serde_json::json!({
+                    "id": number.id,
+                    "numberWithDialCode": number.number_with_dial_code,
+                    "lastTwoDigits": &digits[digits.len().saturating_sub(2)..],
+                    "pushMode": number.push_mode,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        json_response(
+            StatusCode::OK,
+            serde_json::json!({ "trustedPhoneNumbers": numbers }),
+        )
+    }
+
+    fn request_phone_code(&mut self, headers: &HeaderMap, body: &[u8]) -> Response<Body> {
+        if !self.identity_matches(headers) {
+            return empty(StatusCode::UNAUTHORIZED);
+        }
+        let body: VerifyBody = match serde_json::from_slice(body) {
+            Ok(body) => body,
+            Err(_) => return empty(StatusCode::BAD_REQUEST),
+        };
+        let id = body.phone_number.id;
+        if !self.account.trusted_phone_numbers.iter().any(|n| n.id == id)
+            || !matches!(body.mode.as_str(), "sms" | "voice")
+        {
+            return empty(StatusCode::BAD_REQUEST);
+        }
+
+        self.last_phone_id = Some(id);
+        self.code_deliveries.push(CodeDelivery::Phone {
+            id,
+            mode: body.mode,
+        });
+        json_response(StatusCode::OK, serde_json::json!({}))
+    }
+
+    fn verify_phone_code(&mut self, headers: &HeaderMap, body: &[u8]) -> Response<Body> {
+        if !self.identity_matches(headers) {
+            return empty(StatusCode::UNAUTHORIZED);
+        }
+        let body: VerifyBody = match serde_json::from_slice(body) {
+            Ok(body) => body,
+            Err(_) => return empty(StatusCode::BAD_REQUEST),
+        };
+
+        let code = body.security_code.map(|c| c.code);
+        if self.last_phone_id != Some(body.phone_number.id)
+            || code.as_deref() != Some(self.account.security_code.as_str())
+        {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                serde_json::json!({
+                    "service_errors": [{
+                        "code": "-21669",
+                        "title": "Incorrect Verification Code",
+                        "message": "Incorrect verification code.",
+                    }]
+                }),
+            );
+        }
+
+        self.second_factor_verified = true;
+        json_response(StatusCode::OK, serde_json::json!({}))
+    }
+}
+
+fn has_anisette(request: &plist::Dictionary) -> bool {
+    request
+        .get("cpd")
+        .and_then(|cpd| cpd.as_dictionary())
+        .map(|cpd| cpd.contains_key("X-Apple-I-MD") && cpd.contains_key("X-Apple-I-MD-M"))
+        .unwrap_or(false)
+}
+
+fn string_field<'a>(
+    request: &'a plist::Dictionary,
+    key: &str,
+) -> Result<&'a str, (i64, &'static str)> {
+    request
+        .get(key)
+        .and_then(|v| v.as_string())
+        .ok_or((-1, "Malformed request."))
+}
+
+fn data_field<'a>(
+    request: &'a plist::Dictionary,
+    key: &str,
+) -> Result<&'a [u8], (i64, &'static str)> {
+    request
+        .get(key)
+        .and_then(|v| v.as_data())
+        .ok_or((-1, "Malformed request."))
+}
+
+fn status(code: i64, message: &str) -> plist::Dictionary {
+    plist::Dictionary::from_iter([
+        ("ec".to_string(), plist::Value::from(code)),
+        ("em".to_string(), plist::Value::from(message)),
+    ])
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+fn token(token: String, duration: i64) -> plist::Dictionary {
+    let now = now_millis();
+
+    plist::Dictionary::from_iter([
+        ("token".to_string(), plist::Value::from(token)),
+        ("duration".to_string(), plist::Value::from(duration)),
+        ("expiry".to_string(), plist::Value::from(now + duration * 1000)),
+        ("cts".to_string(), plist::Value::from(now)),
+    ])
+}
+
+fn random_hex(len: usize) -> String {
+    (0..len).map(|_| format!("{:02x}", rand::random::<u8>())).collect()
+}
+
+fn empty(status: StatusCode) -> Response<Body> {
+    Response::builder().status(status).body(Body::empty()).unwrap()
+}
+
+fn plist_response(dict: plist::Dictionary) -> Response<Body> {
+    let mut body = Vec::new();
+    plist::to_writer_xml(&mut body, &dict).unwrap();
+
+    Response::builder()
+        .header(CONTENT_TYPE, "text/x-xml-plist")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn json_response(status: StatusCode, value: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(value.to_string()))
+        .unwrap()
+}
@@ -0,0 +1,222 @@
+//! The SRP variant spoken by Apple's GSA servers.
+//!
+//! It uses [`G_2048`] and SHA-256, and differs from the RFC 5054 mode of
+//! [`client`](crate::client) and [`server`](crate::server) in that:
+//!
+//! - the password is first derived with PBKDF2, see [`PasswordDerivation`]
+//! - the username is left out of the identity hash, `x = H(s | H(":" | P))`
+//! - the session key is hashed, `K = H(S)`
+//! - `M1 = H(H(N) XOR H(g) | H(I) | s | A | B | K)`, as in RFC 2945
+//!
+//! ```rust
+//! use srp::apple::{AppleSrp, PasswordDerivation};
+//! # fn server_response() -> (Vec<u8>, Vec<u8>, u32) { (vec![0; 16], vec![1], 1000) }
+//!
+//! let srp = AppleSrp::new();
+//! let a = [0u8; 32];
+//! // rng.fill_bytes(&mut a);
+//! let a_pub = srp.compute_public_ephemeral(&a);
+//!
+//! let (salt, b_pub, iterations) = server_response();
+//! let password = PasswordDerivation::S2k.derive(b"password", &salt, iterations);
+//! let verifier = srp
+//!     .process_reply(&a, b"user@example.com", &password, &salt, &b_pub)
+//!     .unwrap();
+//! let m1 = verifier.proof();
+//! ```
+
+use digest::{Digest, Output};
+use hmac::Hmac;
+use num_bigint::BigUint;
+use sha2::Sha256;
+
+use crate::client::{SrpClient, SrpClientVerifier};
+use crate::groups::G_2048;
+use crate::server::{SrpServer, SrpServerVerifier};
+use crate::types::{SrpAuthError, SrpGroup};
+use crate::utils::{compute_k, compute_m2, compute_u};
+
+/// How the password is turned into the SRP password, `s2k` or `s2k_fo` in GSA's `sp` field
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PasswordDerivation {
+    /// `pbkdf2(sha256(password))`
+    S2k,
+    /// `pbkdf2(hex(sha256(password)))`
+    S2kFo,
+}
+
+impl PasswordDerivation {
+    pub fn derive(&self, password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+        let hashed_password = Sha256::digest(password);
+        let input = match self {
+            PasswordDerivation::S2k => hashed_password.to_vec(),
+            PasswordDerivation::S2kFo => hashed_password
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+                .into_bytes(),
+        };
+
+        let mut derived = [0u8; 32];
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(&input, salt, iterations, &mut derived);
+        derived
+    }
+}
+
+// M1 = H(H(N) XOR H(g) | H(I) | s | A | B | K)
+pub fn compute_m1(
+    a_pub: &[u8],
+    b_pub: &[u8],
+    key: &[u8],
+    username: &[u8],
+    salt: &[u8],
+    params: &SrpGroup,
+) -> Output<Sha256> {
+    let n = params.n.to_bytes_be();
+    let g_bytes = params.g.to_bytes_be();
+    //pad g to the length of n
+    let mut g = vec![0; n.len() - g_bytes.len()];
+    g.extend_from_slice(&g_bytes);
+
+    let mut g_hash = Sha256::digest(&g);
+    let n_hash = Sha256::digest(&n);
+    for (g, n) in g_hash.iter_mut().zip(n_hash) {
+        *g ^= n;
+    }
+
+    let mut d = Sha256::new();
+    d.update(g_hash);
+    d.update(Sha256::digest(username));
+    d.update(salt);
+    d.update(a_pub);
+    d.update(b_pub);
+    d.update(key);
+    d.finalize()
+}
+
+/// SRP as GSA does it, for both the client and the server side.
+///
+/// `password` is always the output of [`PasswordDerivation::derive`].
+pub struct AppleSrp {
+    client: SrpClient<'static, Sha256>,
+    server: SrpServer<'static, Sha256>,
+}
+
+impl Default for AppleSrp {
+    fn default() -> Self {
+        AppleSrp::new()
+    }
+}
+
+impl AppleSrp {
+    pub fn new() -> Self {
+        AppleSrp {
+            client: SrpClient::new(&G_2048),
+            server: SrpServer::new(&G_2048),
+        }
+    }
+
+    pub fn params(&self) -> &'static SrpGroup {
+        &G_2048
+    }
+
+    // x = H(<salt> | H(":" | <password>))
+    pub fn compute_x(password: &[u8], salt: &[u8]) -> BigUint {
+        let identity_hash = SrpClient::<Sha256>::compute_identity_hash(&[], password);
+        SrpClient::<Sha256>::compute_x(identity_hash.as_slice(), salt)
+    }
+
+    /// Get the password verifier `v` the server stores.
+    pub fn compute_verifier(&self, password: &[u8], salt: &[u8]) -> Vec<u8> {
+        self.client
+            .compute_v(&Self::compute_x(password, salt))
+            .to_bytes_be()
+    }
+
+    /// Get the client public ephemeral value `A`.
+    pub fn compute_public_ephemeral(&self, a: &[u8]) -> Vec<u8> {
+        self.client.compute_public_ephemeral(a)
+    }
+
+    /// Get the server public ephemeral value `B`.
+    pub fn compute_server_public_ephemeral(&self, b: &[u8], v: &[u8]) -> Vec<u8> {
+        self.server.compute_public_ephemeral(b, v)
+    }
+
+    /// Process the server reply to the `init` request, on the client side.
+    pub fn process_reply(
+        &self,
+        a: &[u8],
+        username: &[u8],
+        password: &[u8],
+        salt: &[u8],
+        b_pub: &[u8],
+    ) -> Result<SrpClientVerifier<Sha256>, SrpAuthError> {
+        let a = BigUint::from_bytes_be(a);
+        let a_pub = self.client.compute_a_pub(&a).to_bytes_be();
+
+        let b_pub = BigUint::from_bytes_be(b_pub);
+        // Safeguard against malicious B
+        if &b_pub % &G_2048.n == BigUint::default() {
+            return Err(SrpAuthError::IllegalParameter("b_pub".to_owned()));
+        }
+        let b_pub = b_pub.to_bytes_be();
+
+        let u = compute_u::<Sha256>(&a_pub, &b_pub);
+        let k = compute_k::<Sha256>(&G_2048);
+        let x = Self::compute_x(password, salt);
+        let premaster_secret = self.client.compute_premaster_secret(
+            &BigUint::from_bytes_be(&b_pub),
+            &k,
+            &x,
+            &a,
+            &u,
+        );
+        let key = Sha256::digest(premaster_secret.to_bytes_be());
+
+        let m1 = compute_m1(&a_pub, &b_pub, &key, username, salt, &G_2048);
+        let m2 = compute_m2::<Sha256>(&a_pub, &m1, &key);
+
+        Ok(SrpClientVerifier {
+            m1,
+            m2,
+            key: key.to_vec(),
+        })
+    }
+
+    /// Process the client `A` on the server side, `b` being the secret behind the `B` sent back.
+    pub fn process_client_reply(
+        &self,
+        b: &[u8],
+        v: &[u8],
+        a_pub: &[u8],
+        username: &[u8],
+        salt: &[u8],
+    ) -> Result<SrpServerVerifier<Sha256>, SrpAuthError> {
+        let a_pub = BigUint::from_bytes_be(a_pub);
+        // Safeguard against malicious A
+        if &a_pub % &G_2048.n == BigUint::default() {
+            return Err(SrpAuthError::IllegalParameter("a_pub".to_owned()));
+        }
+        let a_pub = a_pub.to_bytes_be();
+        let b_pub = self.compute_server_public_ephemeral(b, v);
+
+        let u = compute_u::<Sha256>(&a_pub, &b_pub);
+        let premaster_secret = self.server.compute_premaster_secret(
+            &BigUint::from_bytes_be(&a_pub),
+            &BigUint::from_bytes_be(v),
+            &u,
+            &BigUint::from_bytes_be(b),
+        );
+        let key = Sha256::digest(premaster_secret.to_bytes_be());
+
+        let m1 = compute_m1(&a_pub, &b_pub, &key, username, salt, &G_2048);
+        let m2 = compute_m2::<Sha256>(&a_pub, &m1, &key);
+
+        Ok(SrpServerVerifier {
+            m1,
+            m2,
+            key: key.to_vec(),
+        })
+    }
+}
@@ -99,9 +99,9 @@ pub struct SrpClient<'a, D: Digest> {
 
 /// SRP client state after handshake with the server.
 pub struct SrpClientVerifier<D: Digest> {
-    m1: Output<D>,
-    m2: Output<D>,
-    key: Vec<u8>,
+    pub(crate) m1: Output<D>,
+    pub(crate) m2: Output<D>,
+    pub(crate) key: Vec<u8>,
 }
 
 impl<'a, D: Digest> SrpClient<'a, D> {
@@ -198,27 +198,23 @@ impl<'a, D: Digest> SrpClient<'a, D> {
 
         let u = compute_u::<D>(&a_pub.to_bytes_be(), &b_pub.to_bytes_be());
         let k = compute_k::<D>(self.params);
-        let identity_hash = Self::compute_identity_hash(&[], password);
+        let identity_hash = Self::compute_identity_hash(username, password);
         let x = Self::compute_x(identity_hash.as_slice(), salt);
 
         let key = self.compute_premaster_secret(&b_pub, &k, &x, &a, &u);
-        let key = D::digest(key.to_bytes_be());
 
         let m1 = compute_m1::<D>(
             &a_pub.to_bytes_be(),
             &b_pub.to_bytes_be(),
-            &key,
-            username,
-            salt,
-            self.params,
+            &key.to_bytes_be(),
         );
 
-        let m2 = compute_m2::<D>(&a_pub.to_bytes_be(), &m1, &key);
+        let m2 = compute_m2::<D>(&a_pub.to_bytes_be(), &m1, &key.to_bytes_be());
 
         Ok(SrpClientVerifier {
             m1,
             m2,
-            key: key.to_vec(),
+            key: key.to_bytes_be(),
         })
     }
 }
@@ -13,6 +13,9 @@
 //! Next read documentation for [`client`](client/index.html) and
 //! [`server`](server/index.html) modules.
 //!
+//! The variant used by Apple's GSA servers lives in the [`apple`](apple/index.html)
+//! module, behind the `apple` feature.
+//!
 //! # Algorithm description
 //! Here we briefly describe implemented algorithm. For additional information
 //! refer to SRP literature. All arithmetic is done modulo `N`, where `N` is a
@@ -50,6 +53,8 @@
 //! [1]: https://en.wikipedia.org/wiki/Secure_Remote_Password_protocol
 //! [2]: https://tools.ietf.org/html/rfc5054
 
+#[cfg(feature = "apple")]
+pub mod apple;
 pub mod client;
 pub mod groups;
 pub mod server;
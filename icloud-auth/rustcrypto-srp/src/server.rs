@@ -76,9 +76,9 @@ pub struct SrpServer<'a, D: Digest> {
 
 /// SRP server state after handshake with the client.
 pub struct SrpServerVerifier<D: Digest> {
-    m1: Output<D>,
-    m2: Output<D>,
-    key: Vec<u8>,
+    pub(crate) m1: Output<D>,
+    pub(crate) m2: Output<D>,
+    pub(crate) key: Vec<u8>,
 }
 
 impl<'a, D: Digest> SrpServer<'a, D> {
@@ -127,8 +127,6 @@ impl<'a, D: Digest> SrpServer<'a, D> {
         b: &[u8],
         v: &[u8],
         a_pub: &[u8],
-        username: &[u8],
-        salt: &[u8],
     ) -> Result<SrpServerVerifier<D>, SrpAuthError> {
         let b = BigUint::from_bytes_be(b);
         let v = BigUint::from_bytes_be(v);
@@ -150,9 +148,6 @@ impl<'a, D: Digest> SrpServer<'a, D> {
             &a_pub.to_bytes_be(),
             &b_pub.to_bytes_be(),
             &key.to_bytes_be(),
-            username,
-            salt,
-            self.params,
         );
 
         let m2 = compute_m2::<D>(&a_pub.to_bytes_be(), &m1, &key.to_bytes_be());
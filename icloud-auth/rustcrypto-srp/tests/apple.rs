@@ -0,0 +1,76 @@
+#![cfg(feature = "apple")]
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use srp::apple::{AppleSrp, PasswordDerivation};
+
+const USERNAME: &[u8] = b"apple3@f1sh.me";
+
+/// The session captured in icloud-auth's `auth_debug` test, from a throwaway account
+fn captured_session() -> (Vec<u8>, [u8; 32], Vec<u8>) {
+    let salt = STANDARD.decode("6fK6ailLUcp2kJswJVrKjQ==").unwrap();
+    let password = PasswordDerivation::S2k.derive(b"WaffleTest123", &salt, 20832);
+    let a = STANDARD
+        .decode("ywN1O32vmBogb5Fyt9M7Tn8bbzLtDDbcYgPFpSy8n9E=")
+        .unwrap();
+    (salt, password, a)
+}
+
+#[test]
+fn captured_public_ephemeral() {
+    let (_, _, a) = captured_session();
+    let a_pub = AppleSrp::new().compute_public_ephemeral(&a);
+    assert_eq!(
+        STANDARD.encode(a_pub),
+        "N2XHuh/4P1urPoBvDocF0RCRIl2pliZYqg9p6wGH0nnJdckJPn3M00jEqoM4teqH03HjG1murdcZiNHb5YayufW//+asW01XB7nYIIVvGiUFLRypYITEKYWBQ6h2q02GaZspYJKy98V8Fwcvr0ri+al7zJo1X1aoRKINyjV5TywhhwmTleI1qJkf+JBRYKKqO1XFtOTpQsysWD3ZJdK3K78kSgT3q0kXE3oDRMiHPAO77GFJZErYTuvI6QPRbOgcrn+RKV6AsjR5tUQAoSGRdtibdZTAQijJg788qVg+OFVCNZoY9GYVxa+Ze1bPGdkkgCYicTE8iNFG9KlJ+QpKgQ=="
+    );
+}
+
+#[test]
+fn client_server_round_trip() {
+    let (salt, password, a) = captured_session();
+    let b = [0x42u8; 32];
+    let srp = AppleSrp::new();
+
+    let v = srp.compute_verifier(&password, &salt);
+    let a_pub = srp.compute_public_ephemeral(&a);
+    let b_pub = srp.compute_server_public_ephemeral(&b, &v);
+
+    let client = srp
+        .process_reply(&a, USERNAME, &password, &salt, &b_pub)
+        .unwrap();
+    let server = srp
+        .process_client_reply(&b, &v, &a_pub, USERNAME, &salt)
+        .unwrap();
+
+    assert_eq!(client.key(), server.key());
+    assert_eq!(client.key().len(), 32);
+    server.verify_client(client.proof()).unwrap();
+    client.verify_server(server.proof()).unwrap();
+
+    // a wrong password gets neither side to agree
+    let wrong = PasswordDerivation::S2k.derive(b"WaffleTest124", &salt, 20832);
+    let client = srp
+        .process_reply(&a, USERNAME, &wrong, &salt, &b_pub)
+        .unwrap();
+    assert_ne!(client.key(), server.key());
+    assert!(server.verify_client(client.proof()).is_err());
+
+    // s2k_fo derives another password from the same input
+    let fo = PasswordDerivation::S2kFo.derive(b"WaffleTest123", &salt, 20832);
+    assert_ne!(fo, password);
+}
+
+#[test]
+fn rejects_zero_ephemerals() {
+    let (salt, password, a) = captured_session();
+    let srp = AppleSrp::new();
+    let v = srp.compute_verifier(&password, &salt);
+    let n = srp.params().n.to_bytes_be();
+
+    assert!(srp
+        .process_reply(&a, USERNAME, &password, &salt, &n)
+        .is_err());
+    assert!(srp
+        .process_client_reply(&[1], &v, &n, USERNAME, &salt)
+        .is_err());
+}
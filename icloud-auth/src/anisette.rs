@@ -1,35 +1,118 @@
-use crate::Error;
-use omnisette::{AnisetteConfiguration, AnisetteHeaders};
-use std::{collections::HashMap, time::SystemTime};
+use crate::{retry::classify_anisette, Error};
+use omnisette::{
+    anisette_headers_provider::AnisetteHeadersProvider, http::with_retry,
+    provider_chain::ProviderChainStatus, AnisetteConfiguration, AnisetteHeaders,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, OnceLock},
+    time::SystemTime,
+};
+use tokio::sync::Mutex;
 
-#[derive(Debug, Clone)]
+/// The provider chain behind an [`AnisetteData`] and its refreshes, created on the first fetch
+#[derive(Default)]
+struct SharedProvider {
+    provider: Mutex<Option<Box<dyn AnisetteHeadersProvider>>>,
+    status: OnceLock<ProviderChainStatus>,
+}
+
+/// Anisette headers, refreshed through the same provider chain for as long as the configuration
+/// stays the same, so the chain remembers which providers failed
+#[derive(Clone)]
 pub struct AnisetteData {
-    pub base_headers: HashMap<String, String>,
-    pub generated_at: SystemTime,
-    pub config: AnisetteConfiguration,
+    base_headers: HashMap<String, String>,
+    generated_at: Option<SystemTime>,
+    config: AnisetteConfiguration,
+    shared: Arc<SharedProvider>,
 }
 
 impl AnisetteData {
-    /// Fetches the data at an anisette server
+    /// Fetches the data at an anisette server, retrying network failures with the configured policy
     pub async fn new(config: AnisetteConfiguration) -> Result<Self, crate::Error> {
-        let mut b = AnisetteHeaders::get_anisette_headers_provider(config.clone())?;
-        let base_headers = b.provider.get_authentication_headers().await?;
+        Self::expired(config).refresh().await
+    }
+
+    /// Data that has to be fetched before use, without fetching it yet
+    pub fn expired(config: AnisetteConfiguration) -> Self {
+        AnisetteData {
+            base_headers: HashMap::new(),
+            generated_at: None,
+            config,
+            shared: Arc::default(),
+        }
+    }
 
-        Ok(AnisetteData { base_headers, generated_at: SystemTime::now(), config })
+    /// Data fetched elsewhere; its refreshes go through a provider chain created from `config`
+    pub fn from_headers(
+        base_headers: HashMap<String, String>,
+        config: AnisetteConfiguration,
+    ) -> Self {
+        AnisetteData {
+            base_headers,
+            generated_at: Some(SystemTime::now()),
+            ..Self::expired(config)
+        }
+    }
+
+    pub fn config(&self) -> &AnisetteConfiguration {
+        &self.config
+    }
+
+    /// Replaces the configuration, and with it the provider chain used by the next refreshes
+    pub fn set_configuration(&mut self, config: AnisetteConfiguration) {
+        self.config = config;
+        self.shared = Arc::default();
+    }
+
+    /// The provider of the chain answering the refreshes and the ones it skipped, once the chain
+    /// was created. Resetting it makes the next refresh try every provider again.
+    pub fn provider_status(&self) -> Option<ProviderChainStatus> {
+        self.shared.status.get().cloned()
     }
 
     pub fn needs_refresh(&self) -> bool {
-        let elapsed = self.generated_at.elapsed().unwrap();
-        elapsed.as_secs() > 60
+        match self.generated_at {
+            Some(generated_at) => generated_at.elapsed().unwrap().as_secs() > 60,
+            None => true,
+        }
     }
 
     pub fn is_valid(&self) -> bool {
-        let elapsed = self.generated_at.elapsed().unwrap();
-        elapsed.as_secs() < 90
+        match self.generated_at {
+            Some(generated_at) => generated_at.elapsed().unwrap().as_secs() < 90,
+            None => false,
+        }
     }
 
+    /// Fetches new data through the provider chain, creating it on the first call
     pub async fn refresh(&self) -> Result<Self, crate::Error> {
-        Self::new(self.config.clone()).await
+        let base_headers = with_retry(
+            self.config.http_configuration().retry_policy(),
+            || async {
+                let mut provider = self.shared.provider.lock().await;
+                let provider = match &mut *provider {
+                    Some(provider) => provider,
+                    None => {
+                        let res =
+                            AnisetteHeaders::get_anisette_headers_provider(self.config.clone())?;
+                        let _ = self.shared.status.set(res.status);
+                        provider.insert(res.provider)
+                    }
+                };
+                Ok(provider.get_authentication_headers().await?)
+            },
+            classify_anisette,
+        )
+        .await?;
+
+        Ok(AnisetteData {
+            base_headers,
+            generated_at: Some(SystemTime::now()),
+            config: self.config.clone(),
+            shared: self.shared.clone(),
+        })
     }
 
     pub fn generate_headers(
@@ -41,6 +124,7 @@ impl AnisetteData {
         if !self.is_valid() {
             panic!("Invalid data!")
         }
+        let profile = self.config.client_profile();
         let mut headers = self.base_headers.clone();
         let old_client_info = headers.remove("X-Mme-Client-Info");
         if client_info {
@@ -50,7 +134,7 @@ impl AnisetteData {
 
                     temp.replace(
                         temp.split('<').nth(3).unwrap().split('>').nth(0).unwrap(),
-                        "com.apple.AuthKit/1 (com.apple.dt.Xcode/3594.4.19)",
+                        &profile.auth_kit,
                     )
                 }
                 None => {
@@ -61,11 +145,12 @@ impl AnisetteData {
         }
 
         if app_info {
-            headers.insert(
-                "X-Apple-App-Info".to_owned(),
-                "com.apple.gs.xcode.auth".to_owned(),
-            );
-            headers.insert("X-Xcode-Version".to_owned(), "11.2 (11B41)".to_owned());
+            if let Some(app_info) = &profile.app_info {
+                headers.insert("X-Apple-App-Info".to_owned(), app_info.to_owned());
+            }
+            if let Some(xcode_version) = &profile.xcode_version {
+                headers.insert("X-Xcode-Version".to_owned(), xcode_version.to_owned());
+            }
         }
 
         if cpd {
@@ -102,3 +187,14 @@ impl AnisetteData {
         }
     }
 }
+
+impl fmt::Debug for AnisetteData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AnisetteData")
+            .field("base_headers", &self.base_headers)
+            .field("generated_at", &self.generated_at)
+            .field("config", &self.config)
+            .field("provider_status", &self.provider_status())
+            .finish()
+    }
+}
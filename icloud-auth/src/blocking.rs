@@ -0,0 +1,181 @@
+//! A blocking API over [`crate::AppleAccount`], for callers without an async runtime.
+//!
+//! Every method drives the async implementation to completion on a runtime owned by the
+//! account, so the two APIs behave the same. Like `reqwest::blocking`, it must not be used
+//! from within an async runtime.
+
+use tokio::runtime::{Builder, Runtime};
+
+use crate::{
+    anisette::AnisetteData, AccountConfiguration, AccountSession, AnisetteConfiguration,
+    AppToken, AuthenticationExtras, DeliveryMode, Error, LoginState, ServerProvidedData,
+    TrustedPhoneNumber, UrlBag, VerifyBody,
+};
+
+fn runtime() -> Result<Runtime, Error> {
+    Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(Error::RuntimeError)
+}
+
+/// The blocking counterpart of [`crate::AppleAccount`]
+pub struct AppleAccount {
+    inner: crate::AppleAccount,
+    runtime: Runtime,
+}
+
+impl AppleAccount {
+    pub fn new(config: AnisetteConfiguration) -> Result<Self, Error> {
+        let runtime = runtime()?;
+        let inner = runtime.block_on(crate::AppleAccount::new(config))?;
+        Ok(AppleAccount { inner, runtime })
+    }
+
+    pub fn new_with_anisette(anisette: AnisetteData) -> Result<Self, Error> {
+        Self::new_with_configuration(anisette, AccountConfiguration::new())
+    }
+
+    pub fn new_with_configuration(
+        anisette: AnisetteData,
+        config: AccountConfiguration,
+    ) -> Result<Self, Error> {
+        Ok(AppleAccount {
+            inner: crate::AppleAccount::new_with_configuration(anisette, config)?,
+            runtime: runtime()?,
+        })
+    }
+
+    pub fn from_session(
+        session: AccountSession,
+        config: AnisetteConfiguration,
+    ) -> Result<Self, Error> {
+        let runtime = runtime()?;
+        let inner = runtime.block_on(crate::AppleAccount::from_session(session, config))?;
+        Ok(AppleAccount { inner, runtime })
+    }
+
+    pub fn from_session_with_anisette(
+        session: AccountSession,
+        anisette: AnisetteData,
+    ) -> Result<Self, Error> {
+        Ok(AppleAccount {
+            inner: crate::AppleAccount::from_session_with_anisette(session, anisette)?,
+            runtime: runtime()?,
+        })
+    }
+
+    /// See [`crate::AppleAccount::login`]
+    pub fn login(
+        appleid_closure: impl Fn() -> (String, String),
+        tfa_closure: impl Fn() -> String,
+        config: AnisetteConfiguration,
+    ) -> Result<Self, Error> {
+        let runtime = runtime()?;
+        let inner =
+            runtime.block_on(crate::AppleAccount::login(appleid_closure, tfa_closure, config))?;
+        Ok(AppleAccount { inner, runtime })
+    }
+
+    /// See [`crate::AppleAccount::login_with_anisette`]
+    pub fn login_with_anisette(
+        appleid_closure: impl Fn() -> (String, String),
+        tfa_closure: impl Fn() -> String,
+        anisette: AnisetteData,
+    ) -> Result<Self, Error> {
+        let runtime = runtime()?;
+        let inner = runtime.block_on(crate::AppleAccount::login_with_anisette(
+            appleid_closure,
+            tfa_closure,
+            anisette,
+        ))?;
+        Ok(AppleAccount { inner, runtime })
+    }
+
+    pub fn inner(&self) -> &crate::AppleAccount {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> crate::AppleAccount {
+        self.inner
+    }
+
+    pub fn configuration(&self) -> &AccountConfiguration {
+        self.inner.configuration()
+    }
+
+    pub fn spd(&self) -> Result<&ServerProvidedData, Error> {
+        self.inner.spd()
+    }
+
+    pub fn export_session(&self) -> Result<AccountSession, Error> {
+        self.inner.export_session()
+    }
+
+    pub fn get_pet(&self) -> Result<String, Error> {
+        self.inner.get_pet()
+    }
+
+    pub fn get_name(&self) -> Result<(String, String), Error> {
+        self.inner.get_name()
+    }
+
+    pub fn get_anisette(&self) -> Result<AnisetteData, Error> {
+        self.runtime.block_on(self.inner.get_anisette())
+    }
+
+    pub fn url_bag(&self) -> Result<&UrlBag, Error> {
+        self.runtime.block_on(self.inner.url_bag())
+    }
+
+    pub fn login_email_pass(&mut self, username: &str, password: &str) -> Result<LoginState, Error> {
+        self.runtime
+            .block_on(self.inner.login_email_pass(username, password))
+    }
+
+    pub fn send_2fa_to_devices(&self) -> Result<LoginState, Error> {
+        self.runtime.block_on(self.inner.send_2fa_to_devices())
+    }
+
+    pub fn verify_2fa(&self, code: String) -> Result<LoginState, Error> {
+        self.runtime.block_on(self.inner.verify_2fa(code))
+    }
+
+    pub fn get_auth_extras(&self) -> Result<AuthenticationExtras, Error> {
+        self.runtime.block_on(self.inner.get_auth_extras())
+    }
+
+    pub fn trusted_phone_numbers(&self) -> Result<Vec<TrustedPhoneNumber>, Error> {
+        self.runtime.block_on(self.inner.trusted_phone_numbers())
+    }
+
+    pub fn send_sms_2fa_to_devices(&self, phone_id: u32) -> Result<LoginState, Error> {
+        self.runtime
+            .block_on(self.inner.send_sms_2fa_to_devices(phone_id))
+    }
+
+    pub fn send_phone_code(&self, phone_id: u32, mode: DeliveryMode) -> Result<LoginState, Error> {
+        self.runtime
+            .block_on(self.inner.send_phone_code(phone_id, mode))
+    }
+
+    pub fn verify_sms_2fa(&self, code: String, body: VerifyBody) -> Result<LoginState, Error> {
+        self.runtime.block_on(self.inner.verify_sms_2fa(code, body))
+    }
+
+    pub fn needs_reauth(&self) -> bool {
+        self.inner.needs_reauth()
+    }
+
+    pub fn renew(&mut self) -> Result<(), Error> {
+        self.runtime.block_on(self.inner.renew())
+    }
+
+    pub fn get_app_token(&self, app_name: &str) -> Result<AppToken, Error> {
+        self.runtime.block_on(self.inner.get_app_token(app_name))
+    }
+
+    pub fn app_token(&mut self, app_name: &str) -> Result<AppToken, Error> {
+        self.runtime.block_on(self.inner.app_token(app_name))
+    }
+}
@@ -1,24 +1,33 @@
-use std::str::FromStr;
-
 // use crate::anisette::AnisetteData;
-use crate::{anisette::AnisetteData, Error};
+use crate::{
+    anisette::AnisetteData,
+    redact::{Redacted, RedactedHeaders},
+    retry::classify_response,
+    spd::PET_TOKEN,
+    AccountConfiguration, AccountSession, Endpoint, Error, LoginFlow, PasswordProtocol,
+    ServerProvidedData, UrlBag, URL_BAG_PATH,
+};
 use aes::cipher::block_padding::Pkcs7;
+use aes_gcm::{
+    aead::{consts::U16, generic_array::GenericArray, Aead, Payload},
+    AesGcm,
+};
 use cbc::cipher::{BlockDecryptMut, KeyIvInit};
 use hmac::{Hmac, Mac};
 use omnisette::AnisetteConfiguration;
-use reqwest::{
-    header::{HeaderMap, HeaderName, HeaderValue}, Certificate, Client, ClientBuilder, Proxy, Response
-};
+use omnisette::http::{self, HttpRequest, HttpResponse, HttpTransport, TransportError};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
-use srp::{
-    client::{SrpClient, SrpClientVerifier},
-    groups::G_2048,
+use sha2::Sha256;
+use srp::{apple::AppleSrp, client::SrpClientVerifier};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::Mutex;
-
-const GSA_ENDPOINT: &str = "https://gsa.apple.com/grandslam/GsService2";
-const APPLE_ROOT: &[u8] = include_bytes!("./apple_root.der");
+use tokio::sync::{Mutex, OnceCell};
+use tracing::{debug, debug_span, trace, warn, Instrument};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InitRequestBody {
@@ -85,24 +94,78 @@ pub struct AuthTokenRequest {
     request: AuthTokenRequestBody,
 }
 
+/// A `complete` authenticated by the PET `t` instead of an SRP proof
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PetCompleteRequestBody {
+    c: plist::Value,
+    cpd: plist::Dictionary,
+    #[serde(rename = "o")]
+    operation: String,
+    t: String,
+    u: String,
+    checksum: plist::Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PetCompleteRequest {
+    #[serde(rename = "Header")]
+    header: RequestHeader,
+    #[serde(rename = "Request")]
+    request: PetCompleteRequestBody,
+}
+
+/// An Apple ID session with GSA.
+///
+/// The client identity it presents comes from the [`ClientProfile`](crate::ClientProfile) of its anisette configuration.
 pub struct AppleAccount {
     //TODO: move this to omnisette
     pub anisette: Mutex<AnisetteData>,
-    // pub spd:  Option<plist::Dictionary>,
-    //mutable spd
-    pub spd: Option<plist::Dictionary>,
-    client: Client,
+    pub spd: Option<ServerProvidedData>,
+    pub(crate) username: Option<String>,
+    app_tokens: HashMap<String, AppToken>,
+    transport: Arc<dyn HttpTransport>,
+    config: AccountConfiguration,
+    url_bag: OnceCell<UrlBag>,
 }
 
 #[derive(Clone)]
 pub struct AppToken {
+    /// Every token returned by the server, keyed by app name
     pub app_tokens: plist::Dictionary,
     pub auth_token: String,
     pub app: String,
+    /// Lifetime of the token, in seconds
+    pub duration: i64,
+    /// Expiry of the token, in milliseconds since the unix epoch
+    pub expiry: i64,
 }
+
+impl AppToken {
+    pub fn expires_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.expiry.max(0) as u64)
+    }
+
+    /// Whether the token has expired or will within `margin`
+    pub fn expires_within(&self, margin: Duration) -> bool {
+        self.expires_at() <= SystemTime::now() + margin
+    }
+}
+
+impl fmt::Debug for AppToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AppToken")
+            .field("app_tokens", &Redacted(&self.app_tokens))
+            .field("auth_token", &Redacted(&self.auth_token))
+            .field("app", &self.app)
+            .field("duration", &self.duration)
+            .field("expiry", &self.expiry)
+            .finish()
+    }
+}
+
 //Just make it return a custom enum, with LoggedIn(account: AppleAccount) or Needs2FA(FinishLoginDel: fn(i32) -> TFAResponse)
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum LoginState {
     LoggedIn,
     // NeedsSMS2FASent(Send2FAToDevices),
@@ -114,26 +177,61 @@ pub enum LoginState {
     NeedsLogin,
 }
 
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 struct VerifyCode {
     code: String,
 }
 
-#[derive(Serialize, Debug, Clone)]
+impl fmt::Debug for VerifyCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VerifyCode")
+            .field("code", &Redacted(&self.code))
+            .finish()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct PhoneNumber {
     id: u32
 }
 
-#[derive(Serialize, Debug, Clone)]
+/// How a verification code is delivered to a trusted phone number
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryMode {
+    Sms,
+    Voice,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct VerifyBody {
     phone_number: PhoneNumber,
-    mode: String,
+    mode: DeliveryMode,
     security_code: Option<VerifyCode>
 }
 
+impl VerifyBody {
+    fn new(phone_id: u32, mode: DeliveryMode) -> VerifyBody {
+        VerifyBody {
+            phone_number: PhoneNumber { id: phone_id },
+            mode,
+            security_code: None,
+        }
+    }
+
+    /// The id of the trusted phone number the code was sent to
+    pub fn phone_id(&self) -> u32 {
+        self.phone_number.id
+    }
+
+    pub fn mode(&self) -> DeliveryMode {
+        self.mode
+    }
+}
+
 #[repr(C)]
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct TrustedPhoneNumber {
     pub number_with_dial_code: String,
@@ -142,6 +240,16 @@ pub struct TrustedPhoneNumber {
     pub id: u32
 }
 
+impl TrustedPhoneNumber {
+    /// The delivery mode Apple uses by default for this number
+    pub fn delivery_mode(&self) -> DeliveryMode {
+        match self.push_mode.as_str() {
+            "voice" => DeliveryMode::Voice,
+            _ => DeliveryMode::Sms,
+        }
+    }
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AuthenticationExtras {
@@ -168,13 +276,12 @@ pub struct AuthenticationExtras {
 //     }
 // }
 
-async fn parse_response(res: Result<Response, reqwest::Error>) -> Result<plist::Dictionary, crate::Error> {
-    let res = res?.text().await?;
-    let res: plist::Dictionary = plist::from_bytes(res.as_bytes())?;
-    let res: plist::Value = res.get("Response").unwrap().to_owned();
-    match res {
-        plist::Value::Dictionary(dict) => Ok(dict),
-        _ => Err(crate::Error::Parse),
+fn parse_response(res: Result<HttpResponse, TransportError>) -> Result<plist::Dictionary, crate::Error> {
+    let res: plist::Dictionary = plist::from_bytes(&res?.body)?;
+    match res.get("Response") {
+        Some(plist::Value::Dictionary(dict)) => Ok(dict.to_owned()),
+        Some(_) => Err(crate::Error::Parse),
+        None => Err(crate::Error::MissingResponseField("Response")),
     }
 }
 
@@ -185,16 +292,63 @@ impl AppleAccount {
     }
 
     pub fn new_with_anisette(anisette: AnisetteData) -> Result<Self, crate::Error> {
-        let client = ClientBuilder::new()
-            .add_root_certificate(Certificate::from_der(APPLE_ROOT)?)
-            .http1_title_case_headers()
-            .connection_verbose(true)
-            .build()?;
+        Self::new_with_configuration(anisette, AccountConfiguration::new())
+    }
+
+    pub fn new_with_configuration(
+        anisette: AnisetteData,
+        config: AccountConfiguration,
+    ) -> Result<Self, crate::Error> {
+        let transport = match config.transport() {
+            Some(transport) => transport.clone(),
+            None => http::apple_transport(config.http_configuration())?,
+        };
 
         Ok(AppleAccount {
-            client,
+            transport,
             anisette: Mutex::new(anisette),
             spd: None,
+            username: None,
+            app_tokens: HashMap::new(),
+            config,
+            url_bag: OnceCell::new(),
+        })
+    }
+
+    /// Resumes an account from a session exported with [`AppleAccount::export_session`].
+    pub async fn from_session(
+        session: AccountSession,
+        config: AnisetteConfiguration,
+    ) -> Result<Self, crate::Error> {
+        let anisette = AnisetteData::new(config).await?;
+        Self::from_session_with_anisette(session, anisette)
+    }
+
+    pub fn from_session_with_anisette(
+        session: AccountSession,
+        anisette: AnisetteData,
+    ) -> Result<Self, crate::Error> {
+        Self::from_session_with_configuration(session, anisette, AccountConfiguration::new())
+    }
+
+    pub fn from_session_with_configuration(
+        session: AccountSession,
+        anisette: AnisetteData,
+        config: AccountConfiguration,
+    ) -> Result<Self, crate::Error> {
+        let mut account = Self::new_with_configuration(anisette, config)?;
+        account.spd = Some(session.spd);
+        account.username = session.username;
+        Ok(account)
+    }
+
+    /// Exports the state of a logged in account, so it can be persisted and resumed later
+    /// without asking for the password or a 2FA code again.
+    pub fn export_session(&self) -> Result<AccountSession, crate::Error> {
+        let session = AccountSession::new(self.spd()?.clone());
+        Ok(match &self.username {
+            Some(username) => session.set_username(username.clone()),
+            None => session,
         })
     }
 
@@ -207,45 +361,257 @@ impl AppleAccount {
         AppleAccount::login_with_anisette(appleid_closure, tfa_closure, anisette).await
     }
 
-    pub async fn get_anisette(&self) -> AnisetteData {
+    pub fn configuration(&self) -> &AccountConfiguration {
+        &self.config
+    }
+
+    /// Sends a request in its own span, logging it with every secret redacted
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse, TransportError> {
+        let span = debug_span!("request", method = %request.method, url = %request.url);
+        async move {
+            trace!(
+                headers = ?RedactedHeaders(&request.headers),
+                body = ?request.body.as_deref().map(String::from_utf8_lossy).map(Redacted),
+                "sending request"
+            );
+            let res = self.transport.send(request).await;
+            match &res {
+                Ok(res) => debug!(status = res.status.as_u16(), "received response"),
+                Err(err) => warn!(error = %err, "request failed"),
+            }
+            res
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Sends a request that is safe to send again, retrying it with the configured policy
+    async fn send_idempotent(&self, request: HttpRequest) -> Result<HttpResponse, TransportError> {
+        http::with_retry(
+            self.config.http_configuration().retry_policy(),
+            || self.send(request.clone()),
+            classify_response,
+        )
+        .await
+    }
+
+    fn gsa_url(&self, path: &str) -> String {
+        format!("{}{}", self.config.base_url().trim_end_matches('/'), path)
+    }
+
+    /// The URL bag of the GSA server, fetched on first use and cached for the lifetime of the account
+    pub async fn url_bag(&self) -> Result<&UrlBag, Error> {
+        self.url_bag
+            .get_or_try_init(|| self.fetch_url_bag())
+            .instrument(debug_span!("url_bag"))
+            .await
+    }
+
+    async fn fetch_url_bag(&self) -> Result<UrlBag, Error> {
+        let valid_anisette = self.get_anisette().await?;
+
+        let mut headers = HeaderMap::new();
+        for (k, v) in valid_anisette.generate_headers(false, true, true).iter() {
+            headers.append(
+                HeaderName::from_bytes(k.as_bytes())?,
+                HeaderValue::from_str(v)?,
+            );
+        }
+        headers.insert(
+            "User-Agent",
+            HeaderValue::from_str(&valid_anisette.config().client_profile().akd_user_agent)?,
+        );
+
+        let res = self
+            .send_idempotent(HttpRequest::get(self.gsa_url(URL_BAG_PATH)).headers(headers))
+            .await?;
+        let bag = UrlBag::from_plist(&plist::from_bytes(&res.body)?)?;
+        debug!(urls = bag.urls().len(), "fetched url bag");
+        Ok(bag)
+    }
+
+    /// Where requests for `endpoint` go: the configured override, then the URL bag, then the usual path under the base url
+    pub async fn endpoint_url(&self, endpoint: Endpoint) -> Result<String, Error> {
+        if let Some(url) = self.config.endpoint(endpoint) {
+            return Ok(url.clone());
+        }
+        if self.config.url_bag_lookup() {
+            if let Some(url) = self.url_bag().await?.endpoint(endpoint) {
+                return Ok(url);
+            }
+        }
+        Ok(self.gsa_url(endpoint.default_path()))
+    }
+
+    /// Returns the server provided data, or [`Error::NotLoggedIn`] if the account never logged in
+    pub fn spd(&self) -> Result<&ServerProvidedData, Error> {
+        self.spd.as_ref().ok_or(Error::NotLoggedIn)
+    }
+
+    pub async fn get_anisette(&self) -> Result<AnisetteData, Error> {
         let mut locked = self.anisette.lock().await;
         if locked.needs_refresh() {
-            *locked = locked.refresh().await.unwrap();
+            debug!("refreshing anisette data");
+            *locked = locked.refresh().await?;
         }
-        locked.clone()
+        Ok(locked.clone())
     }
 
-    pub async fn get_app_token(&self, app_name: &str) -> Result<AppToken, Error> {
-        let spd = self.spd.as_ref().unwrap();
-        // println!("spd: {:#?}", spd);
-        let dsid = spd.get("adsid").unwrap().as_string().unwrap();
-        let auth_token = spd.get("GsIdmsToken").unwrap().as_string().unwrap();
+    /// Whether the PET backing the session has expired or will within the renewal margin
+    pub fn needs_reauth(&self) -> bool {
+        match self.spd.as_ref().and_then(|spd| spd.token(PET_TOKEN).ok()) {
+            Some(pet) => pet.expires_within(self.config.renewal_margin()),
+            None => true,
+        }
+    }
 
-        let valid_anisette = self.get_anisette().await;
+    /// Renews the GsIdmsToken and the PET with a `complete` request carrying the current PET, if
+    /// [`AccountConfiguration::set_pet_renewal`] allows it.
+    ///
+    /// Otherwise, or once the PET has expired or was revoked, this fails with
+    /// [`Error::ReauthRequired`] after calling the configured reauth callback, since only an
+    /// interactive login can get a new one.
+    pub async fn renew(&mut self) -> Result<(), Error> {
+        match self
+            .renew_with_pet()
+            .instrument(debug_span!("renew"))
+            .await
+        {
+            Err(
+                err @ (Error::ReauthRequired
+                | Error::IncorrectCredentials(..)
+                | Error::SecondFactorRequired(..)),
+            ) => {
+                warn!(error = %err, "the session can't be renewed without the user");
+                if let Some(callback) = self.config.reauth_callback() {
+                    callback(&err);
+                }
+                Err(Error::ReauthRequired)
+            }
+            result => result,
+        }
+    }
 
-        let sk = spd.get("sk").unwrap().as_data().unwrap();
-        let c = spd.get("c").unwrap().as_data().unwrap();
-        println!("adsid: {}", dsid);
-        println!("GsIdmsToken: {}", auth_token);
-        // println!("spd: {:#?}", spd);
-        println!("sk: {:#?}", sk);
-        println!("c: {:#?}", c);
+    async fn renew_with_pet(&mut self) -> Result<(), Error> {
+        if !self.config.pet_renewal() {
+            return Err(Error::ReauthRequired);
+        }
+        let spd = self.spd.as_ref().ok_or(Error::ReauthRequired)?;
+        let pet = spd
+            .token(PET_TOKEN)
+            .ok()
+            .filter(|pet| !pet.expires_within(Duration::ZERO))
+            .ok_or(Error::ReauthRequired)?;
+        let dsid = spd.adsid()?;
+        let sk = spd.session_key()?;
+        let c = spd.c()?;
+        // Unverified: there is no public capture of this request. It follows the `apptokens`
+        // request of `get_app_token` (same `c`, `u` and sealed `spd` answer) with the PET as `t`,
+        // and its checksum over "complete" instead of the app name. Hence `set_pet_renewal`.
+        let checksum = Hmac::<Sha256>::new_from_slice(sk)
+            .unwrap()
+            .chain_update("complete".as_bytes())
+            .chain_update(dsid.as_bytes())
+            .finalize()
+            .into_bytes()
+            .to_vec();
+
+        let valid_anisette = self.get_anisette().await?;
+
+        let mut gsa_headers = HeaderMap::new();
+        gsa_headers.insert(
+            "Content-Type",
+            HeaderValue::from_static("text/x-xml-plist"),
+        );
+        gsa_headers.insert("Accept", HeaderValue::from_static("*/*"));
+        gsa_headers.insert(
+            "User-Agent",
+            HeaderValue::from_str(&valid_anisette.config().client_profile().akd_user_agent)?,
+        );
+        gsa_headers.insert(
+            "X-MMe-Client-Info",
+            HeaderValue::from_str(&valid_anisette.get_header("x-mme-client-info")?)?,
+        );
+
+        let body = PetCompleteRequestBody {
+            c: plist::Value::Data(c.to_vec()),
+            cpd: valid_anisette.to_plist(true, false, false),
+            operation: "complete".to_string(),
+            t: pet.token.clone(),
+            u: dsid.to_string(),
+            checksum: plist::Value::Data(checksum),
+        };
+
+        let packet = PetCompleteRequest {
+            header: RequestHeader {
+                version: "1.0.1".to_string(),
+            },
+            request: body,
+        };
+
+        let mut buffer = Vec::new();
+        plist::to_writer_xml(&mut buffer, &packet)?;
+
+        let res = self
+            .send(
+                HttpRequest::post(self.endpoint_url(Endpoint::GsService).await?)
+                    .headers(gsa_headers)
+                    .body(buffer),
+            )
+            .await;
+        let res = parse_response(res)?;
+        Self::check_error(&res)?;
+
+        // sealed with the session key, like the app tokens
+        let spd = Self::get_field(&res, "spd", plist::Value::as_data)?;
+        let decrypted_spd = Self::decrypt_gcm(sk, spd)?;
+        self.spd = Some(plist::from_bytes(&decrypted_spd)?);
+        self.app_tokens.clear();
+        Ok(())
+    }
+
+    /// Returns a cached app token while it is fresh, renewing the session first if needed
+    pub async fn app_token(&mut self, app_name: &str) -> Result<AppToken, Error> {
+        let margin = self.config.renewal_margin();
+        if let Some(token) = self.app_tokens.get(app_name) {
+            if !token.expires_within(margin) && !self.needs_reauth() {
+                return Ok(token.clone());
+            }
+        }
+
+        if self.needs_reauth() {
+            self.renew().await?;
+        }
+        let token = self.get_app_token(app_name).await?;
+        self.app_tokens.insert(app_name.to_string(), token.clone());
+        Ok(token)
+    }
+
+    pub async fn get_app_token(&self, app_name: &str) -> Result<AppToken, Error> {
+        let spd = self.spd()?;
+        let dsid = spd.adsid()?;
+        let auth_token = spd.gs_idms_token()?;
+        let sk = spd.session_key()?;
+        let c = spd.c()?;
 
-        let checksum = Self::create_checksum(&sk.to_vec(), dsid, app_name);
+        debug!(app = app_name, "requesting app token");
+        let valid_anisette = self.get_anisette().await?;
+
+        let checksum = Self::create_checksum(sk, dsid, app_name);
 
         let mut gsa_headers = HeaderMap::new();
         gsa_headers.insert(
             "Content-Type",
-            HeaderValue::from_str("text/x-xml-plist").unwrap(),
+            HeaderValue::from_static("text/x-xml-plist"),
         );
-        gsa_headers.insert("Accept", HeaderValue::from_str("*/*").unwrap());
+        gsa_headers.insert("Accept", HeaderValue::from_static("*/*"));
         gsa_headers.insert(
             "User-Agent",
-            HeaderValue::from_str("akd/1.0 CFNetwork/978.0.7 Darwin/18.7.0").unwrap(),
+            HeaderValue::from_str(&valid_anisette.config().client_profile().akd_user_agent)?,
         );
         gsa_headers.insert(
             "X-MMe-Client-Info",
-            HeaderValue::from_str(&valid_anisette.get_header("x-mme-client-info")?).unwrap(),
+            HeaderValue::from_str(&valid_anisette.get_header("x-mme-client-info")?)?,
         );
 
         let header = RequestHeader {
@@ -268,28 +634,45 @@ impl AppleAccount {
 
         let mut buffer = Vec::new();
         plist::to_writer_xml(&mut buffer, &packet)?;
-        let buffer = String::from_utf8(buffer).unwrap();
-
-        println!("{:?}", gsa_headers.clone());
-        println!("{:?}", buffer);
 
         let res = self
-            .client
-            .post(GSA_ENDPOINT)
-            .headers(gsa_headers.clone())
-            .body(buffer)
-            .send().await;
-        let res = parse_response(res).await?;
-        let err_check = Self::check_error(&res);
-        if err_check.is_err() {
-            return Err(err_check.err().unwrap());
-        }
-        println!("{:?}", res);
-        todo!()
+            .send(
+                HttpRequest::post(self.endpoint_url(Endpoint::GsService).await?)
+                    .headers(gsa_headers.clone())
+                    .body(buffer),
+            )
+            .await;
+        let res = parse_response(res)?;
+        Self::check_error(&res)?;
+
+        let encrypted_token = Self::get_field(&res, "et", plist::Value::as_data)?;
+        let decrypted_token = Self::decrypt_gcm(sk, encrypted_token)?;
+        let decoded_token: plist::Dictionary = plist::from_bytes(&decrypted_token)?;
+
+        let app_tokens = decoded_token
+            .get("t")
+            .and_then(|t| t.as_dictionary())
+            .ok_or(Error::Parse)?;
+        let app_token = app_tokens
+            .get(app_name)
+            .and_then(|t| t.as_dictionary())
+            .ok_or(Error::Parse)?;
+
+        let token = app_token.get("token").and_then(|t| t.as_string()).ok_or(Error::Parse)?;
+        let duration = app_token.get("duration").and_then(|d| d.as_signed_integer()).ok_or(Error::Parse)?;
+        let expiry = app_token.get("expiry").and_then(|e| e.as_signed_integer()).ok_or(Error::Parse)?;
+
+        Ok(AppToken {
+            app_tokens: app_tokens.clone(),
+            auth_token: token.to_string(),
+            app: app_name.to_string(),
+            duration,
+            expiry,
+        })
     }
 
-    fn create_checksum(session_key: &Vec<u8>, dsid: &str, app_name: &str) -> Vec<u8> {
-        Hmac::<Sha256>::new_from_slice(&session_key)
+    fn create_checksum(session_key: &[u8], dsid: &str, app_name: &str) -> Vec<u8> {
+        Hmac::<Sha256>::new_from_slice(session_key)
             .unwrap()
             .chain_update("apptokens".as_bytes())
             .chain_update(dsid.as_bytes())
@@ -324,49 +707,36 @@ impl AppleAccount {
         tfa_closure: G,
         anisette: AnisetteData,
     ) -> Result<AppleAccount, Error> {
-        let mut _self = AppleAccount::new_with_anisette(anisette)?;
         let (username, password) = appleid_closure();
-        let mut response = _self.login_email_pass(&username, &password).await?;
+        let mut flow = LoginFlow::new(AppleAccount::new_with_anisette(anisette)?);
+        let mut state = flow.begin(&username, &password).await?;
         loop {
-            match response {
-                LoginState::NeedsDevice2FA => response = _self.send_2fa_to_devices().await?,
-                LoginState::Needs2FAVerification => {
-                    response = _self.verify_2fa(tfa_closure()).await?
-                }
+            state = match state {
+                LoginState::NeedsDevice2FA => flow.request_device_code().await?,
+                LoginState::Needs2FAVerification => flow.submit_device_code(tfa_closure()).await?,
                 LoginState::NeedsSMS2FA => {
-                    response = _self.send_sms_2fa_to_devices(1).await?
-                }
-                LoginState::NeedsSMS2FAVerification(body) => {
-                    response = _self.verify_sms_2fa(tfa_closure(), body).await?
-                }
-                LoginState::NeedsLogin => {
-                    response = _self.login_email_pass(&username, &password).await?
-                }
-                LoginState::LoggedIn => return Ok(_self),
-                LoginState::NeedsExtraStep(step) => {
-                    if _self.get_pet().is_some() {
-                        return Ok(_self)
-                    } else {
-                        return Err(Error::ExtraStep(step))
-                    }
+                    let number = flow
+                        .trusted_phone_numbers()
+                        .await?
+                        .into_iter()
+                        .next()
+                        .ok_or(Error::NoTrustedPhoneNumbers)?;
+                    flow.request_phone_code(number.id, number.delivery_mode()).await?
                 }
+                LoginState::NeedsSMS2FAVerification(_) => flow.submit_sms_code(tfa_closure()).await?,
+                LoginState::NeedsLogin => flow.relogin().await?,
+                LoginState::LoggedIn | LoginState::NeedsExtraStep(_) => return flow.into_account(),
             }
         }
     }
 
-    pub fn get_pet(&self) -> Option<String> {
-        let Some(token) = self.spd.as_ref().unwrap().get("t") else {
-            return None
-        };
-        Some(token.as_dictionary().unwrap().get("com.apple.gs.idms.pet")
-            .unwrap().as_dictionary().unwrap().get("token").unwrap().as_string().unwrap().to_string())
+    pub fn get_pet(&self) -> Result<String, Error> {
+        Ok(self.spd()?.pet()?.to_string())
     }
 
-    pub fn get_name(&self) -> (String, String) {
-        (
-            self.spd.as_ref().unwrap().get("fn").unwrap().as_string().unwrap().to_string(),
-            self.spd.as_ref().unwrap().get("ln").unwrap().as_string().unwrap().to_string()
-        )
+    pub fn get_name(&self) -> Result<(String, String), Error> {
+        let (first_name, last_name) = self.spd()?.name()?;
+        Ok((first_name.to_string(), last_name.to_string()))
     }
 
     pub async fn login_email_pass(
@@ -374,25 +744,25 @@ impl AppleAccount {
         username: &str,
         password: &str,
     ) -> Result<LoginState, Error> {
-        let srp_client = SrpClient::<Sha256>::new(&G_2048);
+        let srp_client = AppleSrp::new();
         let a: Vec<u8> = (0..32).map(|_| rand::random::<u8>()).collect();
         let a_pub = srp_client.compute_public_ephemeral(&a);
 
-        let valid_anisette = self.get_anisette().await;
+        let valid_anisette = self.get_anisette().await?;
 
         let mut gsa_headers = HeaderMap::new();
         gsa_headers.insert(
             "Content-Type",
-            HeaderValue::from_str("text/x-xml-plist").unwrap(),
+            HeaderValue::from_static("text/x-xml-plist"),
         );
-        gsa_headers.insert("Accept", HeaderValue::from_str("*/*").unwrap());
+        gsa_headers.insert("Accept", HeaderValue::from_static("*/*"));
         gsa_headers.insert(
             "User-Agent",
-            HeaderValue::from_str("akd/1.0 CFNetwork/978.0.7 Darwin/18.7.0").unwrap(),
+            HeaderValue::from_str(&valid_anisette.config().client_profile().akd_user_agent)?,
         );
         gsa_headers.insert(
             "X-MMe-Client-Info",
-            HeaderValue::from_str(&valid_anisette.get_header("x-mme-client-info")?).unwrap(),
+            HeaderValue::from_str(&valid_anisette.get_header("x-mme-client-info")?)?,
         );
 
         let header = RequestHeader {
@@ -402,7 +772,10 @@ impl AppleAccount {
             a_pub: plist::Value::Data(a_pub),
             cpd: valid_anisette.to_plist(true, false, false),
             operation: "init".to_string(),
-            ps: vec!["s2k".to_string(), "s2k_fo".to_string()],
+            ps: PasswordProtocol::SUPPORTED
+                .iter()
+                .map(|p| p.name().to_string())
+                .collect(),
             username: username.to_string(),
         };
 
@@ -413,44 +786,34 @@ impl AppleAccount {
 
         let mut buffer = Vec::new();
         plist::to_writer_xml(&mut buffer, &packet)?;
-        let buffer = String::from_utf8(buffer).unwrap();
-
-        // println!("{:?}", gsa_headers.clone());
-        // println!("{:?}", buffer);
 
         let res = self
-            .client
-            .post(GSA_ENDPOINT)
-            .headers(gsa_headers.clone())
-            .body(buffer)
-            .send().await;
-
-        let res = parse_response(res).await?;
-        let err_check = Self::check_error(&res);
-        if err_check.is_err() {
-            return Err(err_check.err().unwrap());
-        }
-        // println!("{:?}", res);
-        let salt = res.get("s").unwrap().as_data().unwrap();
-        let b_pub = res.get("B").unwrap().as_data().unwrap();
-        let iters = res.get("i").unwrap().as_signed_integer().unwrap();
-        let c = res.get("c").unwrap().as_string().unwrap();
-
-        let mut password_hasher = sha2::Sha256::new();
-        password_hasher.update(&password.as_bytes());
-        let hashed_password = password_hasher.finalize();
-
-        let mut password_buf = [0u8; 32];
-        pbkdf2::pbkdf2::<hmac::Hmac<Sha256>>(
-            &hashed_password,
-            salt,
-            iters as u32,
-            &mut password_buf,
-        );
+            .send(
+                HttpRequest::post(self.endpoint_url(Endpoint::GsService).await?)
+                    .headers(gsa_headers.clone())
+                    .body(buffer),
+            )
+            .await;
+
+        let res = parse_response(res)?;
+        Self::check_error(&res)?;
 
-        let verifier: SrpClientVerifier<Sha256> = srp_client
-            .process_reply(&a, &username.as_bytes(), &password_buf, salt, b_pub)
-            .unwrap();
+        let salt = Self::get_field(&res, "s", plist::Value::as_data)?;
+        let b_pub = Self::get_field(&res, "B", plist::Value::as_data)?;
+        let iters = Self::get_field(&res, "i", plist::Value::as_signed_integer)?;
+        let c = Self::get_field(&res, "c", plist::Value::as_string)?;
+        // older servers don't send `sp` and always use s2k
+        let protocol = match res.get("sp").and_then(|sp| sp.as_string()) {
+            Some(sp) => PasswordProtocol::from_name(sp)?,
+            None => PasswordProtocol::S2k,
+        };
+        debug!(protocol = protocol.name(), iterations = iters, "got SRP challenge");
+
+        let password_buf = protocol.derive_password(password, salt, iters as u32);
+
+        let verifier = srp_client
+            .process_reply(&a, username.as_bytes(), &password_buf, salt, b_pub)
+            .map_err(Error::Srp)?;
 
         let m = verifier.proof();
 
@@ -469,33 +832,32 @@ impl AppleAccount {
 
         let mut buffer = Vec::new();
         plist::to_writer_xml(&mut buffer, &packet)?;
-        let buffer = String::from_utf8(buffer).unwrap();
 
         let res = self
-            .client
-            .post(GSA_ENDPOINT)
-            .headers(gsa_headers.clone())
-            .body(buffer)
-            .send().await;
-
-        let res = parse_response(res).await?;
-        let err_check = Self::check_error(&res);
-        if err_check.is_err() {
-            return Err(err_check.err().unwrap());
-        }
-        // println!("{:?}", res);
-        let m2 = res.get("M2").unwrap().as_data().unwrap();
-        verifier.verify_server(&m2).unwrap();
+            .send(
+                HttpRequest::post(self.endpoint_url(Endpoint::GsService).await?)
+                    .headers(gsa_headers.clone())
+                    .body(buffer),
+            )
+            .await;
+
+        let res = parse_response(res)?;
+        Self::check_error(&res)?;
 
-        let spd = res.get("spd").unwrap().as_data().unwrap();
-        let decrypted_spd = Self::decrypt_cbc(&verifier, spd);
-        let decoded_spd: plist::Dictionary = plist::from_bytes(&decrypted_spd).unwrap();
+        let m2 = Self::get_field(&res, "M2", plist::Value::as_data)?;
+        verifier.verify_server(&m2).map_err(Error::Srp)?;
 
-        let status = res.get("Status").unwrap().as_dictionary().unwrap();
+        let spd = Self::get_field(&res, "spd", plist::Value::as_data)?;
+        let decrypted_spd = Self::decrypt_cbc(&verifier, spd)?;
+        let decoded_spd: ServerProvidedData = plist::from_bytes(&decrypted_spd)?;
+
+        let status = Self::get_field(&res, "Status", plist::Value::as_dictionary)?;
 
         self.spd = Some(decoded_spd);
+        self.username = Some(username.to_string());
 
         if let Some(plist::Value::String(s)) = status.get("au") {
+            debug!(step = s.as_str(), "login needs another step");
             return match s.as_str() {
                 "trustedDeviceSecondaryAuth" => Ok(LoginState::NeedsDevice2FA),
                 "secondaryAuth" => Ok(LoginState::NeedsSMS2FA),
@@ -515,27 +877,50 @@ impl AppleAccount {
             .to_vec()
     }
 
-    fn decrypt_cbc(usr: &SrpClientVerifier<Sha256>, data: &[u8]) -> Vec<u8> {
+    fn decrypt_cbc(usr: &SrpClientVerifier<Sha256>, data: &[u8]) -> Result<Vec<u8>, Error> {
         let extra_data_key = Self::create_session_key(usr, "extra data key:");
         let extra_data_iv = Self::create_session_key(usr, "extra data iv:");
         let extra_data_iv = &extra_data_iv[..16];
 
         cbc::Decryptor::<aes::Aes256>::new_from_slices(&extra_data_key, extra_data_iv)
-            .unwrap()
+            .map_err(|_| Error::DecryptionFailed)?
             .decrypt_padded_vec_mut::<Pkcs7>(&data)
-            .unwrap()
+            .map_err(|_| Error::DecryptionFailed)
+    }
+
+    /// Decrypts an `et` payload from the `apptokens` operation.
+    /// The payload is laid out as a 3 byte version header (also used as the AAD),
+    /// a 16 byte IV, and the AES-256-GCM ciphertext followed by its 16 byte tag.
+    fn decrypt_gcm(session_key: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        if data.len() < 3 + 16 + 16 {
+            return Err(Error::DecryptionFailed);
+        }
+        let (version, rest) = data.split_at(3);
+        let (iv, ciphertext) = rest.split_at(16);
+
+        <AesGcm<aes::Aes256, U16> as aes_gcm::KeyInit>::new_from_slice(session_key)
+            .map_err(|_| Error::DecryptionFailed)?
+            .decrypt(
+                GenericArray::from_slice(iv),
+                Payload {
+                    msg: ciphertext,
+                    aad: version,
+                },
+            )
+            .map_err(|_| Error::DecryptionFailed)
     }
 
     pub async fn send_2fa_to_devices(&self) -> Result<LoginState, crate::Error> {
         let headers = self.build_2fa_headers(false);
 
         let res = self
-            .client
-            .get("https://gsa.apple.com/auth/verify/trusteddevice")
-            .headers(headers.await)
-            .send().await?;
+            .send_idempotent(
+                HttpRequest::get(self.endpoint_url(Endpoint::TrustedDeviceSecondaryAuth).await?)
+                    .headers(headers.await?),
+            )
+            .await?;
 
-        if !res.status().is_success() {
+        if !res.is_success() {
             return Err(Error::AuthSrp);
         }
 
@@ -543,49 +928,57 @@ impl AppleAccount {
     }
 
     pub async fn send_sms_2fa_to_devices(&self, phone_id: u32) -> Result<LoginState, crate::Error> {
+        self.send_phone_code(phone_id, DeliveryMode::Sms).await
+    }
+
+    /// Sends a verification code to the trusted phone number with `phone_id`, by SMS or voice call
+    pub async fn send_phone_code(
+        &self,
+        phone_id: u32,
+        mode: DeliveryMode,
+    ) -> Result<LoginState, crate::Error> {
         let headers = self.build_2fa_headers(true);
 
-
-        let body = VerifyBody {
-            phone_number: PhoneNumber {
-                id: phone_id
-            },
-            mode: "sms".to_string(),
-            security_code: None
-        };
+        let body = VerifyBody::new(phone_id, mode);
 
         let res = self
-            .client
-            .put("https://gsa.apple.com/auth/verify/phone/")
-            .headers(headers.await)
-            .json(&body)
-            .send().await?;
+            .send(
+                HttpRequest::put(self.endpoint_url(Endpoint::VerifyPhone).await?)
+                    .headers(headers.await?)
+                    .header("Content-Type", HeaderValue::from_static("application/json"))
+                    .body(serde_json::to_vec(&body)?),
+            )
+            .await?;
 
-        if !res.status().is_success() {
+        if !res.is_success() {
             return Err(Error::AuthSrp);
         }
 
         return Ok(LoginState::NeedsSMS2FAVerification(body));
     }
 
+    /// Lists the phone numbers a verification code can be sent to
+    pub async fn trusted_phone_numbers(&self) -> Result<Vec<TrustedPhoneNumber>, Error> {
+        Ok(self.get_auth_extras().await?.trusted_phone_numbers)
+    }
+
     pub async fn get_auth_extras(&self) -> Result<AuthenticationExtras, Error> {
         let headers = self.build_2fa_headers(true);
 
-        let req = self.client
-            .get("https://gsa.apple.com/auth")
-            .headers(headers.await)
-            .header("Accept", "application/json")
-            .send().await?;
-        let status = req.status().as_u16();
-        let mut new_state = req.json::<AuthenticationExtras>().await?;
+        let req = self
+            .send_idempotent(
+                HttpRequest::get(self.endpoint_url(Endpoint::SecondaryAuth).await?)
+                    .headers(headers.await?)
+                    .header("Accept", HeaderValue::from_static("application/json")),
+            )
+            .await?;
+        let status = req.status.as_u16();
+        let mut new_state = serde_json::from_slice::<AuthenticationExtras>(&req.body)?;
         if status == 201 {
-            new_state.new_state = Some(LoginState::NeedsSMS2FAVerification(VerifyBody {
-                phone_number: PhoneNumber {
-                    id: new_state.trusted_phone_numbers.first().unwrap().id
-                },
-                mode: "sms".to_string(),
-                security_code: None
-            }));
+            // the server already sent a code to the first number
+            new_state.new_state = new_state.trusted_phone_numbers.first().map(|number| {
+                LoginState::NeedsSMS2FAVerification(VerifyBody::new(number.id, number.delivery_mode()))
+            });
         }
 
         Ok(new_state)
@@ -593,19 +986,18 @@ impl AppleAccount {
 
     pub async fn verify_2fa(&self, code: String) -> Result<LoginState, Error> {
         let headers = self.build_2fa_headers(false);
-        // println!("Recieved code: {}", code);
         let res = self
-            .client
-            .get("https://gsa.apple.com/grandslam/GsService2/validate")
-            .headers(headers.await)
-            .header(
-                HeaderName::from_str("security-code").unwrap(),
-                HeaderValue::from_str(&code).unwrap(),
+            .send(
+                HttpRequest::get(self.endpoint_url(Endpoint::ValidateCode).await?)
+                    .headers(headers.await?)
+                    .header(
+                        HeaderName::from_static("security-code"),
+                        HeaderValue::from_str(&code)?,
+                    ),
             )
-            .send().await?;
+            .await?;
 
-        let res: plist::Dictionary =
-            plist::from_bytes(res.text().await?.as_bytes())?;
+        let res: plist::Dictionary = plist::from_bytes(&res.body)?;
 
         Self::check_error(&res)?;
 
@@ -613,81 +1005,111 @@ impl AppleAccount {
     }
 
     pub async fn verify_sms_2fa(&self, code: String, mut body: VerifyBody) -> Result<LoginState, Error> {
-        let headers = self.build_2fa_headers(true).await;
-        // println!("Recieved code: {}", code);
+        let headers = self.build_2fa_headers(true).await?;
 
         body.security_code = Some(VerifyCode { code });
 
         let res = self
-            .client
-            .post("https://gsa.apple.com/auth/verify/phone/securitycode")
-            .headers(headers)
-            .header("accept", "application/json")
-            .json(&body)
-            .send().await?;
-
-        if res.status() != 200 {
-            return Err(Error::Bad2faCode);
+            .send(
+                HttpRequest::post(self.endpoint_url(Endpoint::VerifyPhoneCode).await?)
+                    .headers(headers)
+                    .header("accept", HeaderValue::from_static("application/json"))
+                    .header("Content-Type", HeaderValue::from_static("application/json"))
+                    .body(serde_json::to_vec(&body)?),
+            )
+            .await?;
+
+        if res.status != 200 {
+            return Err(Self::service_error(&res.body));
         }
 
         Ok(LoginState::NeedsLogin)
     }
 
+    /// The first of the `service_errors` in a failed JSON response, a wrong code if there is none
+    fn service_error(body: &[u8]) -> Error {
+        let error = serde_json::from_slice::<serde_json::Value>(body)
+            .ok()
+            .and_then(|body| body.get("service_errors")?.get(0).cloned());
+        let code = error
+            .as_ref()
+            .and_then(|error| error.get("code")?.as_str()?.parse().ok())
+            .unwrap_or(-21669);
+        let message = error
+            .as_ref()
+            .and_then(|error| error.get("message")?.as_str())
+            .unwrap_or_default();
+        Error::from_gsa_status(code, message.to_string())
+    }
+
     fn check_error(res: &plist::Dictionary) -> Result<(), Error> {
         let res = match res.get("Status") {
             Some(plist::Value::Dictionary(d)) => d,
             _ => &res,
         };
 
-        if res.get("ec").unwrap().as_signed_integer().unwrap() != 0 {
-            return Err(Error::AuthSrpWithMessage(
-                res.get("ec").unwrap().as_signed_integer().unwrap(),
-                res.get("em").unwrap().as_string().unwrap().to_owned(),
-            ));
+        let code = Self::get_field(res, "ec", plist::Value::as_signed_integer)?;
+        if code != 0 {
+            let message = res
+                .get("em")
+                .and_then(|em| em.as_string())
+                .unwrap_or_default();
+            return Err(Error::from_gsa_status(code, message.to_owned()));
         }
 
         Ok(())
     }
 
-    pub async fn build_2fa_headers(&self, sms: bool) -> HeaderMap {
-        let spd = self.spd.as_ref().unwrap();
-        let dsid = spd.get("adsid").unwrap().as_string().unwrap();
-        let token = spd.get("GsIdmsToken").unwrap().as_string().unwrap();
+    /// Gets a field out of a GSA response, failing with [`Error::MissingResponseField`] if it is absent or has the wrong type
+    fn get_field<'a, T>(
+        res: &'a plist::Dictionary,
+        key: &'static str,
+        convert: impl FnOnce(&'a plist::Value) -> Option<T>,
+    ) -> Result<T, Error> {
+        res.get(key)
+            .and_then(convert)
+            .ok_or(Error::MissingResponseField(key))
+    }
+
+    pub async fn build_2fa_headers(&self, sms: bool) -> Result<HeaderMap, Error> {
+        let spd = self.spd()?;
+        let dsid = spd.adsid()?;
+        let token = spd.gs_idms_token()?;
 
         let identity_token = base64::encode(format!("{}:{}", dsid, token));
 
-        let valid_anisette = self.get_anisette().await;
+        let valid_anisette = self.get_anisette().await?;
 
         let mut headers = HeaderMap::new();
-        valid_anisette
-            .generate_headers(false, true, true)
-            .iter()
-            .for_each(|(k, v)| {
-                headers.append(
-                    HeaderName::from_bytes(k.as_bytes()).unwrap(),
-                    HeaderValue::from_str(v).unwrap(),
-                );
-            });
+        for (k, v) in valid_anisette.generate_headers(false, true, true).iter() {
+            headers.append(
+                HeaderName::from_bytes(k.as_bytes())?,
+                HeaderValue::from_str(v)?,
+            );
+        }
 
         if !sms {
             headers.insert(
                 "Content-Type",
-                HeaderValue::from_str("text/x-xml-plist").unwrap(),
+                HeaderValue::from_static("text/x-xml-plist"),
             );
-            headers.insert("Accept", HeaderValue::from_str("text/x-xml-plist").unwrap());
+            headers.insert("Accept", HeaderValue::from_static("text/x-xml-plist"));
         }
-        headers.insert("User-Agent", HeaderValue::from_str("Xcode").unwrap());
-        headers.insert("Accept-Language", HeaderValue::from_str("en-us").unwrap());
+        headers.insert(
+            "User-Agent",
+            HeaderValue::from_str(&valid_anisette.config().client_profile().user_agent)?,
+        );
+        headers.insert("Accept-Language", HeaderValue::from_static("en-us"));
         headers.append(
             "X-Apple-Identity-Token",
-            HeaderValue::from_str(&identity_token).unwrap(),
+            HeaderValue::from_str(&identity_token)?,
         );
 
         headers.insert(
             "Loc",
-            HeaderValue::from_str(&valid_anisette.get_header("x-apple-locale").unwrap()).unwrap(),
+            HeaderValue::from_str(&valid_anisette.get_header("x-apple-locale")?)?,
         );
 
-        headers
+        Ok(headers)
     }
 }
@@ -0,0 +1,250 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, debug_span, Instrument};
+
+use crate::{
+    AppleAccount, AuthenticationExtras, DeliveryMode, Error, LoginState, Redacted,
+    ServerProvidedData, SessionCipher, TrustedPhoneNumber,
+};
+
+/// A login driven one step at a time, for frontends where the 2FA code arrives later
+/// (e.g. from a GUI dialog or a separate HTTP request) instead of from a blocking closure.
+///
+/// Every step returns the new [`LoginState`]; the flow can be suspended between steps with
+/// [`LoginFlow::suspend`] and picked up again with [`LoginFlow::resume`].
+pub struct LoginFlow {
+    account: AppleAccount,
+    username: String,
+    password: String,
+    state: LoginState,
+}
+
+impl LoginFlow {
+    pub fn new(account: AppleAccount) -> LoginFlow {
+        LoginFlow {
+            account,
+            username: String::new(),
+            password: String::new(),
+            state: LoginState::NeedsLogin,
+        }
+    }
+
+    /// Continues a suspended flow, using `account` for the anisette data and configuration
+    pub fn resume(snapshot: LoginFlowSnapshot, mut account: AppleAccount) -> LoginFlow {
+        let snapshot = snapshot.0;
+        account.spd = snapshot.spd;
+        if !snapshot.username.is_empty() {
+            account.username = Some(snapshot.username.clone());
+        }
+        LoginFlow {
+            account,
+            username: snapshot.username,
+            password: snapshot.password,
+            state: snapshot.state,
+        }
+    }
+
+    pub fn suspend(&self) -> LoginFlowSnapshot {
+        LoginFlowSnapshot(SnapshotData {
+            username: self.username.clone(),
+            password: self.password.clone(),
+            spd: self.account.spd.clone(),
+            state: self.state.clone(),
+        })
+    }
+
+    pub fn state(&self) -> &LoginState {
+        &self.state
+    }
+
+    pub fn account(&self) -> &AppleAccount {
+        &self.account
+    }
+
+    /// Starts the SRP login; the password is kept to log in again once 2FA is done
+    pub async fn begin(&mut self, username: &str, password: &str) -> Result<LoginState, Error> {
+        self.username = username.to_string();
+        self.password = password.to_string();
+        self.relogin().await
+    }
+
+    /// Logs in again with the credentials given to [`LoginFlow::begin`]
+    pub async fn relogin(&mut self) -> Result<LoginState, Error> {
+        if self.username.is_empty() {
+            return Err(Error::InvalidLoginStep("relogin"));
+        }
+        let state = self
+            .account
+            .login_email_pass(&self.username, &self.password)
+            .instrument(debug_span!("login", username = %Redacted(&self.username)))
+            .await?;
+        Ok(self.set_state(state))
+    }
+
+    /// Asks Apple to push a code to the account's trusted devices
+    pub async fn request_device_code(&mut self) -> Result<LoginState, Error> {
+        if !matches!(
+            self.state,
+            LoginState::NeedsDevice2FA | LoginState::Needs2FAVerification
+        ) {
+            return Err(Error::InvalidLoginStep("request_device_code"));
+        }
+        let state = self
+            .account
+            .send_2fa_to_devices()
+            .instrument(debug_span!("request_device_code"))
+            .await?;
+        Ok(self.set_state(state))
+    }
+
+    /// Verifies a trusted device code, then logs in again to finish the flow
+    pub async fn submit_device_code(&mut self, code: String) -> Result<LoginState, Error> {
+        if !matches!(
+            self.state,
+            LoginState::NeedsDevice2FA | LoginState::Needs2FAVerification
+        ) {
+            return Err(Error::InvalidLoginStep("submit_device_code"));
+        }
+        let state = self
+            .account
+            .verify_2fa(code)
+            .instrument(debug_span!("submit_device_code"))
+            .await?;
+        self.set_state(state);
+        self.relogin().await
+    }
+
+    /// The trusted phone numbers a code can be sent to
+    pub async fn auth_extras(&self) -> Result<AuthenticationExtras, Error> {
+        if !self.needs_second_factor() {
+            return Err(Error::InvalidLoginStep("auth_extras"));
+        }
+        self.account
+            .get_auth_extras()
+            .instrument(debug_span!("auth_extras"))
+            .await
+    }
+
+    /// The phone numbers a code can be sent to with [`LoginFlow::request_phone_code`]
+    pub async fn trusted_phone_numbers(&self) -> Result<Vec<TrustedPhoneNumber>, Error> {
+        if !self.needs_second_factor() {
+            return Err(Error::InvalidLoginStep("trusted_phone_numbers"));
+        }
+        self.account
+            .trusted_phone_numbers()
+            .instrument(debug_span!("trusted_phone_numbers"))
+            .await
+    }
+
+    /// Sends a code by SMS to the trusted phone number with `phone_id`
+    pub async fn request_sms(&mut self, phone_id: u32) -> Result<LoginState, Error> {
+        self.request_phone_code(phone_id, DeliveryMode::Sms).await
+    }
+
+    /// Sends a code to the trusted phone number with `phone_id`, by SMS or voice call
+    pub async fn request_phone_code(
+        &mut self,
+        phone_id: u32,
+        mode: DeliveryMode,
+    ) -> Result<LoginState, Error> {
+        if !self.needs_second_factor() {
+            return Err(Error::InvalidLoginStep("request_phone_code"));
+        }
+        let state = self
+            .account
+            .send_phone_code(phone_id, mode)
+            .instrument(debug_span!("request_phone_code", phone_id, ?mode))
+            .await?;
+        Ok(self.set_state(state))
+    }
+
+    /// Verifies the code sent by [`LoginFlow::request_phone_code`], then logs in again to finish the flow
+    pub async fn submit_sms_code(&mut self, code: String) -> Result<LoginState, Error> {
+        let body = match &self.state {
+            LoginState::NeedsSMS2FAVerification(body) => body.clone(),
+            _ => return Err(Error::InvalidLoginStep("submit_sms_code")),
+        };
+        let state = self
+            .account
+            .verify_sms_2fa(code, body)
+            .instrument(debug_span!("submit_sms_code"))
+            .await?;
+        self.set_state(state);
+        self.relogin().await
+    }
+
+    /// Returns the logged in account once the flow is done
+    pub fn into_account(self) -> Result<AppleAccount, Error> {
+        match self.state {
+            LoginState::LoggedIn => Ok(self.account),
+            // the account can still be used if the server handed out a PET
+            LoginState::NeedsExtraStep(step) => match self.account.get_pet() {
+                Ok(_) => Ok(self.account),
+                Err(_) => Err(Error::ExtraStep(step)),
+            },
+            _ => Err(Error::InvalidLoginStep("into_account")),
+        }
+    }
+
+    fn needs_second_factor(&self) -> bool {
+        matches!(
+            self.state,
+            LoginState::NeedsDevice2FA
+                | LoginState::Needs2FAVerification
+                | LoginState::NeedsSMS2FA
+                | LoginState::NeedsSMS2FAVerification(_)
+        )
+    }
+
+    fn set_state(&mut self, state: LoginState) -> LoginState {
+        debug!(?state, "login state changed");
+        self.state = state;
+        self.state.clone()
+    }
+}
+
+/// A suspended [`LoginFlow`].
+///
+/// It holds the password, which the flow logs in with again once 2FA is done, and the
+/// GsIdmsToken, so it can only leave the process sealed with a [`SessionCipher`].
+#[derive(Clone)]
+pub struct LoginFlowSnapshot(SnapshotData);
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SnapshotData {
+    username: String,
+    password: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    spd: Option<ServerProvidedData>,
+    state: LoginState,
+}
+
+impl fmt::Debug for LoginFlowSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoginFlowSnapshot")
+            .field("username", &self.0.username)
+            .field("state", &self.0.state)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LoginFlowSnapshot {
+    pub fn state(&self) -> &LoginState {
+        &self.0.state
+    }
+
+    pub fn to_encrypted_bytes(&self, cipher: &dyn SessionCipher) -> Result<Vec<u8>, Error> {
+        let mut buffer = Vec::new();
+        plist::to_writer_binary(&mut buffer, &self.0)?;
+        cipher.encrypt(&buffer).map_err(Error::SessionCipher)
+    }
+
+    pub fn from_encrypted_bytes(
+        data: &[u8],
+        cipher: &dyn SessionCipher,
+    ) -> Result<LoginFlowSnapshot, Error> {
+        let data = cipher.decrypt(data).map_err(Error::SessionCipher)?;
+        Ok(LoginFlowSnapshot(plist::from_bytes(&data)?))
+    }
+}
@@ -1,27 +1,264 @@
 pub mod anisette;
+#[cfg(feature = "blocking")]
+pub mod blocking;
 mod client;
-use std::fmt::Display;
+mod flow;
+mod manager;
+mod password;
+mod redact;
+mod retry;
+mod session;
+mod spd;
+mod url_bag;
 
-pub use client::{AppleAccount, LoginState, TrustedPhoneNumber, AuthenticationExtras, VerifyBody};
-pub use omnisette::AnisetteConfiguration;
+pub use client::{AppleAccount, AppToken, LoginState, TrustedPhoneNumber, AuthenticationExtras, VerifyBody, DeliveryMode};
+pub use flow::{LoginFlow, LoginFlowSnapshot};
+pub use manager::AccountManager;
+pub use password::PasswordProtocol;
+pub use redact::Redacted;
+pub use session::{AccountSession, AesGcmCipher, SessionCipher};
+pub use spd::{ServerProvidedData, SpdToken};
+pub use url_bag::{Endpoint, UrlBag, URL_BAG_PATH};
+pub use omnisette::{AnisetteConfiguration, ClientProfile};
+pub use omnisette::http::{HttpConfiguration, RetryPolicy};
 
+use std::{collections::HashMap, fmt, sync::Arc, time::Duration};
+
+use omnisette::http::HttpTransport;
 use thiserror::Error;
+
+pub const DEFAULT_GSA_URL: &str = "https://gsa.apple.com";
+
+/// Called with the reason when a session can no longer be renewed without the user
+pub type ReauthCallback = Arc<dyn Fn(&Error) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct AccountConfiguration {
+    base_url: String,
+    endpoints: HashMap<Endpoint, String>,
+    url_bag_lookup: bool,
+    transport: Option<Arc<dyn HttpTransport>>,
+    http: HttpConfiguration,
+    renewal_margin: Duration,
+    pet_renewal: bool,
+    reauth_callback: Option<ReauthCallback>,
+}
+
+impl fmt::Debug for AccountConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccountConfiguration")
+            .field("base_url", &self.base_url)
+            .field("endpoints", &self.endpoints)
+            .field("url_bag_lookup", &self.url_bag_lookup)
+            .field("custom_transport", &self.transport.is_some())
+            .field("http", &self.http)
+            .field("renewal_margin", &self.renewal_margin)
+            .field("pet_renewal", &self.pet_renewal)
+            .field("reauth_callback", &self.reauth_callback.is_some())
+            .finish()
+    }
+}
+
+impl Default for AccountConfiguration {
+    fn default() -> Self {
+        AccountConfiguration::new()
+    }
+}
+
+impl AccountConfiguration {
+    pub fn new() -> AccountConfiguration {
+        AccountConfiguration {
+            base_url: DEFAULT_GSA_URL.to_string(),
+            endpoints: HashMap::new(),
+            url_bag_lookup: true,
+            transport: None,
+            http: HttpConfiguration::new(),
+            renewal_margin: Duration::from_secs(60),
+            pet_renewal: false,
+            reauth_callback: None,
+        }
+    }
+
+    pub fn base_url(&self) -> &String {
+        &self.base_url
+    }
+
+    /// Sends every GSA request to `base_url` instead of gsa.apple.com, e.g. to talk to a local emulator
+    pub fn set_base_url(mut self, base_url: String) -> AccountConfiguration {
+        self.base_url = base_url;
+        self
+    }
+
+    pub fn endpoint(&self, endpoint: Endpoint) -> Option<&String> {
+        self.endpoints.get(&endpoint)
+    }
+
+    /// Sends the requests for `endpoint` to `url`, whatever the URL bag says, e.g. to use a staging server
+    pub fn set_endpoint(mut self, endpoint: Endpoint, url: String) -> AccountConfiguration {
+        self.endpoints.insert(endpoint, url);
+        self
+    }
+
+    pub fn url_bag_lookup(&self) -> bool {
+        self.url_bag_lookup
+    }
+
+    /// Whether the endpoints are looked up in the URL bag at [`URL_BAG_PATH`], on by default.
+    /// Without it they are `base_url` followed by the paths gsa.apple.com uses.
+    pub fn set_url_bag_lookup(mut self, url_bag_lookup: bool) -> AccountConfiguration {
+        self.url_bag_lookup = url_bag_lookup;
+        self
+    }
+
+    pub fn transport(&self) -> Option<&Arc<dyn HttpTransport>> {
+        self.transport.as_ref()
+    }
+
+    /// Sends every GSA request through `transport` instead of the default client pinned to Apple's root certificate
+    pub fn set_transport(mut self, transport: Arc<dyn HttpTransport>) -> AccountConfiguration {
+        self.transport = Some(transport);
+        self
+    }
+
+    pub fn http_configuration(&self) -> &HttpConfiguration {
+        &self.http
+    }
+
+    /// Changes the timeouts of the default client, and how requests that are safe to send again
+    /// (listing the trusted phone numbers, pushing a code to the trusted devices) are retried
+    pub fn set_http_configuration(mut self, http: HttpConfiguration) -> AccountConfiguration {
+        self.http = http;
+        self
+    }
+
+    pub fn renewal_margin(&self) -> Duration {
+        self.renewal_margin
+    }
+
+    /// How long before the PET expires the session is renewed, one minute by default
+    pub fn set_renewal_margin(mut self, renewal_margin: Duration) -> AccountConfiguration {
+        self.renewal_margin = renewal_margin;
+        self
+    }
+
+    pub fn pet_renewal(&self) -> bool {
+        self.pet_renewal
+    }
+
+    /// Whether [`AppleAccount::renew`] sends the PET in a `complete` request, off by default.
+    ///
+    /// That request is built like the `apptokens` one and has only been checked against the GSA
+    /// emulator, not gsa.apple.com. Without it renewing asks for a new login.
+    pub fn set_pet_renewal(mut self, pet_renewal: bool) -> AccountConfiguration {
+        self.pet_renewal = pet_renewal;
+        self
+    }
+
+    pub fn reauth_callback(&self) -> Option<&ReauthCallback> {
+        self.reauth_callback.as_ref()
+    }
+
+    /// Notifies the app when the PET can't renew the session anymore and the user has to log in again
+    pub fn set_reauth_callback(mut self, callback: ReauthCallback) -> AccountConfiguration {
+        self.reauth_callback = Some(callback);
+        self
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Failed to parse the response")]
     Parse,
+    #[error("Missing `{0}` in the response")]
+    MissingResponseField(&'static str),
     #[error("Failed to authenticate.")]
     AuthSrp,
-    #[error("Bad 2fa code.")]
-    Bad2faCode,
+    #[error("SRP verification failed {0}")]
+    Srp(srp::types::SrpAuthError),
+    #[error("The server picked an unsupported password protocol `{0}`")]
+    UnsupportedPasswordProtocol(String),
+    #[error("Failed to decrypt the response")]
+    DecryptionFailed,
+    #[error("Bad 2fa code: {1} ({0})")]
+    Bad2faCode(i64, String),
+    #[error("Incorrect Apple ID or password: {1} ({0})")]
+    IncorrectCredentials(i64, String),
+    #[error("This Apple ID is locked: {1} ({0})")]
+    AccountLocked(i64, String),
+    #[error("The anisette data was rejected: {1} ({0})")]
+    AnisetteRejected(i64, String),
+    #[error("Too many requests, try again later: {1} ({0})")]
+    RateLimited(i64, String),
+    #[error("Two-factor authentication is required: {1} ({0})")]
+    SecondFactorRequired(i64, String),
+    /// A GSA error code without a dedicated variant
     #[error("{1} ({0})")]
     AuthSrpWithMessage(i64, String),
+    #[error("`{0}` can't be used in the current login state")]
+    InvalidLoginStep(&'static str),
+    #[error("The account has no trusted phone numbers")]
+    NoTrustedPhoneNumbers,
+    #[error("The account is not logged in")]
+    NotLoggedIn,
+    #[error("The session expired, the user has to log in again")]
+    ReauthRequired,
+    #[error("No account with adsid `{0}`")]
+    UnknownAccount(String),
+    #[error("`{0}` is not a valid adsid")]
+    InvalidAdsid(String),
+    #[error("The server provided data is missing `{0}`")]
+    MissingSpdField(&'static str),
+    #[error("The server provided data has no `{0}` token")]
+    MissingSpdToken(String),
+    #[error("Failed to encrypt or decrypt the session {0}")]
+    SessionCipher(Box<dyn std::error::Error + Send + Sync>),
     #[error("Please login to appleid.apple.com to fix this account")]
     ExtraStep(String),
     #[error("Failed to parse a plist {0}")]
     PlistError(#[from] plist::Error),
+    #[error("Invalid header name {0}")]
+    InvalidHeaderName(#[from] reqwest::header::InvalidHeaderName),
+    #[error("Invalid header value {0}")]
+    InvalidHeaderValue(#[from] reqwest::header::InvalidHeaderValue),
     #[error("Request failed {0}")]
     ReqwestError(#[from] reqwest::Error),
+    #[error("Transport error {0}")]
+    TransportError(#[from] omnisette::http::TransportError),
+    #[error("Failed to parse JSON {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("Failed to start the runtime {0}")]
+    RuntimeError(std::io::Error),
+    #[error("IO error {0}")]
+    IoError(std::io::Error),
     #[error("Failed getting anisette data {0}")]
     ErrorGettingAnisette(#[from] omnisette::AnisetteError)
-}
\ No newline at end of file
+}
+
+impl Error {
+    /// Maps a GSA `ec`/`em` pair to the matching error variant
+    pub fn from_gsa_status(code: i64, message: String) -> Error {
+        match code {
+            -22406 | -20101 => Error::IncorrectCredentials(code, message),
+            -20209 | -20751 => Error::AccountLocked(code, message),
+            -45061 | -22421 => Error::AnisetteRejected(code, message),
+            -21669 => Error::Bad2faCode(code, message),
+            -36607 | -20311 => Error::RateLimited(code, message),
+            -22938 => Error::SecondFactorRequired(code, message),
+            _ => Error::AuthSrpWithMessage(code, message),
+        }
+    }
+
+    /// The raw GSA error code, if this error came from the server
+    pub fn gsa_code(&self) -> Option<i64> {
+        match self {
+            Error::IncorrectCredentials(code, _)
+            | Error::Bad2faCode(code, _)
+            | Error::AccountLocked(code, _)
+            | Error::AnisetteRejected(code, _)
+            | Error::RateLimited(code, _)
+            | Error::SecondFactorRequired(code, _)
+            | Error::AuthSrpWithMessage(code, _) => Some(*code),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use omnisette::AnisetteConfiguration;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::{
+    anisette::AnisetteData, AccountConfiguration, AccountSession, AppleAccount, Error,
+    SessionCipher,
+};
+
+const SESSION_FILE: &str = "session.plist";
+const ANISETTE_DIR: &str = "anisette";
+const PENDING_PREFIX: &str = "pending-";
+/// How long the provisioning directory of a login that never reached [`AccountManager::insert`] is kept
+const PENDING_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Several logged in accounts, keyed by adsid.
+///
+/// Every account lives in its own directory under the root, holding its anisette provisioning
+/// data and its persisted session:
+///
+/// ```text
+/// <root>/<adsid>/anisette/
+/// <root>/<adsid>/session.plist
+/// ```
+///
+/// Accounts are handed out behind a mutex, so concurrent requests on the same account are
+/// serialized while different accounts can be used in parallel.
+pub struct AccountManager {
+    root: PathBuf,
+    anisette_config: AnisetteConfiguration,
+    account_config: AccountConfiguration,
+    cipher: Option<Arc<dyn SessionCipher + Send + Sync>>,
+    accounts: HashMap<String, Arc<Mutex<AppleAccount>>>,
+    current: Option<String>,
+}
+
+impl AccountManager {
+    /// `anisette_config` is used for every account, with its configuration path moved under `root`
+    pub fn new(root: PathBuf, anisette_config: AnisetteConfiguration) -> AccountManager {
+        AccountManager {
+            root,
+            anisette_config,
+            account_config: AccountConfiguration::new(),
+            cipher: None,
+            accounts: HashMap::new(),
+            current: None,
+        }
+    }
+
+    pub fn set_account_configuration(mut self, account_config: AccountConfiguration) -> AccountManager {
+        self.account_config = account_config;
+        self
+    }
+
+    /// Seals the persisted sessions with `cipher`
+    pub fn set_session_cipher(
+        mut self,
+        cipher: Arc<dyn SessionCipher + Send + Sync>,
+    ) -> AccountManager {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The anisette configuration of the account stored under `key`
+    pub fn anisette_configuration(&self, key: &str) -> AnisetteConfiguration {
+        self.anisette_config
+            .clone()
+            .set_configuration_path(self.root.join(key).join(ANISETTE_DIR))
+    }
+
+    /// Resumes every account persisted under the root, and deletes the provisioning directories
+    /// of logins abandoned more than an hour ago.
+    ///
+    /// The anisette data is only fetched on the first request of each account.
+    pub fn load(&mut self) -> Result<(), Error> {
+        if !self.root.exists() {
+            return Ok(());
+        }
+
+        for entry in fs::read_dir(&self.root).map_err(Error::IoError)? {
+            let path = entry.map_err(Error::IoError)?.path();
+            let session_path = path.join(SESSION_FILE);
+            let key = match path.file_name().and_then(|name| name.to_str()) {
+                Some(key) if key.starts_with(PENDING_PREFIX) => {
+                    Self::prune_pending(&path);
+                    continue;
+                }
+                Some(key) if session_path.exists() => key,
+                _ => continue,
+            };
+
+            let data = fs::read(&session_path).map_err(Error::IoError)?;
+            let session = match &self.cipher {
+                Some(cipher) => AccountSession::from_encrypted_bytes(&data, cipher.as_ref())?,
+                None => AccountSession::from_bytes(&data)?,
+            };
+            let anisette = AnisetteData::expired(self.anisette_configuration(key));
+            let account = AppleAccount::from_session_with_configuration(
+                session,
+                anisette,
+                self.account_config.clone(),
+            )?;
+
+            debug!(adsid = key, "loaded account");
+            self.accounts
+                .insert(key.to_string(), Arc::new(Mutex::new(account)));
+        }
+
+        if self.current.is_none() {
+            self.current = self.list().into_iter().next();
+        }
+        Ok(())
+    }
+
+    /// A new account with a provisioning directory of its own, to log in with [`crate::LoginFlow`]
+    /// and hand back with [`AccountManager::insert`]
+    pub async fn new_account(&self) -> Result<AppleAccount, Error> {
+        let key = format!(
+            "{}{}",
+            PENDING_PREFIX,
+            (0..8)
+                .map(|_| format!("{:02x}", rand::random::<u8>()))
+                .collect::<String>()
+        );
+        let config = self.anisette_configuration(&key);
+        fs::create_dir_all(config.configuration_path()).map_err(Error::IoError)?;
+
+        let anisette = AnisetteData::new(config).await?;
+        AppleAccount::new_with_configuration(anisette, self.account_config.clone())
+    }
+
+    /// Stores a logged in account and persists its session, replacing any account with the same adsid.
+    ///
+    /// Its anisette provisioning directory is moved under the root if it lives elsewhere.
+    pub fn insert(&mut self, mut account: AppleAccount) -> Result<String, Error> {
+        let adsid = account.spd()?.adsid()?.to_string();
+        let dir = self.account_dir(&adsid)?;
+
+        let anisette = account.anisette.get_mut();
+        let target = dir.join(ANISETTE_DIR);
+        let source = anisette.config().configuration_path().clone();
+        if source != target {
+            if source.is_dir() {
+                if target.exists() {
+                    fs::remove_dir_all(&target).map_err(Error::IoError)?;
+                }
+                fs::create_dir_all(&dir).map_err(Error::IoError)?;
+                fs::rename(&source, &target).map_err(Error::IoError)?;
+                Self::remove_pending(&source);
+            }
+            let config = anisette.config().clone().set_configuration_path(target);
+            anisette.set_configuration(config);
+        }
+
+        self.save_account(&adsid, &account)?;
+        debug!(adsid = adsid.as_str(), "added account");
+        self.accounts
+            .insert(adsid.clone(), Arc::new(Mutex::new(account)));
+        if self.current.is_none() {
+            self.current = Some(adsid.clone());
+        }
+        Ok(adsid)
+    }
+
+    /// Persists the session of an account again, e.g. after it was renewed
+    pub async fn save(&self, adsid: &str) -> Result<(), Error> {
+        let account = self.get(adsid)?;
+        let account = account.lock().await;
+        self.save_account(adsid, &account)
+    }
+
+    /// Forgets an account and deletes its directory
+    pub fn remove(&mut self, adsid: &str) -> Result<(), Error> {
+        let dir = self.account_dir(adsid)?;
+        self.accounts
+            .remove(adsid)
+            .ok_or_else(|| Error::UnknownAccount(adsid.to_string()))?;
+
+        if dir.exists() {
+            fs::remove_dir_all(dir).map_err(Error::IoError)?;
+        }
+        if self.current.as_deref() == Some(adsid) {
+            self.current = self.list().into_iter().next();
+        }
+        Ok(())
+    }
+
+    /// The adsids of every account, sorted
+    pub fn list(&self) -> Vec<String> {
+        let mut adsids = self.accounts.keys().cloned().collect::<Vec<_>>();
+        adsids.sort();
+        adsids
+    }
+
+    pub fn get(&self, adsid: &str) -> Result<Arc<Mutex<AppleAccount>>, Error> {
+        self.accounts
+            .get(adsid)
+            .cloned()
+            .ok_or_else(|| Error::UnknownAccount(adsid.to_string()))
+    }
+
+    /// Makes `adsid` the account returned by [`AccountManager::current`]
+    pub fn switch(&mut self, adsid: &str) -> Result<(), Error> {
+        if !self.accounts.contains_key(adsid) {
+            return Err(Error::UnknownAccount(adsid.to_string()));
+        }
+        self.current = Some(adsid.to_string());
+        Ok(())
+    }
+
+    pub fn current_adsid(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    pub fn current(&self) -> Option<Arc<Mutex<AppleAccount>>> {
+        self.current
+            .as_ref()
+            .and_then(|adsid| self.accounts.get(adsid))
+            .cloned()
+    }
+
+    fn save_account(&self, adsid: &str, account: &AppleAccount) -> Result<(), Error> {
+        let session = account.export_session()?;
+        let data = match &self.cipher {
+            Some(cipher) => session.to_encrypted_bytes(cipher.as_ref())?,
+            None => session.to_bytes()?,
+        };
+
+        let dir = self.account_dir(adsid)?;
+        fs::create_dir_all(&dir).map_err(Error::IoError)?;
+        fs::write(dir.join(SESSION_FILE), data).map_err(Error::IoError)?;
+        Ok(())
+    }
+
+    /// The directory of `adsid`, which comes from the server and is only used as a single plain directory name
+    fn account_dir(&self, adsid: &str) -> Result<PathBuf, Error> {
+        let mut components = Path::new(adsid).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None)
+                if name == adsid && !adsid.starts_with(PENDING_PREFIX) =>
+            {
+                Ok(self.root.join(adsid))
+            }
+            _ => Err(Error::InvalidAdsid(adsid.to_string())),
+        }
+    }
+
+    /// Deletes the `pending-` directory of a login that was abandoned long enough ago
+    fn prune_pending(dir: &Path) {
+        let stale = fs::metadata(dir)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age > PENDING_MAX_AGE);
+        if stale {
+            debug!(path = %dir.display(), "removing abandoned provisioning directory");
+            fs::remove_dir_all(dir).ok();
+        }
+    }
+
+    /// Deletes the `pending-` directory a provisioning directory was created in by [`AccountManager::new_account`]
+    fn remove_pending(anisette_dir: &Path) {
+        if let Some(parent) = anisette_dir.parent() {
+            let pending = parent
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(PENDING_PREFIX));
+            if pending {
+                fs::remove_dir_all(parent).ok();
+            }
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use srp::apple::PasswordDerivation;
+
+use crate::Error;
+
+/// How the password is turned into the SRP secret, picked by the server in the `sp` field of the `init` response
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PasswordProtocol {
+    /// `pbkdf2(sha256(password))`
+    S2k,
+    /// `pbkdf2(hex(sha256(password)))`
+    S2kFo,
+}
+
+impl PasswordProtocol {
+    /// The protocols sent in `ps`, in order of preference
+    pub const SUPPORTED: [PasswordProtocol; 2] = [PasswordProtocol::S2k, PasswordProtocol::S2kFo];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PasswordProtocol::S2k => "s2k",
+            PasswordProtocol::S2kFo => "s2k_fo",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<PasswordProtocol, Error> {
+        match name {
+            "s2k" => Ok(PasswordProtocol::S2k),
+            "s2k_fo" => Ok(PasswordProtocol::S2kFo),
+            _ => Err(Error::UnsupportedPasswordProtocol(name.to_string())),
+        }
+    }
+
+    pub fn derivation(&self) -> PasswordDerivation {
+        match self {
+            PasswordProtocol::S2k => PasswordDerivation::S2k,
+            PasswordProtocol::S2kFo => PasswordDerivation::S2kFo,
+        }
+    }
+
+    pub fn derive_password(&self, password: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+        self.derivation().derive(password.as_bytes(), salt, iterations)
+    }
+}
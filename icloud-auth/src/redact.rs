@@ -0,0 +1,69 @@
+//! Keeps secrets out of the logs.
+//!
+//! Tokens, passwords, SRP values and anisette OTPs are only ever logged wrapped in [`Redacted`],
+//! which prints `<redacted>` unless the `unsafe-debug` feature is enabled.
+
+use std::fmt;
+
+use reqwest::header::HeaderMap;
+
+/// Formats as `<redacted>`, or as the wrapped value with the `unsafe-debug` feature
+pub struct Redacted<T>(pub T);
+
+impl<T: fmt::Debug> fmt::Debug for Redacted<T> {
+    #[cfg(feature = "unsafe-debug")]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+
+    #[cfg(not(feature = "unsafe-debug"))]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Redacted<T> {
+    #[cfg(feature = "unsafe-debug")]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+
+    #[cfg(not(feature = "unsafe-debug"))]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Headers that never carry anything secret
+const PUBLIC_HEADERS: &[&str] = &[
+    "accept",
+    "accept-language",
+    "content-length",
+    "content-type",
+    "date",
+    "loc",
+    "user-agent",
+    "x-apple-app-info",
+    "x-apple-i-client-time",
+    "x-apple-i-timezone",
+    "x-apple-locale",
+    "x-mme-client-info",
+    "x-xcode-version",
+];
+
+/// Formats a header map with the value of every non-public header redacted
+pub(crate) struct RedactedHeaders<'a>(pub &'a HeaderMap);
+
+impl fmt::Debug for RedactedHeaders<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut map = f.debug_map();
+        for (name, value) in self.0 {
+            if PUBLIC_HEADERS.contains(&name.as_str()) {
+                map.entry(&name.as_str(), value);
+            } else {
+                map.entry(&name.as_str(), &Redacted(value));
+            }
+        }
+        map.finish()
+    }
+}
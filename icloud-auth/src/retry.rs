@@ -0,0 +1,46 @@
+use omnisette::http::{self, HttpResponse, Retry, TransportError};
+
+use crate::Error;
+
+/// Retries what [`http::classify_response`] does, and a GSA `ec` asking to slow down
+pub(crate) fn classify_response(res: &Result<HttpResponse, TransportError>) -> Retry {
+    match res {
+        Ok(response) if is_rate_limited(&response.body) => {
+            Retry::After(http::retry_after(&response.headers))
+        }
+        _ => http::classify_response(res),
+    }
+}
+
+fn is_rate_limited(body: &[u8]) -> bool {
+    let Ok(res) = plist::from_bytes::<plist::Dictionary>(body) else {
+        return false;
+    };
+    let res = match res.get("Response").or_else(|| res.get("Status")) {
+        Some(plist::Value::Dictionary(inner)) => match inner.get("Status") {
+            Some(plist::Value::Dictionary(status)) => status,
+            _ => inner,
+        },
+        _ => &res,
+    };
+    res.get("ec")
+        .and_then(plist::Value::as_signed_integer)
+        .is_some_and(|code| matches!(Error::from_gsa_status(code, String::new()), Error::RateLimited(..)))
+}
+
+/// Retries anisette failures that come from the network rather than the provider itself, which
+/// includes starting a failed provisioning session over
+pub(crate) fn classify_anisette<T>(res: &Result<T, Error>) -> Retry {
+    use omnisette::{adi_proxy::ADIError, AnisetteError};
+
+    match res {
+        Err(Error::ErrorGettingAnisette(
+            AnisetteError::TransportError(_)
+            | AnisetteError::ReqwestError(_)
+            | AnisetteError::WsError(_)
+            | AnisetteError::Timeout
+            | AnisetteError::ADIError(ADIError::TransportError(_) | ADIError::ReqwestError(_)),
+        )) => Retry::After(None),
+        _ => Retry::No,
+    }
+}
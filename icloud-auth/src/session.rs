@@ -0,0 +1,102 @@
+use crate::{Error, ServerProvidedData};
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Nonce,
+};
+use serde::{Deserialize, Serialize};
+
+const NONCE_LENGTH: usize = 12;
+
+/// Encryption-at-rest hook for persisted sessions.
+///
+/// Sessions contain the GsIdmsToken, the PET and the session key, so anything that
+/// writes them to disk should probably seal them with a key from the OS keyring.
+pub trait SessionCipher {
+    fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>;
+    fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// Seals with AES-256-GCM under a 32 bytes key, prepending a random nonce to every ciphertext
+pub struct AesGcmCipher {
+    cipher: Aes256Gcm,
+}
+
+impl AesGcmCipher {
+    pub fn new(key: &[u8; 32]) -> AesGcmCipher {
+        AesGcmCipher {
+            cipher: Aes256Gcm::new(key.into()),
+        }
+    }
+}
+
+impl SessionCipher for AesGcmCipher {
+    fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let nonce = rand::random::<[u8; NONCE_LENGTH]>();
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), data)
+            .map_err(|_| "encryption failed")?;
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        if data.len() < NONCE_LENGTH {
+            return Err("the data is too short".into());
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+        Ok(self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "the data was tampered with or sealed with another key")?)
+    }
+}
+
+/// Everything needed to resume an [`AppleAccount`](crate::AppleAccount) without going through SRP and 2FA again.
+///
+/// The session is stored as a plist, since the server provided data holds binary values
+/// that would not survive a round trip through formats like JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountSession {
+    pub spd: ServerProvidedData,
+    /// The Apple ID the session was created with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+}
+
+impl AccountSession {
+    pub fn new(spd: ServerProvidedData) -> AccountSession {
+        AccountSession {
+            spd,
+            username: None,
+        }
+    }
+
+    pub fn set_username(mut self, username: String) -> AccountSession {
+        self.username = Some(username);
+        self
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buffer = Vec::new();
+        plist::to_writer_binary(&mut buffer, self)?;
+        Ok(buffer)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<AccountSession, Error> {
+        Ok(plist::from_bytes(data)?)
+    }
+
+    pub fn to_encrypted_bytes(&self, cipher: &dyn SessionCipher) -> Result<Vec<u8>, Error> {
+        cipher
+            .encrypt(&self.to_bytes()?)
+            .map_err(Error::SessionCipher)
+    }
+
+    pub fn from_encrypted_bytes(
+        data: &[u8],
+        cipher: &dyn SessionCipher,
+    ) -> Result<AccountSession, Error> {
+        let data = cipher.decrypt(data).map_err(Error::SessionCipher)?;
+        AccountSession::from_bytes(&data)
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::Error;
+
+pub const PET_TOKEN: &str = "com.apple.gs.idms.pet";
+
+/// Apple isn't consistent about the types it uses for flags and ids, so accept any scalar.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Scalar {
+    Bool(bool),
+    Integer(i64),
+    String(String),
+}
+
+fn deserialize_flag<'de, D>(d: D) -> Result<Option<bool>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: Option<Scalar> = Deserialize::deserialize(d)?;
+    Ok(s.map(|s| match s {
+        Scalar::Bool(b) => b,
+        Scalar::Integer(i) => i != 0,
+        Scalar::String(s) => s == "true" || s == "1",
+    }))
+}
+
+fn deserialize_id<'de, D>(d: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: Option<Scalar> = Deserialize::deserialize(d)?;
+    Ok(s.map(|s| match s {
+        Scalar::Bool(b) => b.to_string(),
+        Scalar::Integer(i) => i.to_string(),
+        Scalar::String(s) => s,
+    }))
+}
+
+/// A token from the `t` dictionary of the server provided data
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpdToken {
+    pub token: String,
+    /// Lifetime of the token, in seconds
+    pub duration: Option<i64>,
+    /// Expiry of the token, in milliseconds since the unix epoch
+    pub expiry: Option<i64>,
+    /// Creation time of the token, in milliseconds since the unix epoch
+    pub cts: Option<i64>,
+}
+
+impl SpdToken {
+    /// When the token stops being accepted, if the server said so
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        let expiry = match (self.expiry, self.cts, self.duration) {
+            (Some(expiry), _, _) => expiry,
+            (None, Some(cts), Some(duration)) => cts + duration * 1000,
+            _ => return None,
+        };
+        Some(UNIX_EPOCH + Duration::from_millis(expiry.max(0) as u64))
+    }
+
+    /// Whether the token has expired or will within `margin`; tokens without an expiry never do
+    pub fn expires_within(&self, margin: Duration) -> bool {
+        self.expires_at()
+            .is_some_and(|expires_at| expires_at <= SystemTime::now() + margin)
+    }
+}
+
+/// The decrypted `spd` dictionary returned by GSA at the end of the SRP login.
+///
+/// Every key is optional, since Apple omits some of them depending on the state of the account.
+/// Use the accessors to get a descriptive [`Error`] when something required is missing.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServerProvidedData {
+    pub adsid: Option<String>,
+    #[serde(rename = "DsPrsId", default, deserialize_with = "deserialize_id")]
+    pub dsid: Option<String>,
+    #[serde(rename = "GsIdmsToken")]
+    pub gs_idms_token: Option<String>,
+    #[serde(rename = "fn")]
+    pub first_name: Option<String>,
+    #[serde(rename = "ln")]
+    pub last_name: Option<String>,
+    #[serde(rename = "acname")]
+    pub account_name: Option<String>,
+    /// Session key, used for checksums and to decrypt app tokens
+    pub sk: Option<plist::Data>,
+    pub c: Option<plist::Data>,
+    #[serde(default)]
+    pub t: HashMap<String, SpdToken>,
+    #[serde(rename = "StatusCode")]
+    pub status_code: Option<i64>,
+    #[serde(rename = "isManagedAppleID", default, deserialize_with = "deserialize_flag")]
+    pub is_managed_apple_id: Option<bool>,
+    #[serde(rename = "hasEmptyPassword", default, deserialize_with = "deserialize_flag")]
+    pub has_empty_password: Option<bool>,
+    #[serde(rename = "primaryEmailVerified", default, deserialize_with = "deserialize_flag")]
+    pub primary_email_verified: Option<bool>,
+}
+
+impl ServerProvidedData {
+    pub fn adsid(&self) -> Result<&str, Error> {
+        self.adsid.as_deref().ok_or(Error::MissingSpdField("adsid"))
+    }
+
+    pub fn dsid(&self) -> Result<&str, Error> {
+        self.dsid.as_deref().ok_or(Error::MissingSpdField("DsPrsId"))
+    }
+
+    pub fn gs_idms_token(&self) -> Result<&str, Error> {
+        self.gs_idms_token
+            .as_deref()
+            .ok_or(Error::MissingSpdField("GsIdmsToken"))
+    }
+
+    pub fn session_key(&self) -> Result<&[u8], Error> {
+        self.sk
+            .as_ref()
+            .map(|sk| sk.as_ref())
+            .ok_or(Error::MissingSpdField("sk"))
+    }
+
+    pub fn c(&self) -> Result<&[u8], Error> {
+        self.c
+            .as_ref()
+            .map(|c| c.as_ref())
+            .ok_or(Error::MissingSpdField("c"))
+    }
+
+    /// Returns the first and last name of the account holder
+    pub fn name(&self) -> Result<(&str, &str), Error> {
+        Ok((
+            self.first_name.as_deref().ok_or(Error::MissingSpdField("fn"))?,
+            self.last_name.as_deref().ok_or(Error::MissingSpdField("ln"))?,
+        ))
+    }
+
+    pub fn token(&self, name: &str) -> Result<&SpdToken, Error> {
+        self.t
+            .get(name)
+            .ok_or_else(|| Error::MissingSpdToken(name.to_string()))
+    }
+
+    /// The password equivalent token, which allows skipping 2FA for a while
+    pub fn pet(&self) -> Result<&str, Error> {
+        Ok(&self.token(PET_TOKEN)?.token)
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+/// Where the URL bag is fetched from, relative to the base url
+pub const URL_BAG_PATH: &str = "/grandslam/GsService2/lookup";
+
+/// A GSA endpoint the client sends requests to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    /// SRP login and app tokens
+    GsService,
+    /// Checks a code sent to the trusted devices
+    ValidateCode,
+    /// Pushes a code to the trusted devices
+    TrustedDeviceSecondaryAuth,
+    /// Lists the trusted phone numbers
+    SecondaryAuth,
+    /// Sends a code to a trusted phone number
+    VerifyPhone,
+    /// Checks a code sent to a trusted phone number
+    VerifyPhoneCode,
+}
+
+impl Endpoint {
+    pub const ALL: [Endpoint; 6] = [
+        Endpoint::GsService,
+        Endpoint::ValidateCode,
+        Endpoint::TrustedDeviceSecondaryAuth,
+        Endpoint::SecondaryAuth,
+        Endpoint::VerifyPhone,
+        Endpoint::VerifyPhoneCode,
+    ];
+
+    /// Key of the URL bag entry the endpoint is looked up with
+    pub fn key(&self) -> &'static str {
+        match self {
+            Endpoint::GsService => "gsService",
+            Endpoint::ValidateCode => "validateCode",
+            Endpoint::TrustedDeviceSecondaryAuth => "trustedDeviceSecondaryAuth",
+            // the bag has no entry for the phone endpoints, they live under `secondaryAuth`
+            Endpoint::SecondaryAuth | Endpoint::VerifyPhone | Endpoint::VerifyPhoneCode => {
+                "secondaryAuth"
+            }
+        }
+    }
+
+    /// What is appended to the URL bag entry
+    fn suffix(&self) -> &'static str {
+        match self {
+            Endpoint::VerifyPhone => "/verify/phone/",
+            Endpoint::VerifyPhoneCode => "/verify/phone/securitycode",
+            _ => "",
+        }
+    }
+
+    /// Path of the endpoint on gsa.apple.com
+    pub fn default_path(&self) -> &'static str {
+        match self {
+            Endpoint::GsService => "/grandslam/GsService2",
+            Endpoint::ValidateCode => "/grandslam/GsService2/validate",
+            Endpoint::TrustedDeviceSecondaryAuth => "/auth/verify/trusteddevice",
+            Endpoint::SecondaryAuth => "/auth",
+            Endpoint::VerifyPhone => "/auth/verify/phone/",
+            Endpoint::VerifyPhoneCode => "/auth/verify/phone/securitycode",
+        }
+    }
+}
+
+/// The URLs GSA advertises for its services, as returned by `GsService2/lookup`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UrlBag {
+    urls: HashMap<String, String>,
+}
+
+impl UrlBag {
+    pub fn new(urls: HashMap<String, String>) -> UrlBag {
+        UrlBag { urls }
+    }
+
+    /// Parses a lookup response, skipping the entries that aren't URLs
+    pub fn from_plist(res: &plist::Dictionary) -> Result<UrlBag, Error> {
+        let urls = res
+            .get("urls")
+            .and_then(plist::Value::as_dictionary)
+            .ok_or(Error::MissingResponseField("urls"))?;
+
+        Ok(UrlBag::new(
+            urls.iter()
+                .filter_map(|(key, url)| Some((key.clone(), url.as_string()?.to_string())))
+                .collect(),
+        ))
+    }
+
+    pub fn urls(&self) -> &HashMap<String, String> {
+        &self.urls
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.urls.get(key).map(String::as_str)
+    }
+
+    /// URL of `endpoint`, if the bag has it
+    pub fn endpoint(&self, endpoint: Endpoint) -> Option<String> {
+        self.get(endpoint.key())
+            .map(|url| format!("{}{}", url.trim_end_matches('/'), endpoint.suffix()))
+    }
+}
@@ -0,0 +1,65 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use icloud_auth::{anisette::AnisetteData, *};
+    use omnisette::{
+        http::{HeaderMap, HttpRequest, HttpResponse, HttpTransport, StatusCode, TransportError},
+        provider_chain::{ProviderKind, RejectionReason},
+    };
+
+    /// A v1 anisette server at `http://v1.invalid`, while the v3 one is down
+    #[derive(Default)]
+    struct FakeServers {
+        v3_requests: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl HttpTransport for FakeServers {
+        async fn send(&self, request: HttpRequest) -> Result<HttpResponse, TransportError> {
+            if !request.url.starts_with("http://v1.invalid") {
+                self.v3_requests.fetch_add(1, Ordering::SeqCst);
+                return Err(TransportError::Other("connection refused".into()));
+            }
+            Ok(HttpResponse {
+                status: StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: serde_json::to_vec(&gsa_emulator::fake_anisette_headers()).unwrap(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn refreshes_reuse_the_provider_chain() {
+        let servers = Arc::new(FakeServers::default());
+        let config = AnisetteConfiguration::new()
+            .set_configuration_path(std::env::temp_dir().join("icloud-auth-anisette-unused"))
+            .set_anisette_url("http://v1.invalid".to_string())
+            .set_anisette_url_v3("http://v3.invalid".to_string())
+            .set_transport(servers.clone())
+            .set_provider_chain(vec![ProviderKind::RemoteV3, ProviderKind::Remote]);
+
+        let anisette = AnisetteData::expired(config);
+        assert!(anisette.needs_refresh());
+        assert!(anisette.provider_status().is_none());
+
+        let anisette = anisette.refresh().await.unwrap();
+        let status = anisette.provider_status().unwrap();
+        assert_eq!(status.active(), Some(ProviderKind::Remote));
+        assert_eq!(status.rejections()[0].reason, RejectionReason::Network);
+        let v3_requests = servers.v3_requests.load(Ordering::SeqCst);
+
+        // the v3 server is cooling down in the same chain, rather than tried again by a new one
+        let anisette = anisette.refresh().await.unwrap();
+        assert_eq!(servers.v3_requests.load(Ordering::SeqCst), v3_requests);
+        assert_eq!(anisette.provider_status().unwrap().rejections().len(), 1);
+
+        // until the caller resets it
+        anisette.provider_status().unwrap().reset();
+        anisette.refresh().await.unwrap();
+        assert!(servers.v3_requests.load(Ordering::SeqCst) > v3_requests);
+    }
+}
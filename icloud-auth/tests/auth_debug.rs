@@ -3,11 +3,7 @@ use std::sync::Arc;
 
 use num_bigint::BigUint;
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
-use srp::{
-    client::{SrpClient, SrpClientVerifier},
-    groups::G_2048,
-};
+use srp::apple::{AppleSrp, PasswordDerivation};
 
 #[cfg(test)]
 mod tests {
@@ -22,30 +18,17 @@ mod tests {
         let salt = base64::decode("6fK6ailLUcp2kJswJVrKjQ==").unwrap();
         let iters = 20832;
 
-        let mut password_hasher = sha2::Sha256::new();
-        password_hasher.update(&password.as_bytes());
-        let hashed_password = password_hasher.finalize();
-        // println!("Hashed password: {:?}", base64::encode(&hashed_password));
-
-        let mut password_buf = [0u8; 32];
-        pbkdf2::pbkdf2::<hmac::Hmac<Sha256>>(
-            &hashed_password,
-            &salt,
-            iters as u32,
-            &mut password_buf,
-        );
+        let password_buf = PasswordDerivation::S2k.derive(password.as_bytes(), &salt, iters as u32);
         // println!("PBKDF2 Encrypted password: {:?}",base64::encode(&password_buf));
 
-        let identity_hash = SrpClient::<Sha256>::compute_identity_hash(&[], &password_buf);
-        let x = SrpClient::<Sha256>::compute_x(identity_hash.as_slice(), &salt);
+        let x = AppleSrp::compute_x(&password_buf, &salt);
 
         // apub: N2XHuh/4P1urPoBvDocF0RCRIl2pliZYqg9p6wGH0nnJdckJPn3M00jEqoM4teqH03HjG1murdcZiNHb5YayufW//+asW01XB7nYIIVvGiUFLRypYITEKYWBQ6h2q02GaZspYJKy98V8Fwcvr0ri+al7zJo1X1aoRKINyjV5TywhhwmTleI1qJkf+JBRYKKqO1XFtOTpQsysWD3ZJdK3K78kSgT3q0kXE3oDRMiHPAO77GFJZErYTuvI6QPRbOgcrn+RKV6AsjR5tUQAoSGRdtibdZTAQijJg788qVg+OFVCNZoY9GYVxa+Ze1bPGdkkgCYicTE8iNFG9KlJ+QpKgQ==
 
         let a_random = base64::decode("ywN1O32vmBogb5Fyt9M7Tn8bbzLtDDbcYgPFpSy8n9E=").unwrap();
-        let client = SrpClient::<Sha256>::new(&G_2048);
+        let client = AppleSrp::new();
 
-        let a_pub_compute =
-            SrpClient::<Sha256>::compute_a_pub(&client, &BigUint::from_bytes_be(&a_random));
+        let a_pub_compute = BigUint::from_bytes_be(&client.compute_public_ephemeral(&a_random));
         // expect it to be same to a_pub
         println!(
             "compute a_pub: {:?}",
@@ -58,7 +41,7 @@ mod tests {
 
         println!("salt: {:?} iterations: {:?}", base64::encode(&salt), iters);
 
-        let verifier: SrpClientVerifier<Sha256> = SrpClient::<Sha256>::process_reply(
+        let verifier = AppleSrp::process_reply(
             &client,
             &a_random,
             // &a_pub,
@@ -0,0 +1,57 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use gsa_emulator::{CodeDelivery, EmulatedAccount, GsaEmulator, SecondFactor};
+    use icloud_auth::{blocking::AppleAccount, *};
+
+    use crate::common;
+
+    fn account(emulator: &GsaEmulator) -> AppleAccount {
+        let config = AccountConfiguration::new().set_base_url(emulator.base_url());
+        AppleAccount::new_with_configuration(common::anisette(AnisetteConfiguration::new()), config).unwrap()
+    }
+
+    #[test]
+    fn blocking_login_with_sms() {
+        // the emulator needs a runtime of its own that keeps running while the account blocks
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let emulated = EmulatedAccount {
+            second_factor: SecondFactor::Sms,
+            ..Default::default()
+        };
+        let emulator = runtime.block_on(GsaEmulator::start(emulated.clone())).unwrap();
+        let mut account = account(&emulator);
+
+        let state = account
+            .login_email_pass(&emulated.username, &emulated.password)
+            .unwrap();
+        assert!(matches!(state, LoginState::NeedsSMS2FA));
+
+        let number = account.trusted_phone_numbers().unwrap().remove(0);
+        let state = account.send_phone_code(number.id, DeliveryMode::Sms).unwrap();
+        assert_eq!(
+            emulator.code_deliveries(),
+            vec![CodeDelivery::Phone {
+                id: number.id,
+                mode: "sms".to_string()
+            }]
+        );
+        let body = match state {
+            LoginState::NeedsSMS2FAVerification(body) => body,
+            state => panic!("unexpected state {state:?}"),
+        };
+        let state = account
+            .verify_sms_2fa(emulated.security_code.clone(), body)
+            .unwrap();
+        assert!(matches!(state, LoginState::NeedsLogin));
+
+        let state = account
+            .login_email_pass(&emulated.username, &emulated.password)
+            .unwrap();
+        assert!(matches!(state, LoginState::LoggedIn));
+
+        let token = account.app_token("com.apple.gs.xcode.auth").unwrap();
+        assert!(!token.auth_token.is_empty());
+    }
+}
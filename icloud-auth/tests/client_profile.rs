@@ -0,0 +1,45 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use icloud_auth::{anisette::AnisetteData, *};
+
+    use crate::common;
+
+    fn anisette(profile: ClientProfile) -> AnisetteData {
+        common::anisette(AnisetteConfiguration::new().set_client_profile(profile))
+    }
+
+    #[test]
+    fn xcode_is_the_default() {
+        let headers = anisette(ClientProfile::default()).generate_headers(false, true, true);
+
+        assert_eq!(
+            headers["X-Mme-Client-Info"],
+            "<MacBookPro13,2> <macOS;13.1;22C65> <com.apple.AuthKit/1 (com.apple.dt.Xcode/3594.4.19)>"
+        );
+        assert_eq!(headers["X-Apple-App-Info"], "com.apple.gs.xcode.auth");
+        assert_eq!(headers["X-Xcode-Version"], "11.2 (11B41)");
+    }
+
+    #[test]
+    fn preset_replaces_client_identity() {
+        let headers = anisette(ClientProfile::ios_settings()).generate_headers(false, true, true);
+
+        assert!(headers["X-Mme-Client-Info"]
+            .ends_with("<com.apple.AuthKit/1 (com.apple.Preferences/1112.96)>"));
+        assert_eq!(headers["X-Apple-App-Info"], "com.apple.Preferences");
+        assert!(!headers.contains_key("X-Xcode-Version"));
+    }
+
+    #[test]
+    fn custom_profile() {
+        let profile = ClientProfile {
+            xcode_version: Some("15.2 (15C500b)".to_string()),
+            ..ClientProfile::xcode_macos()
+        };
+        let headers = anisette(profile).generate_headers(false, true, true);
+
+        assert_eq!(headers["X-Xcode-Version"], "15.2 (15C500b)");
+    }
+}
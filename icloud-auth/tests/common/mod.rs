@@ -0,0 +1,22 @@
+//! Fixtures shared by the integration tests
+// each test binary uses only some of them
+#![allow(dead_code)]
+
+use gsa_emulator::GsaEmulator;
+use icloud_auth::{
+    anisette::AnisetteData, AccountConfiguration, AnisetteConfiguration, AppleAccount,
+};
+
+/// Anisette data the emulator accepts, refreshed through `config` if it ever has to be
+pub fn anisette(config: AnisetteConfiguration) -> AnisetteData {
+    AnisetteData::from_headers(gsa_emulator::fake_anisette_headers(), config)
+}
+
+/// An account talking to `emulator`, with the rest of `config`
+pub fn account(emulator: &GsaEmulator, config: AccountConfiguration) -> AppleAccount {
+    AppleAccount::new_with_configuration(
+        anisette(AnisetteConfiguration::new()),
+        config.set_base_url(emulator.base_url()),
+    )
+    .unwrap()
+}
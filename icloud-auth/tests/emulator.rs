@@ -0,0 +1,208 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use gsa_emulator::{CodeDelivery, EmulatedAccount, EmulatedPhoneNumber, GsaEmulator, SecondFactor};
+    use icloud_auth::*;
+
+    use crate::common;
+
+    async fn start(second_factor: SecondFactor) -> (GsaEmulator, EmulatedAccount) {
+        let emulated = EmulatedAccount {
+            second_factor,
+            ..Default::default()
+        };
+        (GsaEmulator::start(emulated.clone()).await.unwrap(), emulated)
+    }
+
+    #[tokio::test]
+    async fn login_without_second_factor() {
+        let (emulator, emulated) = start(SecondFactor::None).await;
+        let mut account = common::account(&emulator, AccountConfiguration::new());
+
+        let state = account
+            .login_email_pass(&emulated.username, &emulated.password)
+            .await
+            .unwrap();
+        assert!(matches!(state, LoginState::LoggedIn));
+
+        let (first_name, last_name) = account.get_name().unwrap();
+        assert_eq!(first_name, emulated.first_name);
+        assert_eq!(last_name, emulated.last_name);
+        assert!(!account.get_pet().unwrap().is_empty());
+
+        let token = account
+            .get_app_token("com.apple.gs.xcode.auth")
+            .await
+            .unwrap();
+        assert_eq!(token.app, "com.apple.gs.xcode.auth");
+        assert!(!token.auth_token.is_empty());
+        assert!(token.expiry > 0);
+    }
+
+    #[tokio::test]
+    async fn login_with_trusted_device() {
+        let (emulator, emulated) = start(SecondFactor::TrustedDevice).await;
+        let mut account = common::account(&emulator, AccountConfiguration::new());
+
+        let state = account
+            .login_email_pass(&emulated.username, &emulated.password)
+            .await
+            .unwrap();
+        assert!(matches!(state, LoginState::NeedsDevice2FA));
+
+        let state = account.send_2fa_to_devices().await.unwrap();
+        assert!(matches!(state, LoginState::Needs2FAVerification));
+        assert_eq!(emulator.code_deliveries(), vec![CodeDelivery::TrustedDevice]);
+
+        assert!(matches!(
+            account.verify_2fa("000000".to_string()).await,
+            Err(Error::Bad2faCode(-21669, _))
+        ));
+        let state = account.verify_2fa(emulated.security_code.clone()).await.unwrap();
+        assert!(matches!(state, LoginState::NeedsLogin));
+
+        let state = account
+            .login_email_pass(&emulated.username, &emulated.password)
+            .await
+            .unwrap();
+        assert!(matches!(state, LoginState::LoggedIn));
+        account.get_app_token("com.apple.gs.xcode.auth").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn login_with_sms() {
+        let (emulator, emulated) = start(SecondFactor::Sms).await;
+        let mut account = common::account(&emulator, AccountConfiguration::new());
+
+        let state = account
+            .login_email_pass(&emulated.username, &emulated.password)
+            .await
+            .unwrap();
+        assert!(matches!(state, LoginState::NeedsSMS2FA));
+
+        let extras = account.get_auth_extras().await.unwrap();
+        assert_eq!(extras.trusted_phone_numbers.len(), 1);
+        assert_eq!(extras.trusted_phone_numbers[0].last_two_digits, "42");
+        let phone_id = extras.trusted_phone_numbers[0].id;
+
+        let body = match account.send_sms_2fa_to_devices(phone_id).await.unwrap() {
+            LoginState::NeedsSMS2FAVerification(body) => body,
+            _ => panic!("expected NeedsSMS2FAVerification"),
+        };
+        assert_eq!(
+            emulator.code_deliveries(),
+            vec![CodeDelivery::Phone {
+                id: phone_id,
+                mode: "sms".to_string()
+            }]
+        );
+
+        assert!(matches!(
+            account.verify_sms_2fa("000000".to_string(), body.clone()).await,
+            Err(Error::Bad2faCode(-21669, message)) if message == "Incorrect verification code."
+        ));
+        let state = account
+            .verify_sms_2fa(emulated.security_code.clone(), body)
+            .await
+            .unwrap();
+        assert!(matches!(state, LoginState::NeedsLogin));
+
+        let state = account
+            .login_email_pass(&emulated.username, &emulated.password)
+            .await
+            .unwrap();
+        assert!(matches!(state, LoginState::LoggedIn));
+    }
+
+    #[tokio::test]
+    async fn voice_call_to_second_number() {
+        let emulated = EmulatedAccount {
+            second_factor: SecondFactor::Sms,
+            trusted_phone_numbers: vec![
+                EmulatedPhoneNumber {
+                    id: 1,
+                    number_with_dial_code: "+1 (•••) •••-••42".to_string(),
+                    push_mode: "sms".to_string(),
+                },
+                EmulatedPhoneNumber {
+                    id: 7,
+                    number_with_dial_code: "+33 •• •• •• 17".to_string(),
+                    push_mode: "voice".to_string(),
+                },
+            ],
+            ..Default::default()
+        };
+        let emulator = GsaEmulator::start(emulated.clone()).await.unwrap();
+        let mut account = common::account(&emulator, AccountConfiguration::new());
+
+        account
+            .login_email_pass(&emulated.username, &emulated.password)
+            .await
+            .unwrap();
+
+        let numbers = account.trusted_phone_numbers().await.unwrap();
+        assert_eq!(numbers.len(), 2);
+        let number = numbers.iter().find(|n| n.last_two_digits == "17").unwrap();
+        assert_eq!(number.delivery_mode(), DeliveryMode::Voice);
+
+        let body = match account.send_phone_code(number.id, DeliveryMode::Voice).await.unwrap() {
+            LoginState::NeedsSMS2FAVerification(body) => body,
+            _ => panic!("expected NeedsSMS2FAVerification"),
+        };
+        assert_eq!(body.phone_id(), 7);
+        assert_eq!(body.mode(), DeliveryMode::Voice);
+        assert_eq!(
+            emulator.code_deliveries(),
+            vec![CodeDelivery::Phone {
+                id: 7,
+                mode: "voice".to_string()
+            }]
+        );
+
+        let state = account
+            .verify_sms_2fa(emulated.security_code.clone(), body)
+            .await
+            .unwrap();
+        assert!(matches!(state, LoginState::NeedsLogin));
+    }
+
+    #[tokio::test]
+    async fn login_with_s2k_fo() {
+        let emulated = EmulatedAccount {
+            password_protocol: gsa_emulator::PasswordProtocol::S2kFo,
+            ..Default::default()
+        };
+        let emulator = GsaEmulator::start(emulated.clone()).await.unwrap();
+        let mut account = common::account(&emulator, AccountConfiguration::new());
+
+        let state = account
+            .login_email_pass(&emulated.username, &emulated.password)
+            .await
+            .unwrap();
+        assert!(matches!(state, LoginState::LoggedIn));
+    }
+
+    #[tokio::test]
+    async fn wrong_password() {
+        let (emulator, emulated) = start(SecondFactor::None).await;
+        let mut account = common::account(&emulator, AccountConfiguration::new());
+
+        let res = account
+            .login_email_pass(&emulated.username, "not the password")
+            .await;
+        assert!(matches!(res, Err(Error::IncorrectCredentials(-22406, _))));
+    }
+
+    #[tokio::test]
+    async fn anisette_rejected() {
+        let (emulator, emulated) = start(SecondFactor::None).await;
+        emulator.set_reject_anisette(true);
+        let mut account = common::account(&emulator, AccountConfiguration::new());
+
+        let res = account
+            .login_email_pass(&emulated.username, &emulated.password)
+            .await;
+        assert!(matches!(res, Err(Error::AnisetteRejected(-45061, _))));
+    }
+}
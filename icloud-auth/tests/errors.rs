@@ -0,0 +1,26 @@
+#[cfg(test)]
+mod tests {
+    use icloud_auth::Error;
+
+    #[test]
+    fn gsa_status_mapping() {
+        let err = Error::from_gsa_status(-22406, "Your Apple ID or password was incorrect.".to_string());
+        assert!(matches!(err, Error::IncorrectCredentials(-22406, _)));
+        assert_eq!(err.gsa_code(), Some(-22406));
+
+        assert!(matches!(
+            Error::from_gsa_status(-45061, String::new()),
+            Error::AnisetteRejected(-45061, _)
+        ));
+
+        let bad_code = Error::from_gsa_status(-21669, "Incorrect verification code.".to_string());
+        assert!(matches!(&bad_code, Error::Bad2faCode(-21669, message) if message == "Incorrect verification code."));
+        assert_eq!(bad_code.gsa_code(), Some(-21669));
+
+        let unknown = Error::from_gsa_status(-1, "Something else".to_string());
+        assert!(matches!(unknown, Error::AuthSrpWithMessage(-1, _)));
+        assert_eq!(unknown.to_string(), "Something else (-1)");
+
+        assert_eq!(Error::NotLoggedIn.gsa_code(), None);
+    }
+}
@@ -36,6 +36,7 @@ mod tests {
         let account = acc.unwrap();
         println!("data {:?}", account.get_name());
         println!("PET: {}", account.get_pet().unwrap());
+        assert!(account.get_app_token("com.apple.gs.xcode.auth").await.is_ok());
         return;
     }
 }
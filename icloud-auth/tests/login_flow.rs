@@ -0,0 +1,107 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use gsa_emulator::{EmulatedAccount, GsaEmulator, SecondFactor};
+    use icloud_auth::*;
+
+    use crate::common;
+
+    #[tokio::test]
+    async fn device_code_across_suspend() {
+        let emulated = EmulatedAccount {
+            second_factor: SecondFactor::TrustedDevice,
+            ..Default::default()
+        };
+        let emulator = GsaEmulator::start(emulated.clone()).await.unwrap();
+
+        let mut flow = LoginFlow::new(common::account(&emulator, AccountConfiguration::new()));
+        let state = flow
+            .begin(&emulated.username, &emulated.password)
+            .await
+            .unwrap();
+        assert!(matches!(state, LoginState::NeedsDevice2FA));
+        assert!(matches!(
+            flow.submit_sms_code(emulated.security_code.clone()).await,
+            Err(Error::InvalidLoginStep("submit_sms_code"))
+        ));
+
+        let state = flow.request_device_code().await.unwrap();
+        assert!(matches!(state, LoginState::Needs2FAVerification));
+
+        // e.g. the code is entered in a later HTTP request, handled by another worker
+        let cipher = AesGcmCipher::new(&[7; 32]);
+        let saved = flow.suspend().to_encrypted_bytes(&cipher).unwrap();
+        drop(flow);
+
+        // the password never leaves the process in the clear
+        assert!(!saved
+            .windows(emulated.password.len())
+            .any(|window| window == emulated.password.as_bytes()));
+        assert!(LoginFlowSnapshot::from_encrypted_bytes(&saved, &AesGcmCipher::new(&[8; 32])).is_err());
+
+        let snapshot = LoginFlowSnapshot::from_encrypted_bytes(&saved, &cipher).unwrap();
+        assert!(matches!(snapshot.state(), LoginState::Needs2FAVerification));
+        let mut flow = LoginFlow::resume(snapshot, common::account(&emulator, AccountConfiguration::new()));
+        let session = flow.account().export_session().unwrap();
+        assert_eq!(session.username.as_deref(), Some(emulated.username.as_str()));
+
+        let state = flow
+            .submit_device_code(emulated.security_code.clone())
+            .await
+            .unwrap();
+        assert!(matches!(state, LoginState::LoggedIn));
+
+        let account = flow.into_account().unwrap();
+        assert_eq!(account.get_name().unwrap().0, emulated.first_name);
+    }
+
+    #[tokio::test]
+    async fn sms_code_across_suspend() {
+        let emulated = EmulatedAccount {
+            second_factor: SecondFactor::Sms,
+            ..Default::default()
+        };
+        let emulator = GsaEmulator::start(emulated.clone()).await.unwrap();
+
+        let mut flow = LoginFlow::new(common::account(&emulator, AccountConfiguration::new()));
+        let state = flow
+            .begin(&emulated.username, &emulated.password)
+            .await
+            .unwrap();
+        assert!(matches!(state, LoginState::NeedsSMS2FA));
+
+        let phone_id = flow.auth_extras().await.unwrap().trusted_phone_numbers[0].id;
+        let state = flow.request_sms(phone_id).await.unwrap();
+        assert!(matches!(state, LoginState::NeedsSMS2FAVerification(_)));
+
+        let cipher = AesGcmCipher::new(&[7; 32]);
+        let saved = flow.suspend().to_encrypted_bytes(&cipher).unwrap();
+        let mut flow = LoginFlow::resume(
+            LoginFlowSnapshot::from_encrypted_bytes(&saved, &cipher).unwrap(),
+            common::account(&emulator, AccountConfiguration::new()),
+        );
+
+        assert!(matches!(
+            flow.submit_sms_code("000000".to_string()).await,
+            Err(Error::Bad2faCode(-21669, _))
+        ));
+        let state = flow
+            .submit_sms_code(emulated.security_code.clone())
+            .await
+            .unwrap();
+        assert!(matches!(state, LoginState::LoggedIn));
+        flow.into_account().unwrap();
+    }
+
+    #[tokio::test]
+    async fn unfinished_flow() {
+        let emulator = GsaEmulator::start(EmulatedAccount::default()).await.unwrap();
+        let flow = LoginFlow::new(common::account(&emulator, AccountConfiguration::new()));
+
+        assert!(matches!(
+            flow.into_account(),
+            Err(Error::InvalidLoginStep("into_account"))
+        ));
+    }
+}
@@ -0,0 +1,151 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::PathBuf,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use gsa_emulator::{EmulatedAccount, GsaEmulator};
+    use icloud_auth::*;
+
+    use crate::common;
+
+    struct XorCipher(u8);
+
+    impl SessionCipher for XorCipher {
+        fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+            Ok(data.iter().map(|b| b ^ self.0).collect())
+        }
+
+        fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+            self.encrypt(data)
+        }
+    }
+
+    fn temp_root() -> PathBuf {
+        let name = (0..8)
+            .map(|_| format!("{:02x}", rand::random::<u8>()))
+            .collect::<String>();
+        std::env::temp_dir().join(format!("icloud-auth-manager-{name}"))
+    }
+
+    fn manager(root: PathBuf) -> AccountManager {
+        AccountManager::new(root, AnisetteConfiguration::new()).set_session_cipher(Arc::new(XorCipher(0x5a)))
+    }
+
+    /// Logs in `adsid` on its own emulator, with a provisioning directory created like `new_account` does
+    async fn logged_in(manager: &AccountManager, adsid: &str) -> (GsaEmulator, AppleAccount) {
+        let emulated = EmulatedAccount {
+            adsid: adsid.to_string(),
+            ..Default::default()
+        };
+        let emulator = GsaEmulator::start(emulated.clone()).await.unwrap();
+
+        let config = manager.anisette_configuration(&format!("pending-{adsid}"));
+        fs::create_dir_all(config.configuration_path()).unwrap();
+        fs::write(config.configuration_path().join("adi.pb"), adsid).unwrap();
+        let anisette = common::anisette(config);
+        let config = AccountConfiguration::new().set_base_url(emulator.base_url());
+        let mut account = AppleAccount::new_with_configuration(anisette, config).unwrap();
+        account
+            .login_email_pass(&emulated.username, &emulated.password)
+            .await
+            .unwrap();
+        (emulator, account)
+    }
+
+    #[tokio::test]
+    async fn manage_accounts() {
+        let root = temp_root();
+        let mut manager = manager(root.clone());
+
+        let (_first_emulator, first) = logged_in(&manager, "adsid-1").await;
+        let (_second_emulator, second) = logged_in(&manager, "adsid-2").await;
+        assert_eq!(manager.insert(first).unwrap(), "adsid-1");
+        assert_eq!(manager.insert(second).unwrap(), "adsid-2");
+        assert_eq!(manager.list(), vec!["adsid-1", "adsid-2"]);
+        assert_eq!(manager.current_adsid(), Some("adsid-1"));
+
+        // the provisioning data moved next to the session
+        for adsid in ["adsid-1", "adsid-2"] {
+            let dir = root.join(adsid);
+            assert_eq!(fs::read_to_string(dir.join("anisette").join("adi.pb")).unwrap(), adsid);
+            assert!(dir.join("session.plist").exists());
+            assert!(!root.join(format!("pending-{adsid}")).exists());
+
+            let account = manager.get(adsid).unwrap();
+            let account = account.lock().await;
+            assert_eq!(account.spd().unwrap().adsid().unwrap(), adsid);
+            account.get_app_token("com.apple.gs.xcode.auth").await.unwrap();
+        }
+
+        manager.switch("adsid-2").unwrap();
+        let current = manager.current().unwrap();
+        assert_eq!(current.lock().await.spd().unwrap().adsid().unwrap(), "adsid-2");
+        assert!(matches!(manager.switch("adsid-3"), Err(Error::UnknownAccount(_))));
+
+        manager.remove("adsid-1").unwrap();
+        assert!(!root.join("adsid-1").exists());
+        assert_eq!(manager.list(), vec!["adsid-2"]);
+
+        let mut reloaded = self::manager(root.clone());
+        reloaded.load().unwrap();
+        assert_eq!(reloaded.list(), vec!["adsid-2"]);
+        let account = reloaded.get("adsid-2").unwrap();
+        let account = account.lock().await;
+        assert_eq!(account.spd().unwrap().adsid().unwrap(), "adsid-2");
+        assert_eq!(
+            account.anisette.lock().await.config().configuration_path(),
+            &root.join("adsid-2").join("anisette")
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn adsid_is_a_single_directory() {
+        let root = temp_root();
+        let mut manager = manager(root.join("accounts"));
+
+        let (_emulator, account) = logged_in(&manager, "../escaped").await;
+        assert!(matches!(manager.insert(account), Err(Error::InvalidAdsid(_))));
+        assert!(!root.join("escaped").exists());
+        for adsid in ["..", "a/b", "/tmp", "", "pending-1"] {
+            assert!(matches!(manager.remove(adsid), Err(Error::InvalidAdsid(_))));
+        }
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn abandoned_logins_are_pruned() {
+        let root = temp_root();
+        fs::create_dir_all(root.join("pending-old").join("anisette")).unwrap();
+        fs::create_dir_all(root.join("pending-new").join("anisette")).unwrap();
+        fs::File::open(root.join("pending-old"))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(2 * 60 * 60))
+            .unwrap();
+
+        manager(root.clone()).load().unwrap();
+        assert!(!root.join("pending-old").exists());
+        // the login may still be going on
+        assert!(root.join("pending-new").exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn sessions_are_sealed() {
+        let root = temp_root();
+        fs::create_dir_all(root.join("adsid-1")).unwrap();
+        fs::write(root.join("adsid-1").join("session.plist"), b"not a sealed session").unwrap();
+
+        assert!(manager(root.clone()).load().is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
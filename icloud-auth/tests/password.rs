@@ -0,0 +1,51 @@
+#[cfg(test)]
+mod tests {
+    use icloud_auth::{Error, PasswordProtocol};
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn s2k_vectors() {
+        let derived = PasswordProtocol::S2k.derive_password("password", b"salt", 1000);
+        assert_eq!(
+            hex(&derived),
+            "bcf2419af19758a976f4908f0cb534e4b572b9f9b54c2206184052d7b6e878b6"
+        );
+
+        let salt: Vec<u8> = (0..16).collect();
+        let derived = PasswordProtocol::S2k.derive_password("hunter2", &salt, 20000);
+        assert_eq!(
+            hex(&derived),
+            "d83cd4434af47a7c4f7c5a4a3460d96335454c6d7921079dad33cc6af560b93b"
+        );
+    }
+
+    #[test]
+    fn s2k_fo_vectors() {
+        let derived = PasswordProtocol::S2kFo.derive_password("password", b"salt", 1000);
+        assert_eq!(
+            hex(&derived),
+            "66f7693a7310205a00b3d0634242209a2d4c687187a13e284d295f1c489ee3bf"
+        );
+
+        let salt: Vec<u8> = (0..16).collect();
+        let derived = PasswordProtocol::S2kFo.derive_password("hunter2", &salt, 20000);
+        assert_eq!(
+            hex(&derived),
+            "d4de0de08c29f1e898eb67278778efe84ad7a22cea3ccee39befa4b39222abc7"
+        );
+    }
+
+    #[test]
+    fn protocol_names() {
+        for protocol in PasswordProtocol::SUPPORTED {
+            assert_eq!(PasswordProtocol::from_name(protocol.name()).unwrap(), protocol);
+        }
+        assert!(matches!(
+            PasswordProtocol::from_name("s2k_unknown"),
+            Err(Error::UnsupportedPasswordProtocol(name)) if name == "s2k_unknown"
+        ));
+    }
+}
@@ -0,0 +1,85 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use gsa_emulator::{EmulatedAccount, GsaEmulator, SecondFactor};
+    use icloud_auth::{anisette::AnisetteData, *};
+
+    /// Collects every log record, tracing events and spans included
+    struct CaptureLogger(Mutex<Vec<String>>);
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CaptureLogger = CaptureLogger(Mutex::new(Vec::new()));
+
+    #[test]
+    fn redacted_hides_value() {
+        let formatted = format!("{} {:?}", Redacted("hunter2"), Redacted("hunter2"));
+        if cfg!(feature = "unsafe-debug") {
+            assert_eq!(formatted, "hunter2 \"hunter2\"");
+        } else {
+            assert_eq!(formatted, "<redacted> <redacted>");
+        }
+    }
+
+    #[cfg(not(feature = "unsafe-debug"))]
+    #[tokio::test]
+    async fn login_logs_no_secrets() {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let emulated = EmulatedAccount {
+            username: "leaky-user@example.com".to_string(),
+            password: "leaky-password".to_string(),
+            security_code: "918273".to_string(),
+            second_factor: SecondFactor::Sms,
+            ..Default::default()
+        };
+        let emulator = GsaEmulator::start(emulated.clone()).await.unwrap();
+
+        let mut headers = gsa_emulator::fake_anisette_headers();
+        headers.insert("X-Apple-I-MD".to_string(), "leaky-otp".to_string());
+        headers.insert("X-Apple-I-MD-M".to_string(), "leaky-machine-id".to_string());
+        let anisette = AnisetteData::from_headers(headers, AnisetteConfiguration::new());
+        let config = AccountConfiguration::new().set_base_url(emulator.base_url());
+        let account = AppleAccount::new_with_configuration(anisette, config).unwrap();
+
+        let mut flow = LoginFlow::new(account);
+        flow.begin(&emulated.username, &emulated.password).await.unwrap();
+        flow.request_sms(emulated.trusted_phone_numbers[0].id).await.unwrap();
+        flow.submit_sms_code(emulated.security_code.clone()).await.unwrap();
+        let account = flow.into_account().unwrap();
+        let token = account.get_app_token("com.apple.gs.xcode.auth").await.unwrap();
+
+        let spd = account.spd().unwrap();
+        let secrets = [
+            emulated.username.clone(),
+            emulated.password.clone(),
+            emulated.security_code.clone(),
+            "leaky-otp".to_string(),
+            "leaky-machine-id".to_string(),
+            spd.gs_idms_token().unwrap().to_string(),
+            spd.pet().unwrap().to_string(),
+            token.auth_token.clone(),
+        ];
+
+        let logs = LOGGER.0.lock().unwrap();
+        assert!(logs.iter().any(|line| line.contains("received response")));
+        for line in logs.iter() {
+            for secret in &secrets {
+                assert!(!line.contains(secret.as_str()), "{secret} leaked in {line}");
+            }
+        }
+        assert!(!format!("{token:?}").contains(&token.auth_token));
+    }
+}
@@ -0,0 +1,134 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use gsa_emulator::{EmulatedAccount, GsaEmulator};
+    use icloud_auth::*;
+
+    use crate::common;
+
+    async fn logged_in(pet_duration: i64, config: AccountConfiguration) -> (GsaEmulator, AppleAccount) {
+        let emulated = EmulatedAccount {
+            pet_duration,
+            ..Default::default()
+        };
+        let emulator = GsaEmulator::start(emulated.clone()).await.unwrap();
+        let mut account = common::account(&emulator, config);
+        let state = account
+            .login_email_pass(&emulated.username, &emulated.password)
+            .await
+            .unwrap();
+        assert!(matches!(state, LoginState::LoggedIn));
+        (emulator, account)
+    }
+
+    fn counting_callback() -> (Arc<AtomicUsize>, ReauthCallback) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let callback: ReauthCallback = Arc::new(move |err: &Error| {
+            assert!(matches!(err, Error::ReauthRequired | Error::IncorrectCredentials(..)));
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        (calls, callback)
+    }
+
+    #[tokio::test]
+    async fn renews_expiring_pet() {
+        let config = AccountConfiguration::new()
+            .set_renewal_margin(Duration::from_secs(60))
+            .set_pet_renewal(true);
+        let (emulator, mut account) = logged_in(30, config).await;
+        assert!(account.needs_reauth());
+
+        let old_token = account.spd().unwrap().gs_idms_token().unwrap().to_string();
+        let old_pet = account.get_pet().unwrap();
+        let requests = emulator.requests();
+        account.renew().await.unwrap();
+
+        // a single `complete` carrying the PET, without going through SRP again
+        assert_eq!(emulator.requests(), requests + 1);
+        assert_eq!(emulator.pet_renewals(), 1);
+        assert_ne!(account.spd().unwrap().gs_idms_token().unwrap(), old_token);
+        assert_ne!(account.get_pet().unwrap(), old_pet);
+        account.get_app_token("com.apple.gs.xcode.auth").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn renewal_is_opt_in() {
+        let (calls, callback) = counting_callback();
+        let config = AccountConfiguration::new().set_reauth_callback(callback);
+        let (emulator, mut account) = logged_in(30, config).await;
+        let requests = emulator.requests();
+
+        assert!(matches!(account.renew().await, Err(Error::ReauthRequired)));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(emulator.requests(), requests);
+    }
+
+    #[tokio::test]
+    async fn app_token_is_cached() {
+        let (emulator, mut account) = logged_in(300, AccountConfiguration::new()).await;
+        assert!(!account.needs_reauth());
+
+        let first = account.app_token("com.apple.gs.xcode.auth").await.unwrap();
+        let second = account.app_token("com.apple.gs.xcode.auth").await.unwrap();
+        assert_eq!(first.auth_token, second.auth_token);
+        assert_eq!(emulator.pet_renewals(), 0);
+    }
+
+    #[tokio::test]
+    async fn app_token_renews_session() {
+        let config = AccountConfiguration::new()
+            .set_renewal_margin(Duration::from_secs(60))
+            .set_pet_renewal(true);
+        let (emulator, mut account) = logged_in(30, config).await;
+
+        account.app_token("com.apple.gs.xcode.auth").await.unwrap();
+        assert_eq!(emulator.pet_renewals(), 1);
+    }
+
+    #[tokio::test]
+    async fn expired_pet_needs_login() {
+        let (calls, callback) = counting_callback();
+        let config = AccountConfiguration::new()
+            .set_pet_renewal(true)
+            .set_reauth_callback(callback);
+        let (emulator, mut account) = logged_in(0, config).await;
+        assert!(account.needs_reauth());
+
+        assert!(matches!(account.renew().await, Err(Error::ReauthRequired)));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(emulator.pet_renewals(), 0);
+    }
+
+    #[tokio::test]
+    async fn revoked_pet_needs_login() {
+        let (calls, callback) = counting_callback();
+        let config = AccountConfiguration::new()
+            .set_pet_renewal(true)
+            .set_reauth_callback(callback);
+        let (emulator, mut account) = logged_in(300, config).await;
+
+        emulator.revoke_pet();
+        assert!(matches!(account.renew().await, Err(Error::ReauthRequired)));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn session_keeps_username() {
+        let (_emulator, account) = logged_in(300, AccountConfiguration::new()).await;
+        let session = account.export_session().unwrap();
+        assert_eq!(session.username.as_deref(), Some("test@example.com"));
+
+        let session = AccountSession::from_bytes(&session.to_bytes().unwrap()).unwrap();
+        assert_eq!(session.username.as_deref(), Some("test@example.com"));
+    }
+}
@@ -0,0 +1,125 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use gsa_emulator::{CodeDelivery, EmulatedAccount, Failure, GsaEmulator, SecondFactor};
+    use icloud_auth::*;
+
+    use crate::common;
+
+    fn fast_retries(max_attempts: u32) -> HttpConfiguration {
+        HttpConfiguration::new().set_retry_policy(
+            RetryPolicy::new()
+                .set_max_attempts(max_attempts)
+                .set_base_delay(Duration::from_millis(10))
+                .set_max_delay(Duration::from_millis(50)),
+        )
+    }
+
+    async fn needs_device_code(emulator: &GsaEmulator, emulated: &EmulatedAccount, http: HttpConfiguration) -> AppleAccount {
+        let config = AccountConfiguration::new().set_http_configuration(http);
+        let mut account = common::account(emulator, config);
+        let state = account
+            .login_email_pass(&emulated.username, &emulated.password)
+            .await
+            .unwrap();
+        assert!(matches!(state, LoginState::NeedsDevice2FA));
+        account
+    }
+
+    #[tokio::test]
+    async fn device_push_retries_server_errors() {
+        let emulated = EmulatedAccount {
+            second_factor: SecondFactor::TrustedDevice,
+            ..Default::default()
+        };
+        let emulator = GsaEmulator::start(emulated.clone()).await.unwrap();
+        let account = needs_device_code(&emulator, &emulated, fast_retries(3)).await;
+        let requests = emulator.requests();
+
+        // the Retry-After of an hour is capped at the policy's max delay
+        emulator.fail_next(Failure::Status {
+            status: 503,
+            retry_after: Some(3600),
+        });
+        emulator.fail_next(Failure::Status {
+            status: 429,
+            retry_after: None,
+        });
+        let started = Instant::now();
+        let state = account.send_2fa_to_devices().await.unwrap();
+        assert!(matches!(state, LoginState::Needs2FAVerification));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(emulator.requests() - requests, 3);
+        assert_eq!(emulator.code_deliveries(), vec![CodeDelivery::TrustedDevice]);
+    }
+
+    #[tokio::test]
+    async fn retries_give_up() {
+        let emulated = EmulatedAccount {
+            second_factor: SecondFactor::Sms,
+            ..Default::default()
+        };
+        let emulator = GsaEmulator::start(emulated.clone()).await.unwrap();
+        let config = AccountConfiguration::new().set_http_configuration(fast_retries(2));
+        let mut account = common::account(&emulator, config);
+        account
+            .login_email_pass(&emulated.username, &emulated.password)
+            .await
+            .unwrap();
+
+        // rate limited through a GSA status rather than the HTTP status
+        emulator.fail_next(Failure::RateLimited);
+        let numbers = account.trusted_phone_numbers().await.unwrap();
+        assert_eq!(numbers.len(), emulated.trusted_phone_numbers.len());
+
+        let requests = emulator.requests();
+        emulator.fail_next(Failure::RateLimited);
+        emulator.fail_next(Failure::RateLimited);
+        assert!(account.trusted_phone_numbers().await.is_err());
+        assert_eq!(emulator.requests() - requests, 2);
+    }
+
+    #[tokio::test]
+    async fn stalled_requests_time_out() {
+        let emulated = EmulatedAccount {
+            second_factor: SecondFactor::TrustedDevice,
+            ..Default::default()
+        };
+        let emulator = GsaEmulator::start(emulated.clone()).await.unwrap();
+        let http = fast_retries(2).set_timeout(Duration::from_millis(200));
+        let account = needs_device_code(&emulator, &emulated, http).await;
+
+        // the first attempt times out and the retry goes through
+        emulator.fail_next(Failure::Stall(Duration::from_secs(2)));
+        account.send_2fa_to_devices().await.unwrap();
+
+        let account = needs_device_code(&emulator, &emulated, fast_retries(1).set_timeout(Duration::from_millis(200))).await;
+        emulator.fail_next(Failure::Stall(Duration::from_secs(2)));
+        let started = Instant::now();
+        assert!(matches!(
+            account.send_2fa_to_devices().await,
+            Err(Error::TransportError(_))
+        ));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn dropping_a_request_cancels_it() {
+        let emulated = EmulatedAccount {
+            second_factor: SecondFactor::TrustedDevice,
+            ..Default::default()
+        };
+        let emulator = GsaEmulator::start(emulated.clone()).await.unwrap();
+        let account = needs_device_code(&emulator, &emulated, HttpConfiguration::new()).await;
+
+        emulator.fail_next(Failure::Stall(Duration::from_secs(5)));
+        let result = tokio::time::timeout(Duration::from_millis(100), account.send_2fa_to_devices()).await;
+        assert!(result.is_err());
+
+        // the account is still usable afterwards
+        account.send_2fa_to_devices().await.unwrap();
+    }
+}
@@ -67,7 +67,7 @@ mod tests {
 
     fn root_write() {
         // write to file src/root.der
-        let mut file = File::create("src/apple_root.der").unwrap();
+        let mut file = File::create("../omnisette/src/apple_root.der").unwrap();
         file.write_all(&APPLE_ROOT).unwrap();
     }
 }
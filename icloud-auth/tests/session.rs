@@ -0,0 +1,61 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use icloud_auth::{anisette::AnisetteData, *};
+
+    struct XorCipher(u8);
+
+    impl SessionCipher for XorCipher {
+        fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+            Ok(data.iter().map(|b| b ^ self.0).collect())
+        }
+
+        fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+            self.encrypt(data)
+        }
+    }
+
+    fn anisette() -> AnisetteData {
+        AnisetteData::from_headers(HashMap::new(), AnisetteConfiguration::new())
+    }
+
+    #[test]
+    fn session_round_trip() {
+        let spd = ServerProvidedData {
+            adsid: Some("000000-00-test".to_string()),
+            sk: Some(plist::Data::new(vec![1, 2, 3, 4])),
+            ..Default::default()
+        };
+
+        let account = AppleAccount::from_session_with_anisette(AccountSession::new(spd.clone()), anisette()).unwrap();
+        let session = account.export_session().unwrap();
+
+        let cipher = XorCipher(0x5a);
+        let sealed = session.to_encrypted_bytes(&cipher).unwrap();
+        assert_ne!(sealed, session.to_bytes().unwrap());
+
+        let resumed = AccountSession::from_encrypted_bytes(&sealed, &cipher).unwrap();
+        assert_eq!(resumed.spd, spd);
+    }
+
+    #[test]
+    fn aes_gcm_cipher() {
+        let cipher = AesGcmCipher::new(&[1; 32]);
+        let sealed = cipher.encrypt(b"session").unwrap();
+        assert_ne!(cipher.encrypt(b"session").unwrap(), sealed);
+        assert_eq!(cipher.decrypt(&sealed).unwrap(), b"session");
+
+        assert!(AesGcmCipher::new(&[2; 32]).decrypt(&sealed).is_err());
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(cipher.decrypt(&tampered).is_err());
+        assert!(cipher.decrypt(b"short").is_err());
+    }
+
+    #[test]
+    fn export_requires_login() {
+        let account = AppleAccount::new_with_anisette(anisette()).unwrap();
+        assert!(matches!(account.export_session(), Err(Error::NotLoggedIn)));
+    }
+}
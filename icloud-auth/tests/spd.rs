@@ -0,0 +1,77 @@
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use icloud_auth::*;
+
+    const SPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>adsid</key><string>000000-00-test</string>
+    <key>DsPrsId</key><integer>12345678</integer>
+    <key>GsIdmsToken</key><string>gs-token</string>
+    <key>fn</key><string>Test</string>
+    <key>ln</key><string>Account</string>
+    <key>sk</key><data>AQIDBA==</data>
+    <key>c</key><data>BQYHCA==</data>
+    <key>isManagedAppleID</key><false/>
+    <key>hasEmptyPassword</key><integer>0</integer>
+    <key>t</key>
+    <dict>
+        <key>com.apple.gs.idms.pet</key>
+        <dict>
+            <key>token</key><string>pet-token</string>
+            <key>duration</key><integer>300</integer>
+            <key>expiry</key><integer>1700000300000</integer>
+        </dict>
+    </dict>
+</dict>
+</plist>"#;
+
+    #[test]
+    fn parse_spd() {
+        let spd: ServerProvidedData = plist::from_bytes(SPD.as_bytes()).unwrap();
+
+        assert_eq!(spd.adsid().unwrap(), "000000-00-test");
+        assert_eq!(spd.dsid().unwrap(), "12345678");
+        assert_eq!(spd.gs_idms_token().unwrap(), "gs-token");
+        assert_eq!(spd.name().unwrap(), ("Test", "Account"));
+        assert_eq!(spd.session_key().unwrap(), &[1, 2, 3, 4]);
+        assert_eq!(spd.pet().unwrap(), "pet-token");
+        assert_eq!(spd.token("com.apple.gs.idms.pet").unwrap().duration, Some(300));
+        assert_eq!(spd.is_managed_apple_id, Some(false));
+        assert_eq!(spd.has_empty_password, Some(false));
+    }
+
+    #[test]
+    fn token_expiry() {
+        let token = SpdToken {
+            token: "pet-token".to_string(),
+            duration: Some(300),
+            expiry: None,
+            cts: Some(1700000000000),
+        };
+        assert_eq!(
+            token.expires_at(),
+            Some(UNIX_EPOCH + Duration::from_millis(1700000300000))
+        );
+        assert!(token.expires_within(Duration::ZERO));
+
+        let token = SpdToken {
+            cts: None,
+            ..token
+        };
+        assert_eq!(token.expires_at(), None);
+        assert!(!token.expires_within(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn missing_fields() {
+        let spd = ServerProvidedData::default();
+
+        assert!(matches!(spd.adsid(), Err(Error::MissingSpdField("adsid"))));
+        assert!(matches!(spd.session_key(), Err(Error::MissingSpdField("sk"))));
+        assert!(matches!(spd.pet(), Err(Error::MissingSpdToken(_))));
+    }
+}
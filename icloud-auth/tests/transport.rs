@@ -0,0 +1,83 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use icloud_auth::*;
+    use omnisette::http::{
+        HeaderMap, HttpRequest, HttpResponse, HttpTransport, StatusCode, TransportError,
+    };
+
+    use crate::common;
+
+    /// Answers every request with a GSA "rate limited" status and remembers what was sent
+    #[derive(Default)]
+    struct FakeTransport {
+        requests: Mutex<Vec<HttpRequest>>,
+    }
+
+    #[async_trait::async_trait]
+    impl HttpTransport for FakeTransport {
+        async fn send(&self, request: HttpRequest) -> Result<HttpResponse, TransportError> {
+            self.requests.lock().unwrap().push(request);
+
+            let status = plist::Dictionary::from_iter([
+                ("ec".to_string(), plist::Value::from(-20311)),
+                ("em".to_string(), plist::Value::from("Slow down")),
+            ]);
+            let response = plist::Dictionary::from_iter([(
+                "Response".to_string(),
+                plist::Value::Dictionary(plist::Dictionary::from_iter([(
+                    "Status".to_string(),
+                    plist::Value::Dictionary(status),
+                )])),
+            )]);
+            let mut body = Vec::new();
+            plist::to_writer_xml(&mut body, &response).unwrap();
+
+            Ok(HttpResponse {
+                status: StatusCode::OK,
+                headers: HeaderMap::new(),
+                body,
+            })
+        }
+    }
+
+    fn account(transport: Arc<FakeTransport>, anisette_config: AnisetteConfiguration) -> AppleAccount {
+        let config = AccountConfiguration::new()
+            .set_base_url("https://gsa.invalid".to_string())
+            .set_url_bag_lookup(false)
+            .set_transport(transport);
+        AppleAccount::new_with_configuration(common::anisette(anisette_config), config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_go_through_transport() {
+        let transport = Arc::new(FakeTransport::default());
+        let mut account = account(transport.clone(), AnisetteConfiguration::new());
+
+        let res = account.login_email_pass("test@example.com", "password").await;
+        assert!(matches!(res, Err(Error::RateLimited(-20311, _))));
+
+        let requests = transport.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].url, "https://gsa.invalid/grandslam/GsService2");
+        assert_eq!(requests[0].headers["Content-Type"], "text/x-xml-plist");
+    }
+
+    #[tokio::test]
+    async fn requests_use_client_profile() {
+        let transport = Arc::new(FakeTransport::default());
+        let config = AnisetteConfiguration::new().set_client_profile(ClientProfile::ios_settings());
+        let mut account = account(transport.clone(), config);
+
+        account.login_email_pass("test@example.com", "password").await.unwrap_err();
+
+        let requests = transport.requests.lock().unwrap();
+        assert_eq!(
+            requests[0].headers["User-Agent"],
+            ClientProfile::ios_settings().akd_user_agent.as_str()
+        );
+    }
+}
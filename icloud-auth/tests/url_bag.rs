@@ -0,0 +1,107 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use gsa_emulator::{EmulatedAccount, GsaEmulator, SecondFactor};
+    use icloud_auth::*;
+
+    use crate::common;
+
+    /// Logs in through SMS 2FA, which goes through every endpoint but the trusted device ones
+    async fn sms_login(account: &mut AppleAccount, emulated: &EmulatedAccount) {
+        let state = account
+            .login_email_pass(&emulated.username, &emulated.password)
+            .await
+            .unwrap();
+        assert!(matches!(state, LoginState::NeedsSMS2FA));
+
+        let number = &account.trusted_phone_numbers().await.unwrap()[0];
+        let state = account.send_phone_code(number.id, number.delivery_mode()).await.unwrap();
+        let LoginState::NeedsSMS2FAVerification(body) = state else {
+            panic!("unexpected state {:?}", state);
+        };
+        let state = account.verify_sms_2fa(emulated.security_code.clone(), body).await.unwrap();
+        assert!(matches!(state, LoginState::NeedsLogin));
+
+        let state = account
+            .login_email_pass(&emulated.username, &emulated.password)
+            .await
+            .unwrap();
+        assert!(matches!(state, LoginState::LoggedIn));
+    }
+
+    fn sms_account() -> EmulatedAccount {
+        EmulatedAccount {
+            second_factor: SecondFactor::Sms,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn endpoints_from_bag() {
+        let bag = UrlBag::new(HashMap::from([
+            ("gsService".to_string(), "https://gsa.example/gs".to_string()),
+            ("secondaryAuth".to_string(), "https://idmsa.example/auth/".to_string()),
+        ]));
+
+        assert_eq!(bag.endpoint(Endpoint::GsService).unwrap(), "https://gsa.example/gs");
+        assert_eq!(
+            bag.endpoint(Endpoint::VerifyPhoneCode).unwrap(),
+            "https://idmsa.example/auth/verify/phone/securitycode"
+        );
+        assert_eq!(bag.endpoint(Endpoint::ValidateCode), None);
+    }
+
+    #[tokio::test]
+    async fn requests_follow_the_bag() {
+        let emulated = sms_account();
+        let lookup = GsaEmulator::start(emulated.clone()).await.unwrap();
+        let gsa = GsaEmulator::start(emulated.clone()).await.unwrap();
+        lookup.set_url_bag_base(gsa.base_url());
+
+        let mut account = common::account(&lookup, AccountConfiguration::new());
+        sms_login(&mut account, &emulated).await;
+        account.get_app_token("com.apple.gs.xcode.auth").await.unwrap();
+
+        // the bag is fetched once, and everything else goes where it points
+        assert_eq!(lookup.url_bag_lookups(), 1);
+        assert_eq!(lookup.requests(), 1);
+        assert_eq!(gsa.url_bag_lookups(), 0);
+        assert_eq!(
+            account.url_bag().await.unwrap().get("gsService").unwrap(),
+            format!("{}/grandslam/GsService2", gsa.base_url())
+        );
+    }
+
+    #[tokio::test]
+    async fn endpoint_overrides() {
+        let emulated = sms_account();
+        let gsa = GsaEmulator::start(emulated.clone()).await.unwrap();
+
+        // nothing listens on the base url, so every request has to use an override
+        let config = Endpoint::ALL.iter().fold(
+            AccountConfiguration::new()
+                .set_base_url("http://127.0.0.1:9".to_string())
+                .set_url_bag_lookup(false),
+            |config, endpoint| {
+                config.set_endpoint(*endpoint, format!("{}{}", gsa.base_url(), endpoint.default_path()))
+            },
+        );
+        let mut account =
+            AppleAccount::new_with_configuration(common::anisette(AnisetteConfiguration::new()), config).unwrap();
+        sms_login(&mut account, &emulated).await;
+        assert_eq!(gsa.url_bag_lookups(), 0);
+    }
+
+    #[tokio::test]
+    async fn lookup_can_be_disabled() {
+        let emulated = sms_account();
+        let gsa = GsaEmulator::start(emulated.clone()).await.unwrap();
+
+        let mut account = common::account(&gsa, AccountConfiguration::new().set_url_bag_lookup(false));
+        sms_login(&mut account, &emulated).await;
+        assert_eq!(gsa.url_bag_lookups(), 0);
+    }
+}
@@ -1,21 +1,22 @@
 use crate::adi_proxy::ProvisioningError::InvalidResponse;
 use crate::anisette_headers_provider::AnisetteHeadersProvider;
-use crate::AnisetteError;
+use crate::http::{self, HttpRequest, HttpResponse, HttpTransport, RetryPolicy, TransportError};
+use crate::state_store::{AnisetteStateStore, FileStateStore, ADI_PB_KEY, IDENTIFIER_KEY};
+use crate::{AnisetteError, ClientProfile};
 use base64::engine::general_purpose::STANDARD as base64_engine;
 use base64::Engine;
 use log::debug;
 use plist::{Dictionary, Value};
 use rand::RngCore;
-#[cfg(not(feature = "async"))]
-use reqwest::blocking::{Client, ClientBuilder, Response};
 use reqwest::header::{HeaderMap, HeaderValue, InvalidHeaderValue};
-#[cfg(feature = "async")]
-use reqwest::{Client, ClientBuilder, Response};
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
-use std::io::{self, Read, Write};
+use std::fs;
+use std::io;
 use std::path::PathBuf;
+use std::sync::Arc;
+use tempfile::TempDir;
 use thiserror::Error;
 
 #[derive(Debug)]
@@ -44,6 +45,7 @@ pub enum ADIError {
     ProvisioningError(#[from] ProvisioningError),
     PlistError(#[from] plist::Error),
     ReqwestError(#[from] reqwest::Error),
+    TransportError(#[from] TransportError),
     Base64Error(#[from] base64::DecodeError),
     InvalidHeaderValue(#[from] InvalidHeaderValue),
     IOError(#[from] io::Error)
@@ -55,17 +57,13 @@ impl ADIError {
     }
 }
 
-#[cfg_attr(feature = "async", async_trait::async_trait)]
 trait ToPlist {
-    #[cfg_attr(not(feature = "async"), remove_async_await::remove_async_await)]
-    async fn plist(self) -> Result<Dictionary, ADIError>;
+    fn plist(self) -> Result<Dictionary, ADIError>;
 }
 
-#[cfg_attr(feature = "async", async_trait::async_trait)]
-impl ToPlist for Response {
-    #[cfg_attr(not(feature = "async"), remove_async_await::remove_async_await)]
-    async fn plist(self) -> Result<Dictionary, ADIError> {
-        if let Ok(property_list) = Value::from_reader_xml(&*self.bytes().await?) {
+impl ToPlist for HttpResponse {
+    fn plist(self) -> Result<Dictionary, ADIError> {
+        if let Ok(property_list) = Value::from_reader_xml(&*self.body) {
             Ok(property_list.as_dictionary().unwrap().to_owned())
         } else {
             Err(ProvisioningError::InvalidResponse.into())
@@ -161,13 +159,27 @@ impl AppleRequestResult for Dictionary {
 }
 
 impl dyn ADIProxy {
-    fn make_http_client(&mut self) -> Result<Client, ADIError> {
+    /// Sets the device identifier and the local user UUID derived from `identifier`
+    pub fn set_identity(&mut self, identifier: &Identifier) -> Result<(), ADIError> {
+        let mut local_user_uuid_hasher = Sha256::new();
+        local_user_uuid_hasher.update(identifier);
+
+        self.set_device_identifier(
+            uuid::Uuid::from_bytes(*identifier)
+                .to_string()
+                .to_uppercase(),
+        )?; // UUID, uppercase
+        self.set_local_user_uuid(hex::encode(local_user_uuid_hasher.finalize()).to_uppercase()); // 64 uppercase character hex
+        Ok(())
+    }
+
+    fn provisioning_headers(&mut self, profile: &ClientProfile) -> Result<HeaderMap, ADIError> {
         let mut headers = HeaderMap::new();
         headers.insert("Content-Type", HeaderValue::from_str("text/x-xml-plist")?);
 
         headers.insert(
             "X-Mme-Client-Info",
-            HeaderValue::from_str(CLIENT_INFO_HEADER)?,
+            HeaderValue::from_str(&profile.client_info)?,
         );
         headers.insert(
             "X-Mme-Device-Id",
@@ -182,28 +194,39 @@ impl dyn ADIProxy {
             HeaderValue::from_str(self.get_serial_number().as_str())?,
         );
 
-        debug!("Headers sent: {headers:?}");
+        headers.insert(
+            "User-Agent",
+            HeaderValue::from_str(&profile.provisioning_user_agent)?,
+        );
 
-        let http_client = ClientBuilder::new()
-            .http1_title_case_headers()
-            .danger_accept_invalid_certs(true) // TODO: pin the apple certificate
-            .user_agent(AKD_USER_AGENT)
-            .default_headers(headers)
-            .build()?;
+        #[cfg(feature = "unsafe-debug")]
+        debug!("Headers sent: {headers:?}");
+        #[cfg(not(feature = "unsafe-debug"))]
+        debug!("Headers sent: {:?}", headers.keys().collect::<Vec<_>>());
 
-        Ok(http_client)
+        Ok(headers)
     }
 
+    /// Provisions the machine in a new session. Only the lookup is retried with `retry_policy`:
+    /// the provisioning requests change Apple's side, so a failed session is destroyed for the
+    /// caller to start over.
     #[cfg_attr(not(feature = "async"), remove_async_await::remove_async_await)]
-    async fn provision_device(&mut self) -> Result<(), ADIError> {
-        let client = self.make_http_client()?;
-
-        let url_bag_res = client
-            .get("https://gsa.apple.com/grandslam/GsService2/lookup")
-            .send()
-            .await?
-            .plist()
-            .await?;
+    async fn provision_device(
+        &mut self,
+        transport: &dyn HttpTransport,
+        retry_policy: &RetryPolicy,
+        profile: &ClientProfile,
+    ) -> Result<(), ADIError> {
+        let headers = self.provisioning_headers(profile)?;
+
+        let url_bag_res = http::send_with_retry(
+            transport,
+            retry_policy,
+            HttpRequest::get("https://gsa.apple.com/grandslam/GsService2/lookup")
+                .headers(headers.clone()),
+        )
+        .await?
+        .plist()?;
 
         let urls = url_bag_res.get("urls").unwrap().as_dictionary().unwrap();
 
@@ -232,13 +255,14 @@ impl dyn ADIProxy {
         plist::Value::Dictionary(body).to_writer_xml(&mut sp_request)?;
 
         debug!("First provisioning request...");
-        let response = client
-            .post(start_provisioning_url)
-            .body(sp_request)
-            .send()
+        let response = transport
+            .send(
+                HttpRequest::post(start_provisioning_url)
+                    .headers(headers.clone())
+                    .body(sp_request),
+            )
             .await?
-            .plist()
-            .await?;
+            .plist()?;
 
         let response = response.get_response()?;
 
@@ -251,7 +275,25 @@ impl dyn ADIProxy {
 
         let spim = base64_engine.decode(spim)?;
         let first_step = self.start_provisioning(DS_ID, spim.as_slice())?;
+        let session = first_step.session;
 
+        let res = self
+            .finish_provisioning(transport, finish_provisioning_url, headers, first_step)
+            .await;
+        if res.is_err() {
+            let _ = self.destroy_provisioning_session(session);
+        }
+        res
+    }
+
+    #[cfg_attr(not(feature = "async"), remove_async_await::remove_async_await)]
+    async fn finish_provisioning(
+        &mut self,
+        transport: &dyn HttpTransport,
+        finish_provisioning_url: &str,
+        headers: HeaderMap,
+        first_step: StartProvisioningData,
+    ) -> Result<(), ADIError> {
         let mut body = Dictionary::new();
         let mut request = Dictionary::new();
         request.insert(
@@ -265,13 +307,14 @@ impl dyn ADIProxy {
         Value::Dictionary(body).to_writer_xml(&mut fp_request)?;
 
         debug!("Second provisioning request...");
-        let response = client
-            .post(finish_provisioning_url)
-            .body(fp_request)
-            .send()
+        let response = transport
+            .send(
+                HttpRequest::post(finish_provisioning_url)
+                    .headers(headers)
+                    .body(fp_request),
+            )
             .await?
-            .plist()
-            .await?;
+            .plist()?;
 
         let response = response.get_response()?;
 
@@ -285,46 +328,93 @@ impl dyn ADIProxy {
     }
 }
 
+/// An `adi.pb` kept in a state store, and the private directory the ADI uses it from
+struct StoredProvisioning {
+    dir: TempDir,
+    state_store: Arc<dyn AnisetteStateStore>,
+}
+
+impl StoredProvisioning {
+    /// Saves what the ADI wrote, if anything
+    fn save(&self) -> io::Result<()> {
+        match fs::read(self.dir.path().join(ADI_PB_KEY)) {
+            Ok(adi_pb) => self.state_store.save(ADI_PB_KEY, &adi_pb),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
 pub struct ADIProxyAnisetteProvider<ProxyType: ADIProxy + 'static> {
     adi_proxy: ProxyType,
+    transport: Option<Arc<dyn HttpTransport>>,
+    retry_policy: RetryPolicy,
+    client_profile: ClientProfile,
+    stored_provisioning: Option<StoredProvisioning>,
 }
 
 impl<ProxyType: ADIProxy + 'static> ADIProxyAnisetteProvider<ProxyType> {
     /// If you use this method, you are expected to set the identifier yourself.
     pub fn without_identifier(adi_proxy: ProxyType) -> Result<ADIProxyAnisetteProvider<ProxyType>, ADIError> {
-        Ok(ADIProxyAnisetteProvider { adi_proxy })
+        Ok(ADIProxyAnisetteProvider {
+            adi_proxy,
+            transport: None,
+            retry_policy: RetryPolicy::new(),
+            client_profile: ClientProfile::default(),
+            stored_provisioning: None,
+        })
     }
 
     pub fn new(
-        mut adi_proxy: ProxyType,
+        adi_proxy: ProxyType,
         configuration_path: PathBuf,
     ) -> Result<ADIProxyAnisetteProvider<ProxyType>, ADIError> {
-        let identifier_file_path = configuration_path.join("identifier");
-        let mut identifier_file = std::fs::OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(identifier_file_path)?;
-        let mut identifier = [0u8; IDENTIFIER_LENGTH];
-        if identifier_file.metadata()?.len() == IDENTIFIER_LENGTH as u64 {
-            identifier_file.read_exact(&mut identifier)?;
-        } else {
-            rand::thread_rng().fill_bytes(&mut identifier);
-            identifier_file.write_all(&identifier)?;
-        }
+        Self::with_state_store(adi_proxy, Arc::new(FileStateStore::new(configuration_path)))
+    }
 
-        let mut local_user_uuid_hasher = Sha256::new();
-        local_user_uuid_hasher.update(identifier);
+    /// Loads the identifier from `state_store`, generating and saving one the first time
+    pub fn with_state_store(
+        mut adi_proxy: ProxyType,
+        state_store: Arc<dyn AnisetteStateStore>,
+    ) -> Result<ADIProxyAnisetteProvider<ProxyType>, ADIError> {
+        let identifier = match state_store.load(IDENTIFIER_KEY)? {
+            Some(identifier) if identifier.len() == IDENTIFIER_LENGTH => {
+                Identifier::try_from(identifier.as_slice()).unwrap()
+            }
+            _ => {
+                let mut identifier = [0u8; IDENTIFIER_LENGTH];
+                rand::thread_rng().fill_bytes(&mut identifier);
+                state_store.save(IDENTIFIER_KEY, &identifier)?;
+                identifier
+            }
+        };
+
+        (&mut adi_proxy as &mut dyn ADIProxy).set_identity(&identifier)?;
+
+        Ok(ADIProxyAnisetteProvider {
+            adi_proxy,
+            transport: None,
+            retry_policy: RetryPolicy::new(),
+            client_profile: ClientProfile::default(),
+            stored_provisioning: None,
+        })
+    }
 
-        adi_proxy.set_device_identifier(
-            uuid::Uuid::from_bytes(identifier)
-                .to_string()
-                .to_uppercase(),
-        )?; // UUID, uppercase
-        adi_proxy
-            .set_local_user_uuid(hex::encode(local_user_uuid_hasher.finalize()).to_uppercase()); // 64 uppercase character hex
+    /// Sends the provisioning requests through `transport` instead of the default client
+    pub fn set_transport(mut self, transport: Arc<dyn HttpTransport>) -> ADIProxyAnisetteProvider<ProxyType> {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Retries the lookup before provisioning with `retry_policy` instead of [`RetryPolicy::new`]
+    pub fn set_retry_policy(mut self, retry_policy: RetryPolicy) -> ADIProxyAnisetteProvider<ProxyType> {
+        self.retry_policy = retry_policy;
+        self
+    }
 
-        Ok(ADIProxyAnisetteProvider { adi_proxy })
+    pub fn set_client_profile(mut self, client_profile: ClientProfile) -> ADIProxyAnisetteProvider<ProxyType> {
+        self.client_profile = client_profile;
+        self
     }
 
     pub fn adi_proxy(&mut self) -> &mut ProxyType {
@@ -332,6 +422,30 @@ impl<ProxyType: ADIProxy + 'static> ADIProxyAnisetteProvider<ProxyType> {
     }
 }
 
+impl<ProxyType: ConfigurableADIProxy + 'static> ADIProxyAnisetteProvider<ProxyType> {
+    /// Like [`Self::with_state_store`], also keeping the `adi.pb` of the ADI in `state_store`.
+    ///
+    /// The ADI provisions in a private temporary directory holding a copy of the stored `adi.pb`,
+    /// which is saved back after each provisioning.
+    pub fn with_stored_provisioning(
+        mut adi_proxy: ProxyType,
+        state_store: Arc<dyn AnisetteStateStore>,
+    ) -> Result<ADIProxyAnisetteProvider<ProxyType>, ADIError> {
+        let dir = tempfile::Builder::new().prefix("omnisette-").tempdir()?;
+        if let Some(adi_pb) = state_store.load(ADI_PB_KEY)? {
+            fs::write(dir.path().join(ADI_PB_KEY), adi_pb)?;
+        }
+        let path = dir.path().to_str().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "temporary directory is not UTF-8")
+        })?;
+        adi_proxy.set_provisioning_path(path)?;
+
+        let mut provider = Self::with_state_store(adi_proxy, state_store.clone())?;
+        provider.stored_provisioning = Some(StoredProvisioning { dir, state_store });
+        Ok(provider)
+    }
+}
+
 #[cfg_attr(feature = "async", async_trait::async_trait)]
 impl<ProxyType: ADIProxy + 'static> AnisetteHeadersProvider
     for ADIProxyAnisetteProvider<ProxyType>
@@ -344,7 +458,16 @@ impl<ProxyType: ADIProxy + 'static> AnisetteHeadersProvider
         let adi_proxy = &mut self.adi_proxy as &mut dyn ADIProxy;
 
         if !adi_proxy.is_machine_provisioned(DS_ID) && !skip_provisioning {
-            adi_proxy.provision_device().await?;
+            let transport = match &self.transport {
+                Some(transport) => transport.clone(),
+                None => http::apple_transport(&http::HttpConfiguration::new())?,
+            };
+            adi_proxy
+                .provision_device(transport.as_ref(), &self.retry_policy, &self.client_profile)
+                .await?;
+            if let Some(stored_provisioning) = &self.stored_provisioning {
+                stored_provisioning.save()?;
+            }
         }
 
         let machine_data = adi_proxy.request_otp(DS_ID)?;
@@ -369,7 +492,7 @@ impl<ProxyType: ADIProxy + 'static> AnisetteHeadersProvider
         );
         headers.insert(
             "X-Mme-Client-Info".to_string(),
-            CLIENT_INFO_HEADER.to_string(),
+            self.client_profile.client_info.clone(),
         );
         headers.insert(
             "X-Mme-Device-Id".to_string(),
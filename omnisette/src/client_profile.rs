@@ -0,0 +1,71 @@
+use crate::adi_proxy::{AKD_USER_AGENT, CLIENT_INFO_HEADER};
+
+/// The client Apple's servers are told they are talking to.
+///
+/// Apple periodically stops accepting old client versions, so the presets can be replaced by a
+/// custom profile without waiting for a new release, e.g.
+/// `ClientProfile { xcode_version: Some("15.2 (15C500b)".to_string()), ..ClientProfile::xcode_macos() }`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientProfile {
+    /// `X-Mme-Client-Info` sent when provisioning, `<model> <os;version;build> <authkit (app)>`
+    pub client_info: String,
+    /// Replaces the last part of the anisette provider's `X-Mme-Client-Info` in GSA requests
+    pub auth_kit: String,
+    /// User agent of the SRP login and app token requests
+    pub akd_user_agent: String,
+    /// User agent used when provisioning the machine
+    pub provisioning_user_agent: String,
+    /// User agent of the 2FA requests
+    pub user_agent: String,
+    /// `X-Apple-App-Info`, the bundle id of the app asking for the login
+    pub app_info: Option<String>,
+    /// `X-Xcode-Version`, only sent by Xcode
+    pub xcode_version: Option<String>,
+}
+
+impl Default for ClientProfile {
+    fn default() -> Self {
+        ClientProfile::xcode_macos()
+    }
+}
+
+impl ClientProfile {
+    /// Xcode on macOS, the identity used so far
+    pub fn xcode_macos() -> ClientProfile {
+        ClientProfile {
+            client_info: CLIENT_INFO_HEADER.to_string(),
+            auth_kit: "com.apple.AuthKit/1 (com.apple.dt.Xcode/3594.4.19)".to_string(),
+            akd_user_agent: "akd/1.0 CFNetwork/978.0.7 Darwin/18.7.0".to_string(),
+            provisioning_user_agent: AKD_USER_AGENT.to_string(),
+            user_agent: "Xcode".to_string(),
+            app_info: Some("com.apple.gs.xcode.auth".to_string()),
+            xcode_version: Some("11.2 (11B41)".to_string()),
+        }
+    }
+
+    /// The Settings app on iOS
+    pub fn ios_settings() -> ClientProfile {
+        ClientProfile {
+            client_info: "<iPhone13,2> <iPhone OS;17.1;21B80> <com.apple.AuthKit/1 (com.apple.Preferences/1112.96)>".to_string(),
+            auth_kit: "com.apple.AuthKit/1 (com.apple.Preferences/1112.96)".to_string(),
+            akd_user_agent: "akd/1.0 CFNetwork/1485 Darwin/23.1.0".to_string(),
+            provisioning_user_agent: "akd/1.0 CFNetwork/1485 Darwin/23.1.0".to_string(),
+            user_agent: "Preferences/1112.96 CFNetwork/1485 Darwin/23.1.0".to_string(),
+            app_info: Some("com.apple.Preferences".to_string()),
+            xcode_version: None,
+        }
+    }
+
+    /// iCloud for Windows
+    pub fn icloud_windows() -> ClientProfile {
+        ClientProfile {
+            client_info: "<PC> <Windows;6.2(0,0);9200> <com.apple.AuthKit.Win/1 (com.apple.iCloud/7.21)>".to_string(),
+            auth_kit: "com.apple.AuthKit.Win/1 (com.apple.iCloud/7.21)".to_string(),
+            akd_user_agent: "iCloud.exe (unknown version) CFNetwork/520.44.6".to_string(),
+            provisioning_user_agent: "iCloud.exe (unknown version) CFNetwork/520.44.6".to_string(),
+            user_agent: "iCloud/7.21 CFNetwork/520.44.6 Windows/6.2".to_string(),
+            app_info: Some("com.apple.iCloud".to_string()),
+            xcode_version: None,
+        }
+    }
+}
@@ -0,0 +1,531 @@
+//! The HTTP layer used by omnisette and icloud_auth.
+//!
+//! Everything goes through an [`HttpTransport`], so callers can plug in their own client
+//! (to add a proxy, custom TLS, request logging, or an in-memory fake for tests).
+//! [`ReqwestTransport`] is used when none is configured.
+//!
+//! [`HttpConfiguration`] holds the timeouts of the default transports and the [`RetryPolicy`]
+//! used for idempotent requests, which [`with_retry`] applies, and is shared by omnisette and
+//! icloud_auth.
+//!
+//! Requests to Apple go through [`apple_transport`], which only trusts Apple's root certificate,
+//! while anisette servers are checked against the usual webpki roots by [`default_transport`].
+
+use std::{sync::Arc, time::Duration};
+
+#[cfg(not(feature = "async"))]
+use reqwest::blocking::{Client, ClientBuilder};
+use reqwest::header::{HeaderValue, IntoHeaderName, InvalidHeaderValue};
+use reqwest::Certificate;
+#[cfg(feature = "async")]
+use reqwest::{Client, ClientBuilder};
+use thiserror::Error;
+
+pub use reqwest::{header::HeaderMap, Method, StatusCode};
+
+/// Apple Root CA, which gsa.apple.com and the provisioning endpoints chain up to
+pub const APPLE_ROOT: &[u8] = include_bytes!("./apple_root.der");
+
+#[derive(Debug, Error)]
+pub enum TransportError {
+    #[error("Request failed {0}")]
+    ReqwestError(#[from] reqwest::Error),
+    #[error("Invalid header value {0}")]
+    InvalidHeaderValue(#[from] InvalidHeaderValue),
+    #[error("{0}")]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub url: String,
+    pub headers: HeaderMap,
+    pub body: Option<Vec<u8>>,
+}
+
+impl HttpRequest {
+    pub fn new(method: Method, url: impl Into<String>) -> HttpRequest {
+        HttpRequest {
+            method,
+            url: url.into(),
+            headers: HeaderMap::new(),
+            body: None,
+        }
+    }
+
+    pub fn get(url: impl Into<String>) -> HttpRequest {
+        HttpRequest::new(Method::GET, url)
+    }
+
+    pub fn post(url: impl Into<String>) -> HttpRequest {
+        HttpRequest::new(Method::POST, url)
+    }
+
+    pub fn put(url: impl Into<String>) -> HttpRequest {
+        HttpRequest::new(Method::PUT, url)
+    }
+
+    pub fn header<K: IntoHeaderName>(mut self, name: K, value: HeaderValue) -> HttpRequest {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Adds every header in `headers`, replacing the ones already set
+    pub fn headers(mut self, headers: HeaderMap) -> HttpRequest {
+        self.headers.extend(headers);
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> HttpRequest {
+        self.body = Some(body.into());
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn is_success(&self) -> bool {
+        self.status.is_success()
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+#[cfg_attr(feature = "async", async_trait::async_trait)]
+pub trait HttpTransport: Send + Sync {
+    #[cfg_attr(not(feature = "async"), remove_async_await::remove_async_await)]
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse, TransportError>;
+}
+
+/// An [`HttpTransport`] backed by a `reqwest` client
+#[derive(Clone)]
+pub struct ReqwestTransport {
+    client: Client,
+}
+
+impl ReqwestTransport {
+    pub fn new(client: Client) -> ReqwestTransport {
+        ReqwestTransport { client }
+    }
+}
+
+#[cfg_attr(feature = "async", async_trait::async_trait)]
+impl HttpTransport for ReqwestTransport {
+    #[cfg_attr(not(feature = "async"), remove_async_await::remove_async_await)]
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse, TransportError> {
+        let mut builder = self
+            .client
+            .request(request.method, request.url)
+            .headers(request.headers);
+        if let Some(body) = request.body {
+            builder = builder.body(body);
+        }
+
+        let response = builder.send().await?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.bytes().await?.to_vec();
+
+        Ok(HttpResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+/// Jittered exponential backoff for requests that are safe to send again
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new()
+    }
+}
+
+impl RetryPolicy {
+    /// Three attempts, waiting up to 500ms then up to 1s in between
+    pub fn new() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+
+    /// Sends every request once
+    pub fn none() -> RetryPolicy {
+        RetryPolicy::new().set_max_attempts(1)
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    pub fn base_delay(&self) -> Duration {
+        self.base_delay
+    }
+
+    pub fn max_delay(&self) -> Duration {
+        self.max_delay
+    }
+
+    /// How many times a request is sent at most, including the first one
+    pub fn set_max_attempts(mut self, max_attempts: u32) -> RetryPolicy {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// The delay is picked at random below `base_delay * 2^n` after the n-th retry
+    pub fn set_base_delay(mut self, base_delay: Duration) -> RetryPolicy {
+        self.base_delay = base_delay;
+        self
+    }
+
+    /// Caps every delay, including the ones asked for with `Retry-After`
+    pub fn set_max_delay(mut self, max_delay: Duration) -> RetryPolicy {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// The delay before sending a request again after `attempt` failed attempts, or `None` once
+    /// they are used up. `retry_after` is the delay the server asked for, if any.
+    pub fn backoff(&self, attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let delay = match retry_after {
+            Some(retry_after) => retry_after,
+            None => {
+                let ceiling = self
+                    .base_delay
+                    .saturating_mul(1u32 << attempt.saturating_sub(1).min(16))
+                    .min(self.max_delay);
+                ceiling.mul_f64(rand::random::<f64>())
+            }
+        };
+        Some(delay.min(self.max_delay))
+    }
+}
+
+/// The `Retry-After` of a response, when given in seconds
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// What to do after a failed attempt
+pub enum Retry {
+    No,
+    /// Try again, after the delay the server asked for if any
+    After(Option<Duration>),
+}
+
+/// Runs `attempt` until `classify` says its result is final or the policy gives up.
+///
+/// Dropping the returned future cancels the attempt in flight along with the remaining ones.
+#[cfg(feature = "async")]
+pub async fn with_retry<T, F, Fut>(
+    policy: &RetryPolicy,
+    mut attempt: F,
+    classify: impl Fn(&T) -> Retry,
+) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = T>,
+{
+    let mut attempts = 0;
+    loop {
+        let result = attempt().await;
+        attempts += 1;
+
+        match next_delay(policy, attempts, classify(&result)) {
+            Some(delay) => tokio::time::sleep(delay).await,
+            None => return result,
+        }
+    }
+}
+
+/// Runs `attempt` until `classify` says its result is final or the policy gives up
+#[cfg(not(feature = "async"))]
+pub fn with_retry<T>(
+    policy: &RetryPolicy,
+    mut attempt: impl FnMut() -> T,
+    classify: impl Fn(&T) -> Retry,
+) -> T {
+    let mut attempts = 0;
+    loop {
+        let result = attempt();
+        attempts += 1;
+
+        match next_delay(policy, attempts, classify(&result)) {
+            Some(delay) => std::thread::sleep(delay),
+            None => return result,
+        }
+    }
+}
+
+fn next_delay(policy: &RetryPolicy, attempts: u32, retry: Retry) -> Option<Duration> {
+    let retry_after = match retry {
+        Retry::No => return None,
+        Retry::After(retry_after) => retry_after,
+    };
+    let delay = policy.backoff(attempts, retry_after)?;
+    log::debug!("Retrying after {delay:?} (attempt {attempts})");
+    Some(delay)
+}
+
+/// Retries transport failures, server errors and 429s
+pub fn classify_response(res: &Result<HttpResponse, TransportError>) -> Retry {
+    match res {
+        Ok(res) if res.status == StatusCode::TOO_MANY_REQUESTS || res.status.is_server_error() => {
+            Retry::After(retry_after(&res.headers))
+        }
+        Ok(_) => Retry::No,
+        Err(_) => Retry::After(None),
+    }
+}
+
+/// Sends a request that is safe to send again, retrying it with `policy`
+#[cfg_attr(not(feature = "async"), remove_async_await::remove_async_await)]
+pub async fn send_with_retry(
+    transport: &dyn HttpTransport,
+    policy: &RetryPolicy,
+    request: HttpRequest,
+) -> Result<HttpResponse, TransportError> {
+    with_retry(policy, || transport.send(request.clone()), classify_response).await
+}
+
+/// Timeouts and retries of the requests made by omnisette and icloud_auth
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpConfiguration {
+    connect_timeout: Duration,
+    timeout: Duration,
+    retry_policy: RetryPolicy,
+    custom_roots: Vec<Vec<u8>>,
+}
+
+impl Default for HttpConfiguration {
+    fn default() -> Self {
+        HttpConfiguration::new()
+    }
+}
+
+impl HttpConfiguration {
+    pub fn new() -> HttpConfiguration {
+        HttpConfiguration {
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            retry_policy: RetryPolicy::new(),
+            custom_roots: Vec::new(),
+        }
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        self.connect_timeout
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    pub fn custom_roots(&self) -> &[Vec<u8>] {
+        &self.custom_roots
+    }
+
+    /// How long connecting to a server may take, 10 seconds by default
+    pub fn set_connect_timeout(mut self, connect_timeout: Duration) -> HttpConfiguration {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// How long a whole request may take, from connecting to reading the body, 30 seconds by default.
+    ///
+    /// It also bounds the provisioning session of the v3 anisette provider.
+    pub fn set_timeout(mut self, timeout: Duration) -> HttpConfiguration {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn set_retry_policy(mut self, retry_policy: RetryPolicy) -> HttpConfiguration {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Also trusts the DER encoded certificate `root`, on top of Apple's root for requests to Apple
+    /// and of the webpki roots for anisette servers.
+    ///
+    /// This is an explicit opt-in for setups that intercept TLS on purpose, like a debugging proxy;
+    /// anyone holding the matching key can read and tamper with provisioning.
+    pub fn add_custom_root(mut self, root: Vec<u8>) -> HttpConfiguration {
+        self.custom_roots.push(root);
+        self
+    }
+
+    /// A `reqwest` client builder with the timeouts and custom roots applied
+    pub fn client_builder(&self) -> Result<ClientBuilder, TransportError> {
+        let mut builder = ClientBuilder::new()
+            .use_rustls_tls()
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.timeout);
+        for root in &self.custom_roots {
+            builder = builder.add_root_certificate(Certificate::from_der(root)?);
+        }
+        Ok(builder)
+    }
+}
+
+/// The transport omnisette uses for anisette servers when none is configured
+pub fn default_transport() -> Result<Arc<dyn HttpTransport>, TransportError> {
+    transport_with_configuration(&HttpConfiguration::new())
+}
+
+/// The default transport for anisette servers, with the timeouts and custom roots of `config`
+pub fn transport_with_configuration(
+    config: &HttpConfiguration,
+) -> Result<Arc<dyn HttpTransport>, TransportError> {
+    let client = config.client_builder()?.http1_title_case_headers().build()?;
+
+    Ok(Arc::new(ReqwestTransport::new(client)))
+}
+
+/// A transport for Apple's servers, which only accepts certificates issued under [`APPLE_ROOT`]
+/// or the custom roots of `config`
+pub fn apple_transport(config: &HttpConfiguration) -> Result<Arc<dyn HttpTransport>, TransportError> {
+    let client = config
+        .client_builder()?
+        .tls_built_in_root_certs(false)
+        .add_root_certificate(Certificate::from_der(APPLE_ROOT)?)
+        .http1_title_case_headers()
+        .build()?;
+
+    Ok(Arc::new(ReqwestTransport::new(client)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{retry_after, HeaderMap, RetryPolicy};
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy::new()
+            .set_max_attempts(4)
+            .set_base_delay(Duration::from_millis(100))
+            .set_max_delay(Duration::from_millis(250));
+
+        for _ in 0..100 {
+            assert!(policy.backoff(1, None).unwrap() <= Duration::from_millis(100));
+            assert!(policy.backoff(2, None).unwrap() <= Duration::from_millis(200));
+            assert!(policy.backoff(3, None).unwrap() <= Duration::from_millis(250));
+        }
+        assert_eq!(policy.backoff(4, None), None);
+
+        assert_eq!(
+            policy.backoff(1, Some(Duration::from_millis(150))),
+            Some(Duration::from_millis(150))
+        );
+        assert_eq!(
+            policy.backoff(1, Some(Duration::from_secs(60))),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(RetryPolicy::none().backoff(1, None), None);
+    }
+
+    #[test]
+    fn pinned_transport() {
+        use super::{apple_transport, transport_with_configuration, HttpConfiguration, APPLE_ROOT};
+
+        assert!(apple_transport(&HttpConfiguration::new()).is_ok());
+
+        let config = HttpConfiguration::new().add_custom_root(APPLE_ROOT.to_vec());
+        assert!(apple_transport(&config).is_ok());
+
+        let config = HttpConfiguration::new().add_custom_root(b"not a certificate".to_vec());
+        assert!(apple_transport(&config).is_err());
+        assert!(transport_with_configuration(&config).is_err());
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert("Retry-After", "12".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(12)));
+
+        headers.insert("Retry-After", "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap());
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn retries_server_errors() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        use super::{send_with_retry, HttpRequest, HttpResponse, HttpTransport, StatusCode, TransportError};
+
+        /// Answers with a 503 until `failures` requests were sent
+        struct FlakyTransport {
+            failures: u32,
+            sent: AtomicU32,
+        }
+
+        #[async_trait::async_trait]
+        impl HttpTransport for FlakyTransport {
+            async fn send(&self, _request: HttpRequest) -> Result<HttpResponse, TransportError> {
+                let sent = self.sent.fetch_add(1, Ordering::SeqCst);
+                let status = if sent < self.failures {
+                    StatusCode::SERVICE_UNAVAILABLE
+                } else {
+                    StatusCode::OK
+                };
+                Ok(HttpResponse {
+                    status,
+                    headers: HeaderMap::new(),
+                    body: Vec::new(),
+                })
+            }
+        }
+
+        let policy = RetryPolicy::new()
+            .set_max_attempts(3)
+            .set_base_delay(Duration::from_millis(1));
+        let request = HttpRequest::get("https://gsa.apple.com/grandslam/GsService2/lookup");
+
+        let transport = FlakyTransport { failures: 2, sent: AtomicU32::new(0) };
+        let response = send_with_retry(&transport, &policy, request.clone()).await.unwrap();
+        assert!(response.is_success());
+        assert_eq!(transport.sent.load(Ordering::SeqCst), 3);
+
+        let transport = FlakyTransport { failures: 3, sent: AtomicU32::new(0) };
+        let response = send_with_retry(&transport, &policy, request).await.unwrap();
+        assert_eq!(response.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(transport.sent.load(Ordering::SeqCst), 3);
+    }
+}
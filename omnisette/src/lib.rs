@@ -3,16 +3,32 @@
 //! If you want an async API, enable the `async` feature.
 //!
 //! If you want remote anisette, make sure the `remote-anisette` feature is enabled. (it's currently on by default)
+//!
+//! [`AnisetteHeaders::get_anisette_headers_provider`] picks the provider with the chain in
+//! [`provider_chain`], configured with [`AnisetteConfiguration::set_provider_chain`].
 
-use crate::adi_proxy::{ADIProxyAnisetteProvider, ConfigurableADIProxy};
+use crate::adi_proxy::ADIProxyAnisetteProvider;
 use crate::anisette_headers_provider::AnisetteHeadersProvider;
+use crate::http::{HttpConfiguration, HttpTransport, TransportError};
+use crate::state_store::{AnisetteStateStore, FileStateStore};
+use crate::provider_chain::{
+    ChainedAnisetteProvider, ProviderChainStatus, ProviderKind, ProviderRejection,
+};
+use std::fmt;
 use std::io;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use adi_proxy::ADIError;
 use thiserror::Error;
 
 pub mod adi_proxy;
 pub mod anisette_headers_provider;
+pub mod client_profile;
+pub mod http;
+pub mod provider_chain;
+pub mod provisioning_bundle;
+pub mod state_store;
 pub mod store_services_core;
 
 #[cfg(feature = "remote-anisette-v3")]
@@ -24,6 +40,8 @@ pub mod aos_kit;
 #[cfg(feature = "remote-anisette")]
 pub mod remote_anisette;
 
+pub use client_profile::ClientProfile;
+
 #[allow(dead_code)]
 pub struct AnisetteHeaders;
 
@@ -35,16 +53,22 @@ pub enum AnisetteError {
     UnsupportedDevice,
     #[error("Invalid argument {0}")]
     InvalidArgument(String),
+    #[error("Timed out")]
+    Timeout,
     #[error("Anisette not provisioned!")]
     AnisetteNotProvisioned,
     #[error("Plist serialization error {0}")]
     PlistError(#[from] plist::Error),
     #[error("Request Error {0}")]
     ReqwestError(#[from] reqwest::Error),
+    #[error("Transport Error {0}")]
+    TransportError(#[from] TransportError),
+    #[error("Invalid header value {0}")]
+    InvalidHeaderValue(#[from] reqwest::header::InvalidHeaderValue),
     #[cfg(feature = "remote-anisette-v3")]
     #[error("Provisioning socket error {0}")]
     WsError(#[from] tokio_tungstenite::tungstenite::error::Error),
-    #[cfg(feature = "remote-anisette-v3")]
+    #[cfg(any(feature = "remote-anisette", feature = "remote-anisette-v3"))]
     #[error("JSON error {0}")]
     SerdeError(#[from] serde_json::Error),
     #[error("IO error {0}")]
@@ -57,6 +81,10 @@ pub enum AnisetteError {
     Misc,
     #[error("Missing Libraries")]
     MissingLibraries,
+    #[error("The {0} anisette provider is not available here")]
+    ProviderUnavailable(ProviderKind),
+    #[error("No anisette provider available: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))]
+    NoProviderAvailable(Vec<ProviderRejection>),
     #[error("{0}")]
     Anyhow(#[from] anyhow::Error)
 }
@@ -65,12 +93,35 @@ pub const DEFAULT_ANISETTE_URL: &str = "https://ani.f1sh.me/";
 
 pub const DEFAULT_ANISETTE_URL_V3: &str = "https://ani.sidestore.io";
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct AnisetteConfiguration {
     anisette_url: String,
     anisette_url_v3: String,
     configuration_path: PathBuf,
     macos_serial: String,
+    client_profile: ClientProfile,
+    http: HttpConfiguration,
+    transport: Option<Arc<dyn HttpTransport>>,
+    state_store: Option<Arc<dyn AnisetteStateStore>>,
+    provider_chain: Vec<ProviderKind>,
+    provider_cooldown: Duration,
+}
+
+impl fmt::Debug for AnisetteConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AnisetteConfiguration")
+            .field("anisette_url", &self.anisette_url)
+            .field("anisette_url_v3", &self.anisette_url_v3)
+            .field("configuration_path", &self.configuration_path)
+            .field("macos_serial", &self.macos_serial)
+            .field("client_profile", &self.client_profile)
+            .field("http", &self.http)
+            .field("custom_transport", &self.transport.is_some())
+            .field("custom_state_store", &self.state_store.is_some())
+            .field("provider_chain", &self.provider_chain)
+            .field("provider_cooldown", &self.provider_cooldown)
+            .finish()
+    }
 }
 
 impl Default for AnisetteConfiguration {
@@ -85,7 +136,13 @@ impl AnisetteConfiguration {
             anisette_url: DEFAULT_ANISETTE_URL.to_string(),
             anisette_url_v3: DEFAULT_ANISETTE_URL_V3.to_string(),
             configuration_path: PathBuf::new(),
-            macos_serial: "0".to_string()
+            macos_serial: "0".to_string(),
+            client_profile: ClientProfile::default(),
+            http: HttpConfiguration::new(),
+            transport: None,
+            state_store: None,
+            provider_chain: ProviderKind::DEFAULT_CHAIN.to_vec(),
+            provider_cooldown: provider_chain::DEFAULT_COOLDOWN,
         }
     }
 
@@ -93,15 +150,52 @@ impl AnisetteConfiguration {
         &self.anisette_url
     }
 
+    pub fn anisette_url_v3(&self) -> &String {
+        &self.anisette_url_v3
+    }
+
+    pub fn macos_serial(&self) -> &String {
+        &self.macos_serial
+    }
+
     pub fn configuration_path(&self) -> &PathBuf {
         &self.configuration_path
     }
 
+    pub fn client_profile(&self) -> &ClientProfile {
+        &self.client_profile
+    }
+
+    pub fn http_configuration(&self) -> &HttpConfiguration {
+        &self.http
+    }
+
+    /// The store for the provisioning state: the configured one, or the files in the configuration path
+    pub fn state_store(&self) -> Arc<dyn AnisetteStateStore> {
+        match &self.state_store {
+            Some(state_store) => state_store.clone(),
+            None => Arc::new(FileStateStore::new(self.configuration_path.clone())),
+        }
+    }
+
+    pub fn provider_chain(&self) -> &[ProviderKind] {
+        &self.provider_chain
+    }
+
+    pub fn provider_cooldown(&self) -> Duration {
+        self.provider_cooldown
+    }
+
     pub fn set_anisette_url(mut self, anisette_url: String) -> AnisetteConfiguration {
         self.anisette_url = anisette_url;
         self
     }
 
+    pub fn set_anisette_url_v3(mut self, anisette_url_v3: String) -> AnisetteConfiguration {
+        self.anisette_url_v3 = anisette_url_v3;
+        self
+    }
+
     pub fn set_macos_serial(mut self, macos_serial: String) -> AnisetteConfiguration {
         self.macos_serial = macos_serial;
         self
@@ -111,8 +205,64 @@ impl AnisetteConfiguration {
         self.configuration_path = configuration_path;
         self
     }
+
+    /// Changes the client identity sent when provisioning and in the GSA requests made with this anisette data
+    pub fn set_client_profile(mut self, client_profile: ClientProfile) -> AnisetteConfiguration {
+        self.client_profile = client_profile;
+        self
+    }
+
+    /// Changes the timeouts of the default transport and how anisette fetches are retried
+    pub fn set_http_configuration(mut self, http: HttpConfiguration) -> AnisetteConfiguration {
+        self.http = http;
+        self
+    }
+
+    /// The transport for anisette servers: the configured one, or the default one with the configured
+    /// timeouts if none was set
+    pub fn transport(&self) -> Result<Arc<dyn HttpTransport>, AnisetteError> {
+        match &self.transport {
+            Some(transport) => Ok(transport.clone()),
+            None => Ok(http::transport_with_configuration(&self.http)?),
+        }
+    }
+
+    /// The transport for Apple's provisioning endpoints: the configured one, or
+    /// [`http::apple_transport`] if none was set
+    pub fn apple_transport(&self) -> Result<Arc<dyn HttpTransport>, AnisetteError> {
+        match &self.transport {
+            Some(transport) => Ok(transport.clone()),
+            None => Ok(http::apple_transport(&self.http)?),
+        }
+    }
+
+    /// Sends every request made by the anisette providers through `transport`, which is then
+    /// responsible for checking the certificates of Apple's servers
+    pub fn set_transport(mut self, transport: Arc<dyn HttpTransport>) -> AnisetteConfiguration {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Keeps the identifiers and the remote v3 state in `state_store` instead of the configuration path
+    pub fn set_state_store(mut self, state_store: Arc<dyn AnisetteStateStore>) -> AnisetteConfiguration {
+        self.state_store = Some(state_store);
+        self
+    }
+
+    /// The providers [`AnisetteHeaders::get_anisette_headers_provider`] tries, in order
+    pub fn set_provider_chain(mut self, provider_chain: Vec<ProviderKind>) -> AnisetteConfiguration {
+        self.provider_chain = provider_chain;
+        self
+    }
+
+    /// How long a provider of the chain that failed with a network error is skipped, five minutes by default
+    pub fn set_provider_cooldown(mut self, provider_cooldown: Duration) -> AnisetteConfiguration {
+        self.provider_cooldown = provider_cooldown;
+        self
+    }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum AnisetteHeadersProviderType {
     Local,
     Remote,
@@ -120,7 +270,10 @@ pub enum AnisetteHeadersProviderType {
 
 pub struct AnisetteHeadersProviderRes {
     pub provider: Box<dyn AnisetteHeadersProvider>,
+    /// The type of the provider answering when it was created
     pub provider_type: AnisetteHeadersProviderType,
+    /// The provider of the chain answering now, and the ones that were skipped
+    pub status: ProviderChainStatus,
 }
 
 impl AnisetteHeadersProviderRes {
@@ -128,6 +281,7 @@ impl AnisetteHeadersProviderRes {
         AnisetteHeadersProviderRes {
             provider,
             provider_type: AnisetteHeadersProviderType::Local,
+            status: ProviderChainStatus::default(),
         }
     }
 
@@ -135,53 +289,50 @@ impl AnisetteHeadersProviderRes {
         AnisetteHeadersProviderRes {
             provider,
             provider_type: AnisetteHeadersProviderType::Remote,
+            status: ProviderChainStatus::default(),
         }
     }
 }
 
 impl AnisetteHeaders {
+    /// The first provider of the configured chain that can be created, falling back on the next
+    /// ones when it fails
     pub fn get_anisette_headers_provider(
         configuration: AnisetteConfiguration,
     ) -> Result<AnisetteHeadersProviderRes, AnisetteError> {
-        #[cfg(target_os = "macos")]
-        if let Ok(prov) = aos_kit::AOSKitAnisetteProvider::new() {
-            return Ok(AnisetteHeadersProviderRes::local(Box::new(prov)));
-        }
-
-        // TODO: handle Err because it will just go to remote anisette and not tell the user anything
-        if let Ok(ssc_anisette_headers_provider) =
-            AnisetteHeaders::get_ssc_anisette_headers_provider(configuration.clone())
-        {
-            return Ok(ssc_anisette_headers_provider);
-        }
-
-        #[cfg(feature = "remote-anisette-v3")]
-        return Ok(AnisetteHeadersProviderRes::remote(Box::new(
-            remote_anisette_v3::RemoteAnisetteProviderV3::new(configuration.anisette_url_v3, configuration.configuration_path.clone(), configuration.macos_serial.clone()),
-        )));
-
-        #[cfg(feature = "remote-anisette")]
-        return Ok(AnisetteHeadersProviderRes::remote(Box::new(
-            remote_anisette::RemoteAnisetteProvider::new(configuration.anisette_url),
-        )));
-
-        #[cfg(not(feature = "remote-anisette"))]
-        bail!(AnisetteMetaError::UnsupportedDevice)
+        let chain = configuration.provider_chain().to_vec();
+        let provider = ChainedAnisetteProvider::new(configuration, &chain)?;
+        let status = provider.status().clone();
+        let provider_type = status
+            .active()
+            .ok_or_else(|| AnisetteError::NoProviderAvailable(status.rejections()))?
+            .provider_type();
+
+        Ok(AnisetteHeadersProviderRes {
+            provider: Box::new(provider),
+            provider_type,
+            status,
+        })
     }
 
     pub fn get_ssc_anisette_headers_provider(
         configuration: AnisetteConfiguration,
     ) -> Result<AnisetteHeadersProviderRes, AnisetteError> {
-        let mut ssc_adi_proxy = store_services_core::StoreServicesCoreADIProxy::new(
+        // the libraries are loaded from the configuration path, the provisioning goes to the store
+        let ssc_adi_proxy = store_services_core::StoreServicesCoreADIProxy::new(
             configuration.configuration_path(),
         )?;
-        let config_path = configuration.configuration_path();
-        ssc_adi_proxy.set_provisioning_path(config_path.to_str().ok_or(
-            AnisetteError::InvalidArgument("configuration.configuration_path".to_string()),
-        )?)?;
-        Ok(AnisetteHeadersProviderRes::local(Box::new(
-            ADIProxyAnisetteProvider::new(ssc_adi_proxy, config_path.to_path_buf())?,
-        )))
+        let mut res = AnisetteHeadersProviderRes::local(Box::new(
+            ADIProxyAnisetteProvider::with_stored_provisioning(
+                ssc_adi_proxy,
+                configuration.state_store(),
+            )?
+                .set_transport(configuration.apple_transport()?)
+                .set_retry_policy(configuration.http_configuration().retry_policy().clone())
+                .set_client_profile(configuration.client_profile().clone()),
+        ));
+        res.status = ProviderChainStatus::with_active(ProviderKind::StoreServicesCore);
+        Ok(res)
     }
 }
 
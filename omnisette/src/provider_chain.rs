@@ -0,0 +1,499 @@
+//! The ordered list of providers behind [`AnisetteHeaders::get_anisette_headers_provider`].
+//!
+//! Providers that can't be created are skipped, and when the active one fails the next one takes
+//! over. A provider that failed for good (see [`RejectionReason::is_persistent`]) isn't tried again
+//! until [`ProviderChainStatus::reset`], while one that failed on the network is tried again after
+//! [`AnisetteConfiguration::set_provider_cooldown`]. Every skipped provider is recorded with the
+//! reason, so callers can tell why they ended up on a remote server.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::adi_proxy::ADIError;
+use crate::anisette_headers_provider::AnisetteHeadersProvider;
+use crate::{AnisetteConfiguration, AnisetteError, AnisetteHeaders, AnisetteHeadersProviderType};
+
+/// How long a provider that failed on the network is skipped by default
+pub const DEFAULT_COOLDOWN: Duration = Duration::from_secs(5 * 60);
+
+/// A provider the chain can try
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ProviderKind {
+    /// AOSKit, only on macOS
+    AOSKit,
+    /// StoreServicesCore from the Apple Music APK, provisioned in the configuration path
+    StoreServicesCore,
+    /// A SideStore v3 server, provisioned in the configuration path
+    RemoteV3,
+    /// A v1 server, which keeps the provisioning on its side
+    Remote,
+}
+
+impl ProviderKind {
+    /// The order used when none is configured: local providers first, then the remote ones
+    pub const DEFAULT_CHAIN: [ProviderKind; 4] = [
+        ProviderKind::AOSKit,
+        ProviderKind::StoreServicesCore,
+        ProviderKind::RemoteV3,
+        ProviderKind::Remote,
+    ];
+
+    pub fn provider_type(&self) -> AnisetteHeadersProviderType {
+        match self {
+            ProviderKind::AOSKit | ProviderKind::StoreServicesCore => {
+                AnisetteHeadersProviderType::Local
+            }
+            ProviderKind::RemoteV3 | ProviderKind::Remote => AnisetteHeadersProviderType::Remote,
+        }
+    }
+
+    fn create(
+        &self,
+        configuration: &AnisetteConfiguration,
+    ) -> Result<Box<dyn AnisetteHeadersProvider>, AnisetteError> {
+        match self {
+            #[cfg(target_os = "macos")]
+            ProviderKind::AOSKit => Ok(Box::new(crate::aos_kit::AOSKitAnisetteProvider::new()?)),
+            ProviderKind::StoreServicesCore => Ok(
+                AnisetteHeaders::get_ssc_anisette_headers_provider(configuration.clone())?.provider,
+            ),
+            #[cfg(feature = "remote-anisette-v3")]
+            ProviderKind::RemoteV3 => Ok(Box::new(
+                crate::remote_anisette_v3::RemoteAnisetteProviderV3::new(
+                    configuration.anisette_url_v3().clone(),
+                    configuration.configuration_path().clone(),
+                    configuration.macos_serial().clone(),
+                )
+                .set_transport(configuration.transport()?)
+                .set_apple_transport(configuration.apple_transport()?)
+                .set_retry_policy(configuration.http_configuration().retry_policy().clone())
+                .set_timeout(configuration.http_configuration().timeout())
+                .set_state_store(configuration.state_store()),
+            )),
+            #[cfg(feature = "remote-anisette")]
+            ProviderKind::Remote => Ok(Box::new(
+                crate::remote_anisette::RemoteAnisetteProvider::new(
+                    configuration.anisette_url().clone(),
+                )
+                .set_transport(configuration.transport()?),
+            )),
+            #[allow(unreachable_patterns)]
+            kind => Err(AnisetteError::ProviderUnavailable(*kind)),
+        }
+    }
+}
+
+impl fmt::Display for ProviderKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ProviderKind::AOSKit => "AOSKit",
+            ProviderKind::StoreServicesCore => "StoreServicesCore",
+            ProviderKind::RemoteV3 => "remote v3",
+            ProviderKind::Remote => "remote",
+        })
+    }
+}
+
+/// Why a provider was skipped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RejectionReason {
+    /// Not supported on this platform or left out of the build
+    Unsupported,
+    /// The libraries it loads are missing or invalid
+    MissingLibraries,
+    /// The machine could not be provisioned, or the provisioning was refused
+    Provisioning,
+    /// The anisette server or Apple could not be reached
+    Network,
+    Other,
+}
+
+impl RejectionReason {
+    pub fn of(error: &AnisetteError) -> RejectionReason {
+        match error {
+            AnisetteError::UnsupportedDevice | AnisetteError::ProviderUnavailable(_) => {
+                RejectionReason::Unsupported
+            }
+            AnisetteError::MissingLibraries | AnisetteError::InvalidLibraryFormat => {
+                RejectionReason::MissingLibraries
+            }
+            AnisetteError::ReqwestError(_)
+            | AnisetteError::TransportError(_)
+            | AnisetteError::Timeout
+            | AnisetteError::ADIError(ADIError::ReqwestError(_))
+            | AnisetteError::ADIError(ADIError::TransportError(_)) => RejectionReason::Network,
+            #[cfg(feature = "remote-anisette-v3")]
+            AnisetteError::WsError(_) => RejectionReason::Network,
+            AnisetteError::AnisetteNotProvisioned | AnisetteError::ADIError(_) => {
+                RejectionReason::Provisioning
+            }
+            _ => RejectionReason::Other,
+        }
+    }
+
+    /// Whether trying the provider again can't help, as opposed to network failures and unknown
+    /// errors that may go away
+    pub fn is_persistent(&self) -> bool {
+        match self {
+            RejectionReason::Unsupported
+            | RejectionReason::MissingLibraries
+            | RejectionReason::Provisioning => true,
+            RejectionReason::Network | RejectionReason::Other => false,
+        }
+    }
+}
+
+/// A provider of the chain that was skipped, and the error it gave
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProviderRejection {
+    pub kind: ProviderKind,
+    pub reason: RejectionReason,
+    pub message: String,
+}
+
+impl fmt::Display for ProviderRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({:?}): {}", self.kind, self.reason, self.message)
+    }
+}
+
+#[derive(Default)]
+struct ChainState {
+    active: Option<ProviderKind>,
+    rejections: Vec<ProviderRejection>,
+    /// The providers that failed for good
+    failed: HashSet<ProviderKind>,
+    /// The providers that failed for now, and when
+    cooling_down: HashMap<ProviderKind, Instant>,
+}
+
+/// What a chain is using, shared with the chain so it stays current after a fallback
+#[derive(Clone, Default)]
+pub struct ProviderChainStatus(Arc<Mutex<ChainState>>);
+
+impl ProviderChainStatus {
+    pub(crate) fn with_active(kind: ProviderKind) -> ProviderChainStatus {
+        let status = ProviderChainStatus::default();
+        status.0.lock().unwrap().active = Some(kind);
+        status
+    }
+
+    /// The provider answering the calls, `None` once they have all failed
+    pub fn active(&self) -> Option<ProviderKind> {
+        self.0.lock().unwrap().active
+    }
+
+    /// The providers skipped so far, in order
+    pub fn rejections(&self) -> Vec<ProviderRejection> {
+        self.0.lock().unwrap().rejections.clone()
+    }
+
+    /// Forgets the failed providers, so the next call starts again from the first one of the chain
+    pub fn reset(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.rejections.clear();
+        state.failed.clear();
+        state.cooling_down.clear();
+    }
+
+    fn reject(&self, kind: ProviderKind, error: &AnisetteError) {
+        let mut state = self.0.lock().unwrap();
+        if state.active == Some(kind) {
+            state.active = None;
+        }
+        let reason = RejectionReason::of(error);
+        if reason.is_persistent() {
+            state.failed.insert(kind);
+        } else {
+            state.cooling_down.insert(kind, Instant::now());
+        }
+        state.rejections.push(ProviderRejection {
+            kind,
+            reason,
+            message: error.to_string(),
+        });
+    }
+
+    /// The first provider of `chain` that isn't in `tried` and hasn't failed, preferring the ones
+    /// not cooling down
+    fn next(
+        &self,
+        chain: &[ProviderKind],
+        tried: &HashSet<ProviderKind>,
+        cooldown: Duration,
+    ) -> Option<ProviderKind> {
+        let mut state = self.0.lock().unwrap();
+        state
+            .cooling_down
+            .retain(|_, failed_at| failed_at.elapsed() < cooldown);
+
+        let mut candidates = chain
+            .iter()
+            .copied()
+            .filter(|kind| !tried.contains(kind) && !state.failed.contains(kind));
+        let first = candidates.clone().next();
+        candidates
+            .find(|kind| !state.cooling_down.contains_key(kind))
+            .or(first)
+    }
+}
+
+impl fmt::Debug for ProviderChainStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProviderChainStatus")
+            .field("active", &self.active())
+            .field("rejections", &self.rejections())
+            .finish()
+    }
+}
+
+/// Forwards to the first working provider of the chain
+pub struct ChainedAnisetteProvider {
+    configuration: AnisetteConfiguration,
+    chain: Vec<ProviderKind>,
+    current: Option<(ProviderKind, Box<dyn AnisetteHeadersProvider>)>,
+    status: ProviderChainStatus,
+}
+
+impl ChainedAnisetteProvider {
+    /// Creates the first provider of `chain` that can be, failing with every rejection if none can
+    pub fn new(
+        configuration: AnisetteConfiguration,
+        chain: &[ProviderKind],
+    ) -> Result<ChainedAnisetteProvider, AnisetteError> {
+        let mut provider = ChainedAnisetteProvider {
+            configuration,
+            chain: chain.to_vec(),
+            current: None,
+            status: ProviderChainStatus::default(),
+        };
+        if !provider.select(&mut HashSet::new()) {
+            return Err(AnisetteError::NoProviderAvailable(
+                provider.status.rejections(),
+            ));
+        }
+        Ok(provider)
+    }
+
+    pub fn status(&self) -> &ProviderChainStatus {
+        &self.status
+    }
+
+    /// Switches to the provider to try next, creating it unless it's the current one. Providers
+    /// that can't be created are added to `tried`. Returns false when there is none left.
+    fn select(&mut self, tried: &mut HashSet<ProviderKind>) -> bool {
+        let cooldown = self.configuration.provider_cooldown();
+        while let Some(kind) = self.status.next(&self.chain, tried, cooldown) {
+            if !matches!(&self.current, Some((current, _)) if *current == kind) {
+                match kind.create(&self.configuration) {
+                    Ok(provider) => self.current = Some((kind, provider)),
+                    Err(err) => {
+                        self.status.reject(kind, &err);
+                        tried.insert(kind);
+                        continue;
+                    }
+                }
+            }
+            self.status.0.lock().unwrap().active = Some(kind);
+            return true;
+        }
+        self.current = None;
+        false
+    }
+}
+
+#[cfg_attr(feature = "async", async_trait::async_trait)]
+impl AnisetteHeadersProvider for ChainedAnisetteProvider {
+    #[cfg_attr(not(feature = "async"), remove_async_await::remove_async_await)]
+    async fn get_anisette_headers(
+        &mut self,
+        skip_provisioning: bool,
+    ) -> Result<HashMap<String, String>, AnisetteError> {
+        let mut tried = HashSet::new();
+        let mut failed: Option<(ProviderKind, AnisetteError)> = None;
+        loop {
+            if !self.select(&mut tried) {
+                // the caller gets the error itself, the rest is in the status
+                return Err(match failed {
+                    Some((_, err)) => err,
+                    None => AnisetteError::NoProviderAvailable(self.status.rejections()),
+                });
+            }
+            let (kind, provider) = self.current.as_mut().unwrap();
+            let kind = *kind;
+            if let Some((failed_kind, err)) = &failed {
+                warn!("The {failed_kind} anisette provider failed, using {kind} instead: {err}");
+            }
+
+            let err = match provider.get_anisette_headers(skip_provisioning).await {
+                Ok(headers) => return Ok(headers),
+                Err(err) => err,
+            };
+            self.status.reject(kind, &err);
+            tried.insert(kind);
+            failed = Some((kind, err));
+        }
+    }
+}
+
+#[cfg(all(test, feature = "async", feature = "remote-anisette-v3"))]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::http::{
+        HeaderMap, HttpRequest, HttpResponse, HttpTransport, StatusCode, TransportError,
+    };
+    use crate::provider_chain::{ProviderKind, RejectionReason};
+    use crate::{
+        AnisetteConfiguration, AnisetteError, AnisetteHeaders, AnisetteHeadersProviderType,
+    };
+
+    /// A v1 server at `http://v1.invalid` that can be taken down, while the v3 one is down
+    #[derive(Default)]
+    struct FakeServers {
+        v1_down: AtomicBool,
+    }
+
+    #[async_trait::async_trait]
+    impl HttpTransport for FakeServers {
+        async fn send(&self, request: HttpRequest) -> Result<HttpResponse, TransportError> {
+            if !request.url.starts_with("http://v1.invalid") || self.v1_down.load(Ordering::SeqCst)
+            {
+                return Err(TransportError::Other("connection refused".into()));
+            }
+            Ok(HttpResponse {
+                status: StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: br#"{"X-Apple-I-MD": "otp", "X-Apple-I-MD-M": "mid"}"#.to_vec(),
+            })
+        }
+    }
+
+    fn configuration(chain: Vec<ProviderKind>) -> AnisetteConfiguration {
+        configuration_with(chain, Arc::default())
+    }
+
+    fn configuration_with(
+        chain: Vec<ProviderKind>,
+        servers: Arc<FakeServers>,
+    ) -> AnisetteConfiguration {
+        let path = std::env::temp_dir().join(format!("omnisette-chain-{}", rand::random::<u64>()));
+        AnisetteConfiguration::new()
+            .set_configuration_path(path)
+            .set_anisette_url("http://v1.invalid".to_string())
+            .set_anisette_url_v3("http://v3.invalid".to_string())
+            .set_transport(servers)
+            .set_provider_chain(chain)
+    }
+
+    #[tokio::test]
+    async fn falls_back_at_runtime() {
+        let mut res = AnisetteHeaders::get_anisette_headers_provider(configuration(vec![
+            ProviderKind::StoreServicesCore,
+            ProviderKind::RemoteV3,
+            ProviderKind::Remote,
+        ]))
+        .unwrap();
+
+        // there are no libraries in the configuration path
+        assert!(matches!(
+            res.provider_type,
+            AnisetteHeadersProviderType::Remote
+        ));
+        assert_eq!(res.status.active(), Some(ProviderKind::RemoteV3));
+        let rejections = res.status.rejections();
+        assert_eq!(rejections[0].kind, ProviderKind::StoreServicesCore);
+        assert_eq!(rejections[0].reason, RejectionReason::MissingLibraries);
+
+        let headers = res.provider.get_authentication_headers().await.unwrap();
+        assert_eq!(headers["X-Apple-I-MD"], "otp");
+        assert_eq!(res.status.active(), Some(ProviderKind::Remote));
+        let rejections = res.status.rejections();
+        assert_eq!(rejections[1].kind, ProviderKind::RemoteV3);
+        assert_eq!(rejections[1].reason, RejectionReason::Network);
+
+        // the remote v3 provider isn't tried again before the cooldown
+        res.provider.get_authentication_headers().await.unwrap();
+        assert_eq!(res.status.rejections().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn network_failures_are_retried() {
+        let servers = Arc::new(FakeServers::default());
+        let configuration = configuration_with(
+            vec![ProviderKind::Remote, ProviderKind::RemoteV3],
+            servers.clone(),
+        )
+        .set_provider_cooldown(Duration::ZERO);
+        let mut res = AnisetteHeaders::get_anisette_headers_provider(configuration).unwrap();
+
+        servers.v1_down.store(true, Ordering::SeqCst);
+        let err = res.provider.get_authentication_headers().await.unwrap_err();
+        assert!(matches!(err, AnisetteError::TransportError(_)));
+        let rejections = res.status.rejections();
+        assert_eq!(rejections.len(), 2);
+        assert!(rejections
+            .iter()
+            .all(|rejection| !rejection.reason.is_persistent()));
+
+        // once the server is back, the first provider answers again
+        servers.v1_down.store(false, Ordering::SeqCst);
+        let headers = res.provider.get_authentication_headers().await.unwrap();
+        assert_eq!(headers["X-Apple-I-MD"], "otp");
+        assert_eq!(res.status.active(), Some(ProviderKind::Remote));
+    }
+
+    // AOSKit is unsupported everywhere else, which is a failure for good
+    #[cfg(not(target_os = "macos"))]
+    #[tokio::test]
+    async fn reset_tries_every_provider_again() {
+        let mut res = AnisetteHeaders::get_anisette_headers_provider(configuration(vec![
+            ProviderKind::AOSKit,
+            ProviderKind::RemoteV3,
+            ProviderKind::Remote,
+        ]))
+        .unwrap();
+        res.provider.get_authentication_headers().await.unwrap();
+        assert_eq!(res.status.rejections().len(), 2);
+
+        res.status.reset();
+        assert!(res.status.rejections().is_empty());
+        res.provider.get_authentication_headers().await.unwrap();
+        let kinds = res
+            .status
+            .rejections()
+            .iter()
+            .map(|rejection| rejection.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, [ProviderKind::AOSKit, ProviderKind::RemoteV3]);
+        assert_eq!(res.status.active(), Some(ProviderKind::Remote));
+    }
+
+    #[tokio::test]
+    async fn reports_every_rejection() {
+        let err = AnisetteHeaders::get_anisette_headers_provider(configuration(vec![
+            ProviderKind::StoreServicesCore,
+        ]))
+        .err()
+        .unwrap();
+        let AnisetteError::NoProviderAvailable(rejections) = err else {
+            panic!("unexpected error {err}");
+        };
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].reason, RejectionReason::MissingLibraries);
+
+        let mut res = AnisetteHeaders::get_anisette_headers_provider(configuration(vec![
+            ProviderKind::RemoteV3,
+        ]))
+        .unwrap();
+        let err = res.provider.get_authentication_headers().await.unwrap_err();
+        assert!(matches!(err, AnisetteError::TransportError(_)));
+        assert_eq!(res.status.active(), None);
+    }
+}
@@ -0,0 +1,149 @@
+//! Moving a provisioned machine between providers.
+//!
+//! The remote v3 provider and [`ADIProxyAnisetteProvider`](crate::adi_proxy::ADIProxyAnisetteProvider)
+//! with StoreServicesCore both identify the machine with a 16 bytes identifier and keep what Apple
+//! returned in an `adi.pb` file from CoreADI, so a machine provisioned through a v3 server can
+//! generate its OTPs locally, and the other way around, without provisioning it again.
+
+use plist::{Dictionary, Value};
+
+use crate::adi_proxy::{Identifier, IDENTIFIER_LENGTH};
+use crate::state_store::{AnisetteStateStore, ADI_PB_KEY, IDENTIFIER_KEY};
+use crate::AnisetteError;
+
+/// A provisioned machine, as exported by one provider for another
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProvisioningBundle {
+    pub identifier: Identifier,
+    pub adi_pb: Vec<u8>,
+}
+
+impl ProvisioningBundle {
+    pub fn new(identifier: Identifier, adi_pb: Vec<u8>) -> ProvisioningBundle {
+        ProvisioningBundle { identifier, adi_pb }
+    }
+
+    /// An XML plist with the `identifier` and `adi_pb` data
+    pub fn to_bytes(&self) -> Result<Vec<u8>, AnisetteError> {
+        let bundle = Dictionary::from_iter([
+            ("identifier", Value::Data(self.identifier.to_vec())),
+            ("adi_pb", Value::Data(self.adi_pb.clone())),
+        ]);
+        let mut data = Vec::new();
+        plist::to_writer_xml(&mut data, &bundle)?;
+        Ok(data)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<ProvisioningBundle, AnisetteError> {
+        let bundle: Dictionary = plist::from_bytes(data)?;
+        let field = |name: &str| {
+            bundle
+                .get(name)
+                .and_then(Value::as_data)
+                .ok_or_else(|| AnisetteError::InvalidArgument(format!("bundle.{name}")))
+        };
+        let identifier = Identifier::try_from(field("identifier")?)
+            .map_err(|_| AnisetteError::InvalidArgument("bundle.identifier".to_string()))?;
+        Ok(ProvisioningBundle::new(
+            identifier,
+            field("adi_pb")?.to_vec(),
+        ))
+    }
+
+    /// The provisioning of an `ADIProxyAnisetteProvider` created with `with_stored_provisioning`
+    pub fn export_local(
+        state_store: &dyn AnisetteStateStore,
+    ) -> Result<ProvisioningBundle, AnisetteError> {
+        let identifier = state_store
+            .load(IDENTIFIER_KEY)?
+            .filter(|identifier| identifier.len() == IDENTIFIER_LENGTH)
+            .ok_or(AnisetteError::AnisetteNotProvisioned)?;
+        let adi_pb = state_store
+            .load(ADI_PB_KEY)?
+            .ok_or(AnisetteError::AnisetteNotProvisioned)?;
+        Ok(ProvisioningBundle::new(
+            Identifier::try_from(identifier.as_slice()).unwrap(),
+            adi_pb,
+        ))
+    }
+
+    /// Replaces the provisioning of an `ADIProxyAnisetteProvider`, which picks it up when created
+    pub fn import_local(&self, state_store: &dyn AnisetteStateStore) -> Result<(), AnisetteError> {
+        state_store.save(ADI_PB_KEY, &self.adi_pb)?;
+        state_store.save(IDENTIFIER_KEY, &self.identifier)?;
+        Ok(())
+    }
+
+    /// The provisioning of a `RemoteAnisetteProviderV3`
+    #[cfg(feature = "remote-anisette-v3")]
+    pub fn export_remote_v3(
+        state_store: &dyn AnisetteStateStore,
+    ) -> Result<ProvisioningBundle, AnisetteError> {
+        let state = state_store
+            .load(crate::state_store::STATE_KEY)?
+            .ok_or(AnisetteError::AnisetteNotProvisioned)?;
+        let state: crate::remote_anisette_v3::AnisetteState = plist::from_bytes(&state)?;
+        state
+            .provisioning_bundle()
+            .ok_or(AnisetteError::AnisetteNotProvisioned)
+    }
+
+    /// Replaces the provisioning of a `RemoteAnisetteProviderV3`, which picks it up when created
+    #[cfg(feature = "remote-anisette-v3")]
+    pub fn import_remote_v3(
+        &self,
+        state_store: &dyn AnisetteStateStore,
+    ) -> Result<(), AnisetteError> {
+        let state = crate::remote_anisette_v3::AnisetteState::from(self.clone());
+        let mut data = Vec::new();
+        plist::to_writer_xml(&mut data, &state)?;
+        state_store.save(crate::state_store::STATE_KEY, &data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::provisioning_bundle::ProvisioningBundle;
+    use crate::state_store::{AnisetteStateStore, MemoryStateStore, ADI_PB_KEY, IDENTIFIER_KEY};
+    use crate::AnisetteError;
+
+    fn bundle() -> ProvisioningBundle {
+        ProvisioningBundle::new(*b"0123456789abcdef", b"adi".to_vec())
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let data = bundle().to_bytes().unwrap();
+        assert_eq!(ProvisioningBundle::from_bytes(&data).unwrap(), bundle());
+        assert!(ProvisioningBundle::from_bytes(b"<plist><dict/></plist>").is_err());
+    }
+
+    #[test]
+    fn local_round_trip() {
+        let store = MemoryStateStore::new();
+        assert!(matches!(
+            ProvisioningBundle::export_local(&store),
+            Err(AnisetteError::AnisetteNotProvisioned)
+        ));
+
+        bundle().import_local(&store).unwrap();
+        assert_eq!(
+            store.load(IDENTIFIER_KEY).unwrap().unwrap(),
+            b"0123456789abcdef"
+        );
+        assert_eq!(store.load(ADI_PB_KEY).unwrap().unwrap(), b"adi");
+        assert_eq!(ProvisioningBundle::export_local(&store).unwrap(), bundle());
+    }
+
+    #[cfg(feature = "remote-anisette-v3")]
+    #[test]
+    fn remote_v3_round_trip() {
+        let store = MemoryStateStore::new();
+        bundle().import_remote_v3(&store).unwrap();
+        assert_eq!(
+            ProvisioningBundle::export_remote_v3(&store).unwrap(),
+            bundle()
+        );
+    }
+}
@@ -1,17 +1,27 @@
-use crate::{anisette_headers_provider::AnisetteHeadersProvider, AnisetteError};
-#[cfg(not(feature = "async"))]
-use reqwest::blocking::get;
-#[cfg(feature = "async")]
-use reqwest::get;
+use crate::{
+    anisette_headers_provider::AnisetteHeadersProvider,
+    http::{self, HttpRequest, HttpTransport},
+    AnisetteError,
+};
 use std::collections::HashMap;
+use std::sync::Arc;
 
 pub struct RemoteAnisetteProvider {
     url: String,
+    transport: Option<Arc<dyn HttpTransport>>,
 }
 
 impl RemoteAnisetteProvider {
     pub fn new(url: String) -> RemoteAnisetteProvider {
-        RemoteAnisetteProvider { url }
+        RemoteAnisetteProvider {
+            url,
+            transport: None,
+        }
+    }
+
+    pub fn set_transport(mut self, transport: Arc<dyn HttpTransport>) -> RemoteAnisetteProvider {
+        self.transport = Some(transport);
+        self
     }
 }
 
@@ -22,7 +32,12 @@ impl AnisetteHeadersProvider for RemoteAnisetteProvider {
         &mut self,
         _skip_provisioning: bool,
     ) -> Result<HashMap<String, String>, AnisetteError> {
-        Ok(get(&self.url).await?.json().await?)
+        let transport = match &self.transport {
+            Some(transport) => transport.clone(),
+            None => http::default_transport()?,
+        };
+        let response = transport.send(HttpRequest::get(self.url.clone())).await?;
+        Ok(serde_json::from_slice(&response.body)?)
     }
 }
 
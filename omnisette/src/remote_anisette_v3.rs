@@ -1,24 +1,30 @@
 
 // Implementing the SideStore Anisette v3 protocol
 
-use std::{collections::HashMap, fs, io::Cursor, path::PathBuf};
+use std::{collections::HashMap, io::Cursor, path::PathBuf, sync::Arc, time::Duration};
 
 use base64::engine::general_purpose;
 use chrono::{DateTime, SubsecRound, Utc};
 use log::debug;
 use plist::{Data, Dictionary};
-use reqwest::{Client, ClientBuilder, RequestBuilder};
+use reqwest::header::{HeaderMap, HeaderValue};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use rand::Rng;
 use sha2::{Sha256, Digest};
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio_tungstenite::{connect_async, tungstenite::{self, Message}};
 use uuid::Uuid;
 use futures_util::{stream::StreamExt, SinkExt};
 use std::fmt::Write;
 use base64::Engine;
 use async_trait::async_trait;
 
-use crate::{anisette_headers_provider::AnisetteHeadersProvider, AnisetteError};
+use crate::{
+    anisette_headers_provider::AnisetteHeadersProvider,
+    http::{self, HttpRequest, HttpTransport, RetryPolicy},
+    provisioning_bundle::ProvisioningBundle,
+    state_store::{AnisetteStateStore, FileStateStore, STATE_KEY},
+    AnisetteError,
+};
 
 
 fn plist_to_string<T: serde::Serialize>(value: &T) -> Result<String, plist::Error> {
@@ -112,6 +118,12 @@ impl AnisetteState {
         self.adi_pb.is_some()
     }
 
+    /// The machine this state was provisioned as, `None` if it wasn't yet
+    pub fn provisioning_bundle(&self) -> Option<ProvisioningBundle> {
+        let adi_pb = self.adi_pb.clone()?;
+        Some(ProvisioningBundle::new(self.keychain_identifier, adi_pb))
+    }
+
     fn md_lu(&self) -> [u8; 32] {
         let mut hasher = Sha256::new();
         hasher.update(&self.keychain_identifier);
@@ -122,9 +134,21 @@ impl AnisetteState {
         Uuid::from_bytes(self.keychain_identifier).to_string()
     }
 }
+impl From<ProvisioningBundle> for AnisetteState {
+    fn from(bundle: ProvisioningBundle) -> Self {
+        AnisetteState {
+            keychain_identifier: bundle.identifier,
+            adi_pb: Some(bundle.adi_pb),
+        }
+    }
+}
+
 pub struct AnisetteClient {
     client_info: AnisetteClientInfo,
-    url: String
+    url: String,
+    transport: Arc<dyn HttpTransport>,
+    apple_transport: Arc<dyn HttpTransport>,
+    retry_policy: RetryPolicy,
 }
 
 #[derive(Serialize)]
@@ -163,42 +187,48 @@ impl AnisetteData {
     }
 }
 
-fn make_reqwest() -> Result<Client, AnisetteError> {
-    Ok(ClientBuilder::new()
-        .http1_title_case_headers()
-        .danger_accept_invalid_certs(true) // TODO: pin the apple certificate
-        .build()?)
-}
-
 impl AnisetteClient {
-    pub async fn new(url: String) -> Result<AnisetteClient, AnisetteError> {
+    /// `transport` talks to the anisette server at `url` and `apple_transport` to Apple's provisioning endpoints
+    pub async fn new(
+        url: String,
+        transport: Arc<dyn HttpTransport>,
+        apple_transport: Arc<dyn HttpTransport>,
+    ) -> Result<AnisetteClient, AnisetteError> {
         let path = format!("{}/v3/client_info", url);
-        let http_client = make_reqwest()?;
-        let client_info = http_client.get(path)
-            .send().await?
-            .json::<AnisetteClientInfo>().await?;
+        let response = transport.send(HttpRequest::get(path)).await?;
+        let client_info = serde_json::from_slice::<AnisetteClientInfo>(&response.body)?;
         Ok(AnisetteClient {
             client_info,
-            url
+            url,
+            transport,
+            apple_transport,
+            retry_policy: RetryPolicy::new(),
         })
     }
 
-    fn build_apple_request(&self, state: &AnisetteState, builder: RequestBuilder) -> RequestBuilder {
+    /// Retries the lookup of Apple's provisioning endpoints with `retry_policy`
+    pub fn set_retry_policy(mut self, retry_policy: RetryPolicy) -> AnisetteClient {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    fn apple_headers(&self, state: &AnisetteState) -> Result<HeaderMap, AnisetteError> {
         let dt: DateTime<Utc> = Utc::now().round_subsecs(0);
 
-        builder.header("X-Mme-Client-Info", &self.client_info.client_info)
-            .header("User-Agent", &self.client_info.user_agent)
-            .header("Content-Type", "text/x-xml-plist")
-            .header("X-Apple-I-MD-LU", encode_hex(&state.md_lu()))
-            .header("X-Mme-Device-Id", state.device_id())
-            .header("X-Apple-I-Client-Time", dt.format("%+").to_string())
-            .header("X-Apple-I-TimeZone", "UTC")
-            .header("X-Apple-Locale", "en_US")
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Mme-Client-Info", HeaderValue::from_str(&self.client_info.client_info)?);
+        headers.insert("User-Agent", HeaderValue::from_str(&self.client_info.user_agent)?);
+        headers.insert("Content-Type", HeaderValue::from_static("text/x-xml-plist"));
+        headers.insert("X-Apple-I-MD-LU", HeaderValue::from_str(&encode_hex(&state.md_lu()))?);
+        headers.insert("X-Mme-Device-Id", HeaderValue::from_str(&state.device_id())?);
+        headers.insert("X-Apple-I-Client-Time", HeaderValue::from_str(&dt.format("%+").to_string())?);
+        headers.insert("X-Apple-I-TimeZone", HeaderValue::from_static("UTC"));
+        headers.insert("X-Apple-Locale", HeaderValue::from_static("en_US"));
+        Ok(headers)
     }
 
     pub async fn get_headers(&self, state: &AnisetteState) -> Result<AnisetteData, AnisetteError> {
         let path = format!("{}/v3/get_headers", self.url);
-        let http_client = make_reqwest()?;
 
         #[derive(Serialize)]
         struct GetHeadersBody {
@@ -226,10 +256,11 @@ impl AnisetteClient {
             }
         }
 
-        let headers = http_client.post(path)
-            .json(&body)
-            .send().await?
-            .json::<AnisetteHeaders>().await?;
+        let request = HttpRequest::post(path)
+            .header("Content-Type", HeaderValue::from_static("application/json"))
+            .body(serde_json::to_vec(&body)?);
+        let response = self.transport.send(request).await?;
+        let headers = serde_json::from_slice::<AnisetteHeaders>(&response.body)?;
         match headers {
             AnisetteHeaders::GetHeadersError { message } => {
                 if message.contains("-45061") {
@@ -253,10 +284,11 @@ impl AnisetteClient {
 
     pub async fn provision(&self, state: &mut AnisetteState) -> Result<(), AnisetteError> {
         debug!("Provisioning Anisette");
-        let http_client = make_reqwest()?;
-        let resp = self.build_apple_request(&state, http_client.get("https://gsa.apple.com/grandslam/GsService2/lookup"))
-            .send().await?;
-        let text = resp.text().await?;
+        let request = HttpRequest::get("https://gsa.apple.com/grandslam/GsService2/lookup")
+            .headers(self.apple_headers(state)?);
+        let text = http::send_with_retry(self.apple_transport.as_ref(), &self.retry_policy, request)
+            .await?
+            .text();
 
         let protocol_val = plist::Value::from_reader(Cursor::new(text.as_str()))?;
         let urls = protocol_val.as_dictionary().unwrap().get("urls").unwrap().as_dictionary().unwrap();
@@ -265,7 +297,9 @@ impl AnisetteClient {
         let end_provisioning_url = urls.get("midFinishProvisioning").unwrap().as_string().unwrap();
         debug!("Got provisioning urls: {} and {}", start_provisioning_url, end_provisioning_url);
 
-        let provision_ws_url = format!("{}/v3/provisioning_session", self.url).replace("https://", "wss://");
+        let provision_ws_url = format!("{}/v3/provisioning_session", self.url)
+            .replacen("https://", "wss://", 1)
+            .replacen("http://", "ws://", 1);
         let (mut connection, _) = connect_async(&provision_ws_url).await?;
 
 
@@ -285,8 +319,9 @@ impl AnisetteClient {
         }
 
         loop {
-            let Some(Ok(data)) = connection.next().await else {
-                continue
+            let data = match connection.next().await {
+                Some(data) => data?,
+                None => break,
             };
             if data.is_text() {
                 let txt = data.to_text().unwrap();
@@ -301,12 +336,12 @@ impl AnisetteClient {
                         connection.send(Message::Text(serde_json::to_string(&identifier)?)).await?;
                     },
                     ProvisionInput::GiveStartProvisioningData => {
-                        let http_client = make_reqwest()?;
                         let body_data = ProvisionBodyData { header: Dictionary::new(), request: Dictionary::new() };
-                        let resp = self.build_apple_request(state, http_client.post(start_provisioning_url))
-                            .body(plist_to_string(&body_data)?)
-                            .send().await?;
-                        let text = resp.text().await?;
+                        let request = HttpRequest::post(start_provisioning_url)
+                            .headers(self.apple_headers(state)?)
+                            .body(plist_to_string(&body_data)?);
+                        // unlike the lookup, sent once: the session is started over if it fails
+                        let text = self.apple_transport.send(request).await?.text();
 
                         let protocol_val = plist::Value::from_reader(Cursor::new(text.as_str()))?;
                         let spim = protocol_val.as_dictionary().unwrap().get("Response").unwrap().as_dictionary().unwrap()
@@ -321,12 +356,11 @@ impl AnisetteClient {
                         connection.send(Message::Text(serde_json::to_string(&spim)?)).await?;
                     },
                     ProvisionInput::GiveEndProvisioningData { cpim } => {
-                        let http_client = make_reqwest()?;
                         let body_data = ProvisionBodyData { header: Dictionary::new(), request: Dictionary::from_iter([("cpim", cpim)].into_iter()) };
-                        let resp = self.build_apple_request(state, http_client.post(end_provisioning_url))
-                            .body(plist_to_string(&body_data)?)
-                            .send().await?;
-                        let text = resp.text().await?;
+                        let request = HttpRequest::post(end_provisioning_url)
+                            .headers(self.apple_headers(state)?)
+                            .body(plist_to_string(&body_data)?);
+                        let text = self.apple_transport.send(request).await?.text();
 
                         let protocol_val = plist::Value::from_reader(Cursor::new(text.as_str()))?;
                         let response = protocol_val.as_dictionary().unwrap().get("Response").unwrap().as_dictionary().unwrap();
@@ -356,6 +390,10 @@ impl AnisetteClient {
             }
         }
 
+        if !state.is_provisioned() {
+            // the server hung up before the end of the session, which is worth trying again
+            return Err(tungstenite::Error::ConnectionClosed.into());
+        }
         Ok(())
     }
 }
@@ -365,20 +403,72 @@ pub struct RemoteAnisetteProviderV3 {
     client_url: String,
     client: Option<AnisetteClient>,
     pub state: Option<AnisetteState>,
-    configuration_path: PathBuf,
-    serial: String
+    serial: String,
+    transport: Option<Arc<dyn HttpTransport>>,
+    apple_transport: Option<Arc<dyn HttpTransport>>,
+    retry_policy: RetryPolicy,
+    timeout: Option<Duration>,
+    state_store: Arc<dyn AnisetteStateStore>,
 }
 
 impl RemoteAnisetteProviderV3 {
+    /// Keeps the state in `configuration_path/state.plist` unless another store is set
     pub fn new(url: String, configuration_path: PathBuf, serial: String) -> RemoteAnisetteProviderV3 {
         RemoteAnisetteProviderV3 {
             client_url: url,
             client: None,
             state: None,
-            configuration_path,
-            serial
+            state_store: Arc::new(FileStateStore::new(configuration_path)),
+            serial,
+            transport: None,
+            apple_transport: None,
+            retry_policy: RetryPolicy::new(),
+            timeout: None,
         }
     }
+
+    /// Sends the requests to the anisette server through `transport`
+    pub fn set_transport(mut self, transport: Arc<dyn HttpTransport>) -> RemoteAnisetteProviderV3 {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Sends the requests to Apple's provisioning endpoints through `transport` instead of [`http::apple_transport`]
+    pub fn set_apple_transport(mut self, transport: Arc<dyn HttpTransport>) -> RemoteAnisetteProviderV3 {
+        self.apple_transport = Some(transport);
+        self
+    }
+
+    /// Retries the lookup of Apple's provisioning endpoints with `retry_policy` instead of [`RetryPolicy::new`]
+    pub fn set_retry_policy(mut self, retry_policy: RetryPolicy) -> RemoteAnisetteProviderV3 {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Gives up on a provisioning session that takes longer than `timeout`
+    pub fn set_timeout(mut self, timeout: Duration) -> RemoteAnisetteProviderV3 {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Loads and saves the state through `state_store` instead of the configuration path
+    pub fn set_state_store(mut self, state_store: Arc<dyn AnisetteStateStore>) -> RemoteAnisetteProviderV3 {
+        self.state_store = state_store;
+        self
+    }
+}
+
+async fn provision_with_timeout(
+    client: &AnisetteClient,
+    state: &mut AnisetteState,
+    timeout: Option<Duration>,
+) -> Result<(), AnisetteError> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, client.provision(state))
+            .await
+            .map_err(|_| AnisetteError::Timeout)?,
+        None => client.provision(state).await,
+    }
 }
 
 #[async_trait]
@@ -388,35 +478,47 @@ impl AnisetteHeadersProvider for RemoteAnisetteProviderV3 {
         _skip_provisioning: bool,
     ) -> Result<HashMap<String, String>, AnisetteError> {
         if self.client.is_none() {
-            self.client = Some(AnisetteClient::new(self.client_url.clone()).await?);
+            let transport = match &self.transport {
+                Some(transport) => transport.clone(),
+                None => http::default_transport()?,
+            };
+            let apple_transport = match &self.apple_transport {
+                Some(transport) => transport.clone(),
+                None => http::apple_transport(&http::HttpConfiguration::new())?,
+            };
+            self.client = Some(
+                AnisetteClient::new(self.client_url.clone(), transport, apple_transport)
+                    .await?
+                    .set_retry_policy(self.retry_policy.clone()),
+            );
         }
         let client = self.client.as_ref().unwrap();
 
-        fs::create_dir_all(&self.configuration_path)?;
-        
-        let config_path = self.configuration_path.join("state.plist");
         if self.state.is_none() {
-            self.state = Some(if let Ok(text) = plist::from_file(&config_path) {
-                text
-            } else {
-                AnisetteState::new()
-            });
+            let saved = self.state_store.load(STATE_KEY)?;
+            self.state = Some(
+                saved
+                    .and_then(|data| plist::from_bytes(&data).ok())
+                    .unwrap_or_default(),
+            );
         }
 
         let state = self.state.as_mut().unwrap();
         if !state.is_provisioned() {
-            client.provision(state).await?;
-            plist::to_file_xml(&config_path, state)?;
+            provision_with_timeout(client, state, self.timeout).await?;
+            self.state_store.save(STATE_KEY, &plist_to_buf(state)?)?;
         }
         let data = match client.get_headers(&state).await {
             Ok(data) => data,
             Err(err) => {
                 if matches!(err, AnisetteError::AnisetteNotProvisioned) {
                     state.adi_pb = None;
-                    client.provision(state).await?;
-                    plist::to_file_xml(config_path, state)?;
+                    provision_with_timeout(client, state, self.timeout).await?;
+                    self.state_store.save(STATE_KEY, &plist_to_buf(state)?)?;
                     client.get_headers(&state).await?
-                } else { panic!() }
+                } else {
+                    return Err(err);
+                }
             },
         };
         Ok(data.get_headers(self.serial.clone()))
@@ -425,11 +527,70 @@ impl AnisetteHeadersProvider for RemoteAnisetteProviderV3 {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::{accept_async, tungstenite::Message};
+
     use crate::anisette_headers_provider::AnisetteHeadersProvider;
+    use crate::http::{HeaderMap, HttpRequest, HttpResponse, HttpTransport, StatusCode, TransportError};
+    use crate::provider_chain::RejectionReason;
     use crate::remote_anisette_v3::RemoteAnisetteProviderV3;
+    use crate::state_store::MemoryStateStore;
     use crate::{AnisetteError, DEFAULT_ANISETTE_URL_V3};
     use log::info;
 
+    /// Answers the client info of the anisette server and Apple's lookup
+    struct FakeEndpoints;
+
+    #[async_trait::async_trait]
+    impl HttpTransport for FakeEndpoints {
+        async fn send(&self, request: HttpRequest) -> Result<HttpResponse, TransportError> {
+            let body = if request.url.ends_with("/v3/client_info") {
+                br#"{"client_info": "<MacBookPro13,2>", "user_agent": "akd/1.0"}"#.to_vec()
+            } else {
+                let urls = plist::Dictionary::from_iter([
+                    ("midStartProvisioning", plist::Value::from("https://apple.invalid/start")),
+                    ("midFinishProvisioning", plist::Value::from("https://apple.invalid/finish")),
+                ]);
+                let mut body = Vec::new();
+                plist::to_writer_xml(&mut body, &plist::Dictionary::from_iter([("urls", urls)]))
+                    .unwrap();
+                body
+            };
+            Ok(HttpResponse {
+                status: StatusCode::OK,
+                headers: HeaderMap::new(),
+                body,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn hang_up_is_not_a_provisioning_failure() {
+        // asks for the identifier, then goes away
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = accept_async(stream).await.unwrap();
+            let ask = r#"{"result": "GiveIdentifier"}"#.to_string();
+            socket.send(Message::Text(ask)).await.unwrap();
+            socket.next().await;
+            socket.close(None).await.unwrap();
+        });
+
+        let mut provider = RemoteAnisetteProviderV3::new(url, "anisette_test".into(), "0".to_string())
+            .set_transport(Arc::new(FakeEndpoints))
+            .set_apple_transport(Arc::new(FakeEndpoints))
+            .set_state_store(Arc::new(MemoryStateStore::new()));
+        let err = provider.get_anisette_headers(false).await.unwrap_err();
+
+        assert!(matches!(err, AnisetteError::WsError(_)));
+        assert!(!RejectionReason::of(&err).is_persistent());
+    }
+
     #[tokio::test]
     async fn fetch_anisette_remote_v3() -> Result<(), AnisetteError> {
         crate::tests::init_logger();
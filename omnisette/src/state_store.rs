@@ -0,0 +1,130 @@
+//! Where the providers keep their provisioning state.
+//!
+//! The state is a few named blobs, see the `*_KEY` constants. [`FileStateStore`] keeps them as
+//! files in the configuration path like before, [`MemoryStateStore`] keeps them in memory, and
+//! anything else (a database, the OS keyring) can implement [`AnisetteStateStore`].
+//!
+//! StoreServicesCore can only read and write its `adi.pb` in a directory, so it's handed a private
+//! copy, see [`ADIProxyAnisetteProvider::with_stored_provisioning`](crate::adi_proxy::ADIProxyAnisetteProvider::with_stored_provisioning).
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// The device identifier of [`ADIProxyAnisetteProvider`](crate::adi_proxy::ADIProxyAnisetteProvider), 16 raw bytes
+pub const IDENTIFIER_KEY: &str = "identifier";
+/// The provisioning CoreADI writes for [`ADIProxyAnisetteProvider`](crate::adi_proxy::ADIProxyAnisetteProvider)
+pub const ADI_PB_KEY: &str = "adi.pb";
+/// The XML plist of the remote v3 provider's `AnisetteState`
+pub const STATE_KEY: &str = "state.plist";
+
+pub trait AnisetteStateStore: Send + Sync {
+    /// The blob saved as `key`, `None` if there is none
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+    fn save(&self, key: &str, data: &[u8]) -> io::Result<()>;
+    /// Removes `key`, doing nothing if there is none
+    fn remove(&self, key: &str) -> io::Result<()>;
+}
+
+/// One file per key in a directory, created when the first blob is saved
+#[derive(Clone, Debug)]
+pub struct FileStateStore {
+    path: PathBuf,
+}
+
+impl FileStateStore {
+    pub fn new(path: PathBuf) -> FileStateStore {
+        FileStateStore { path }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+}
+
+impl AnisetteStateStore for FileStateStore {
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path.join(key)) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn save(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.path)?;
+        fs::write(self.path.join(key), data)
+    }
+
+    fn remove(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path.join(key)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Keeps the state for the lifetime of the store only
+#[derive(Debug, Default)]
+pub struct MemoryStateStore {
+    entries: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryStateStore {
+    pub fn new() -> MemoryStateStore {
+        MemoryStateStore::default()
+    }
+
+    /// A copy of every blob, e.g. to persist them somewhere else
+    pub fn entries(&self) -> HashMap<String, Vec<u8>> {
+        self.entries.lock().unwrap().clone()
+    }
+}
+
+impl AnisetteStateStore for MemoryStateStore {
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.entries.lock().unwrap().get(key).cloned())
+    }
+
+    fn save(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> io::Result<()> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AnisetteStateStore, FileStateStore, MemoryStateStore, IDENTIFIER_KEY};
+
+    fn round_trip(store: &dyn AnisetteStateStore) {
+        assert_eq!(store.load(IDENTIFIER_KEY).unwrap(), None);
+        store.save(IDENTIFIER_KEY, b"first").unwrap();
+        store.save(IDENTIFIER_KEY, b"second").unwrap();
+        assert_eq!(store.load(IDENTIFIER_KEY).unwrap().unwrap(), b"second");
+        store.remove(IDENTIFIER_KEY).unwrap();
+        store.remove(IDENTIFIER_KEY).unwrap();
+        assert_eq!(store.load(IDENTIFIER_KEY).unwrap(), None);
+    }
+
+    #[test]
+    fn file_store() {
+        let path = std::env::temp_dir().join(format!("omnisette-store-{}", rand::random::<u64>()));
+        round_trip(&FileStateStore::new(path.join("nested")));
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn memory_store() {
+        round_trip(&MemoryStateStore::new());
+    }
+}